@@ -0,0 +1,69 @@
+use crate::{errors::ErrorType, Result, TransactionId};
+
+/// Hands out increasing transaction ids, each at most once, for code generating its own transactions like
+/// simulators and test harnesses.
+///
+/// Once `u32::MAX` was handed out every further call fails with `TransactionIdsExhausted`, ids never wrap around.
+#[derive(Debug, Clone)]
+pub struct TransactionIdAllocator {
+    /// `None` once every id was handed out
+    next: Option<TransactionId>,
+}
+
+impl Default for TransactionIdAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TransactionIdAllocator {
+    /// Starts at 1
+    pub fn new() -> Self {
+        Self::starting_at(1)
+    }
+
+    /// Starts at `first`, e.g. to continue after the ids of an existing feed
+    pub fn starting_at(first: TransactionId) -> Self {
+        Self { next: Some(first) }
+    }
+
+    pub fn next_id(&mut self) -> Result<TransactionId> {
+        let id = self.next.ok_or(ErrorType::TransactionIdsExhausted)?;
+        self.next = id.checked_add(1);
+        Ok(id)
+    }
+
+    /// The id the next call to `next_id` returns, without using it up
+    pub fn peek(&self) -> Option<TransactionId> {
+        self.next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn increasing_ids() -> Result<()> {
+        let mut ids = TransactionIdAllocator::new();
+        assert_eq!(ids.peek(), Some(1));
+        assert_eq!((ids.next_id()?, ids.next_id()?, ids.next_id()?), (1, 2, 3));
+        assert_eq!(ids.peek(), Some(4));
+        Ok(())
+    }
+
+    #[test]
+    fn exhaustion() -> Result<()> {
+        let mut ids = TransactionIdAllocator::starting_at(u32::MAX - 1);
+        assert_eq!(ids.next_id()?, u32::MAX - 1);
+        assert_eq!(ids.next_id()?, u32::MAX);
+        assert_eq!(ids.peek(), None);
+        for _ in 0..2 {
+            assert_eq!(
+                ids.next_id().unwrap_err().error_type,
+                ErrorType::TransactionIdsExhausted
+            );
+        }
+        Ok(())
+    }
+}
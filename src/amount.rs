@@ -0,0 +1,201 @@
+//! Parsing of input amounts, which some exporters write with thousands separators, e.g. `1,234.56`.
+//!
+//! serde's `deserialize_with` can't carry any configuration, so `deserialize` only accepts plain numbers and
+//! `parse_transactions_with` strips the separator of `ParseOptions::thousands_separator` before deserializing.
+
+use std::{fmt, str::FromStr};
+
+use rust_decimal::Decimal;
+use serde::{
+    de::{self, Visitor},
+    Deserializer,
+};
+
+/// Parses `text` as a decimal, allowing `separator` between groups of three digits of the integer part.
+///
+/// Anything that isn't clearly a thousands separator, like `1,23.5`, `,123` or `1.234,5`, is rejected rather
+/// than guessed, since it could just as well be a decimal separator.
+pub fn parse(text: &str, separator: Option<char>) -> Result<Decimal, String> {
+    let Some(separator) = separator.filter(|&separator| text.contains(separator)) else {
+        return decimal(text);
+    };
+
+    let digits = text.trim_start_matches(['+', '-']);
+    let sign = &text[..text.len() - digits.len()];
+    let (integer, fraction) = match digits.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (digits, None),
+    };
+    let mut groups = integer.split(separator);
+    let first = groups.next().unwrap_or_default();
+    let all_digits = |group: &str| group.bytes().all(|byte| byte.is_ascii_digit());
+    if sign.len() > 1
+        || !(1..=3).contains(&first.len())
+        || !all_digits(first)
+        || !groups.all(|group| group.len() == 3 && all_digits(group))
+        || fraction.is_some_and(|fraction| fraction.contains(separator))
+    {
+        return Err(format!("ambiguous thousands separators in amount {text:?}"));
+    }
+
+    let mut plain: String = integer.chars().filter(|&c| c != separator).collect();
+    plain.insert_str(0, sign);
+    if let Some(fraction) = fraction {
+        plain.push('.');
+        plain.push_str(fraction);
+    }
+    decimal(&plain).map_err(|err| err.replace(&format!("{plain:?}"), &format!("{text:?}")))
+}
+
+/// `Decimal::from_str` with an error naming the offending text, and telling numbers too large for a `Decimal`
+/// apart from text that isn't a number at all
+fn decimal(text: &str) -> Result<Decimal, String> {
+    Decimal::from_str(text).map_err(|_| {
+        let digits = text.strip_prefix(['+', '-']).unwrap_or(text);
+        let is_number = digits.bytes().any(|byte| byte.is_ascii_digit())
+            && digits
+                .bytes()
+                .all(|byte| byte.is_ascii_digit() || byte == b'.')
+            && digits.matches('.').count() <= 1;
+        if is_number {
+            format!(
+                "amount {text:?} is out of range, amounts can be at most {}",
+                Decimal::MAX
+            )
+        } else {
+            format!("invalid amount {text:?}")
+        }
+    })
+}
+
+/// Used as `deserialize_with` on amounts, numbers are taken as they are and text goes through `parse` without
+/// a thousands separator
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Decimal, D::Error> {
+    deserializer.deserialize_any(AmountVisitor)
+}
+
+struct AmountVisitor;
+
+impl Visitor<'_> for AmountVisitor {
+    type Value = Decimal;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a decimal amount")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Decimal, E> {
+        decimal(value).map_err(E::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Decimal, E> {
+        Ok(Decimal::from(value))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Decimal, E> {
+        Ok(Decimal::from(value))
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<Decimal, E> {
+        decimal(&value.to_string()).map_err(E::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::{errors::ErrorType, Event, ParseOptions, Transaction};
+
+    #[test]
+    fn thousands_separators() {
+        assert_eq!(parse("1,234.56", Some(',')), Ok(dec!(1234.56)));
+        assert_eq!(parse("1234.56", Some(',')), Ok(dec!(1234.56)));
+        assert_eq!(parse("-12,345,678", Some(',')), Ok(dec!(-12345678)));
+        assert_eq!(parse("+1 000.5", Some(' ')), Ok(dec!(1000.5)));
+        for ambiguous in [
+            "1,23.5",
+            ",123",
+            "1234,567",
+            "1.234,5",
+            "1,2345",
+            "1,234.5,6",
+        ] {
+            assert!(parse(ambiguous, Some(',')).is_err(), "{ambiguous}");
+        }
+        assert!(parse("1,234.56", None).is_err());
+    }
+
+    #[test]
+    fn csv_amounts() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,\"1,234.56\"\n\
+                     withdrawal,1,2,1234.56\n\
+                     deposit,1,3,\"1,23.4\"\n";
+        let options = ParseOptions {
+            thousands_separator: Some(','),
+            ..ParseOptions::default()
+        };
+        let mut rows = crate::parse_transactions_with(input.as_bytes(), options);
+        for expected in [dec!(1234.56), dec!(1234.56)] {
+            let transaction = rows.next().unwrap().unwrap();
+            assert_eq!(transaction.event.amount().unwrap().1, expected);
+        }
+        assert!(matches!(
+            rows.next().unwrap().unwrap_err().error_type,
+            ErrorType::MalformedRow { line: 4, error, .. } if error.contains("ambiguous")
+        ));
+
+        // plain numbers only, unless asked for
+        let mut rows = crate::parse_transactions(input.as_bytes());
+        assert!(rows.next().unwrap().is_err());
+        assert!(rows.next().unwrap().is_ok());
+
+        let json = r#"[{"type": "deposit", "client": 1, "tx": 1, "amount": "1000"},
+                       {"type": "withdrawal", "client": 1, "tx": 2, "amount": 2.5}]"#;
+        let transactions: Vec<Transaction> = serde_json::from_str(json).unwrap();
+        assert!(matches!(
+            transactions[0].event,
+            Event::Deposit { amount, .. } if amount == dec!(1000)
+        ));
+        assert!(serde_json::from_str::<Transaction>(
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1,000"}"#
+        )
+        .is_err());
+        assert!(matches!(
+            transactions[1].event,
+            Event::Withdrawal { amount, .. } if amount == dec!(2.5)
+        ));
+    }
+
+    #[test]
+    fn out_of_range() {
+        let too_large = "99999999999999999999999999999999.5";
+        let err = parse(too_large, Some(',')).unwrap_err();
+        assert!(
+            err.contains("out of range") && err.contains(too_large),
+            "{err}"
+        );
+        let err = parse("-99,999,999,999,999,999,999,999,999,999", Some(',')).unwrap_err();
+        assert!(
+            err.contains("out of range") && err.contains("-99,999,999"),
+            "{err}"
+        );
+        assert_eq!(
+            parse("1.2.3", None),
+            Err("invalid amount \"1.2.3\"".to_string())
+        );
+
+        // too large for any integer serde can buffer, so this fails before reaching the visitor
+        let too_large = "100000000000000000000000000000000";
+        let input = format!("type,client,tx,amount\ndeposit,1,1,{too_large}\ndeposit,1,2,1.0\n");
+        let mut transactions = crate::parse_transactions(input.as_bytes());
+        let err = transactions.next().unwrap().unwrap_err();
+        assert!(
+            matches!(&err.error_type, ErrorType::MalformedRow { line: 2, error, .. }
+                if error.contains("out of range") && error.contains(too_large)),
+            "{err}"
+        );
+        assert!(transactions.next().unwrap().is_ok());
+    }
+}
@@ -0,0 +1,138 @@
+//! Account balances, kept either as a `Decimal` or as a whole number of minor units.
+//!
+//! With `Engine::with_minor_units` every amount is checked to have at most `scale` decimal places before it reaches
+//! a balance, so balances are kept as an `i128` count of `10^-scale` units and the handlers only do integer math on
+//! them. They are turned back into a `Decimal` for output, e.g. in `AccountInfo`.
+
+use rust_decimal::Decimal;
+
+use crate::SignedAmount;
+
+/// The most decimal places a `Decimal` has, finer minor units couldn't be told apart
+pub const MAX_SCALE: u32 = 28;
+
+/// The largest number of units a `Decimal` can hold (its 96 bit mantissa), so every balance can be written out
+const MAX_UNITS: u128 = (1 << 96) - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Balance {
+    Decimal(Decimal),
+    /// `units` of `10^-scale`, e.g. cents with `scale` 2
+    Minor {
+        units: i128,
+        scale: u32,
+    },
+}
+
+impl Default for Balance {
+    fn default() -> Self {
+        Balance::Decimal(Decimal::ZERO)
+    }
+}
+
+impl Balance {
+    /// An empty balance, in minor units of `scale` if there is one
+    pub fn zero(minor_units: Option<u32>) -> Self {
+        match minor_units {
+            Some(scale) => Balance::Minor { units: 0, scale },
+            None => Balance::Decimal(Decimal::ZERO),
+        }
+    }
+
+    pub fn to_decimal(self) -> Decimal {
+        match self {
+            Balance::Decimal(amount) => amount,
+            Balance::Minor { units, scale } => Decimal::from_i128_with_scale(units, scale),
+        }
+    }
+
+    pub fn is_zero(self) -> bool {
+        match self {
+            Balance::Decimal(amount) => amount.is_zero(),
+            Balance::Minor { units, .. } => units == 0,
+        }
+    }
+
+    /// `None` if the balance would overflow.
+    ///
+    /// Amounts more precise than the minor units (which only a merge with a `Decimal` engine can bring in) are
+    /// still added exactly, by keeping the balance as a `Decimal` from then on.
+    pub fn checked_add(self, amount: Decimal) -> Option<Self> {
+        match self {
+            Balance::Decimal(balance) => balance.checked_add(amount).map(Balance::Decimal),
+            Balance::Minor { units, scale } => match to_units(amount, scale) {
+                Some(amount) => units
+                    .checked_add(amount)
+                    .filter(|units| units.unsigned_abs() <= MAX_UNITS)
+                    .map(|units| Balance::Minor { units, scale }),
+                None => self.to_decimal().checked_add(amount).map(Balance::Decimal),
+            },
+        }
+    }
+
+    /// `None` if the balance would overflow, see `checked_add`
+    pub fn checked_sub(self, amount: Decimal) -> Option<Self> {
+        self.checked_add(-amount)
+    }
+}
+
+impl SignedAmount {
+    /// `checked_move_between` for account balances, in whichever form they are kept
+    pub(crate) fn checked_move_between_balances(
+        self,
+        from: Balance,
+        to: Balance,
+    ) -> Option<(Balance, Balance)> {
+        let change = self.balance_change();
+        Some((from.checked_sub(change)?, to.checked_add(change)?))
+    }
+}
+
+/// `amount` in units of `10^-scale`, or `None` if it has more decimal places. Amounts too large for an `i128`
+/// saturate, which is still too large for any balance.
+fn to_units(amount: Decimal, scale: u32) -> Option<i128> {
+    let amount = amount.normalize();
+    let shift = scale.checked_sub(amount.scale())?;
+    Some(amount.mantissa().saturating_mul(10i128.pow(shift)))
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn minor_units() {
+        let cents = Balance::zero(Some(2));
+        let balance = cents.checked_add(dec!(10.5)).unwrap();
+        assert_eq!(
+            balance,
+            Balance::Minor {
+                units: 1050,
+                scale: 2
+            }
+        );
+        let balance = balance.checked_sub(dec!(0.50)).unwrap();
+        assert_eq!(balance.to_decimal(), dec!(10));
+        assert!(balance.checked_sub(dec!(10)).unwrap().is_zero());
+
+        // too precise for cents, but still exact
+        let balance = balance.checked_add(dec!(0.001)).unwrap();
+        assert_eq!(balance, Balance::Decimal(dec!(10.001)));
+    }
+
+    #[test]
+    fn overflow() {
+        let cents = Balance::zero(Some(2));
+        let max = cents.checked_add(Decimal::MAX / dec!(100)).unwrap();
+        assert_eq!(max.to_decimal(), Decimal::MAX / dec!(100));
+        assert_eq!(max.checked_add(dec!(0.01)), None);
+        assert_eq!(cents.checked_add(Decimal::MAX), None);
+        assert_eq!(cents.checked_sub(Decimal::MAX), None);
+        assert_eq!(
+            Balance::zero(None).checked_add(Decimal::MAX),
+            Some(Balance::Decimal(Decimal::MAX))
+        );
+    }
+}
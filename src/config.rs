@@ -0,0 +1,194 @@
+use std::collections::BTreeMap;
+
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::{
+    errors::{ErrorKind, ErrorType, Severity},
+    Engine, Result, RoundingMode,
+};
+
+/// Engine settings as read from a TOML file, every setting left out keeps the engine's default.
+///
+/// See `config.example.toml` for all of them.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EngineConfig {
+    pub global_dispute: Option<bool>,
+    pub output_scale: Option<u32>,
+    pub rounding: Option<RoundingMode>,
+    /// Builds the engine with `Engine::with_minor_units`, keeping balances as integer minor units
+    pub minor_units: Option<u32>,
+    pub min_withdrawal: Option<Decimal>,
+    pub max_withdrawal: Option<Decimal>,
+    pub daily_withdrawal_limit: Option<Decimal>,
+    pub max_transaction_amount: Option<Decimal>,
+    /// In seconds
+    pub dispute_window: Option<i64>,
+    pub chargeback_fee: Option<Decimal>,
+    pub withdrawal_fee: Option<Decimal>,
+    pub prune_settled: Option<bool>,
+    pub require_flag_before_dispute: Option<bool>,
+    pub idempotent: Option<bool>,
+    pub reject_zero_amount: Option<bool>,
+    pub reject_unknown_account_withdrawals: Option<bool>,
+    pub allow_negative_available_on_dispute: Option<bool>,
+    pub max_clients: Option<usize>,
+    pub max_open_disputes_per_client: Option<usize>,
+    pub buffer_early_disputes: Option<bool>,
+    pub rollback_limit: Option<usize>,
+    /// Severity of the errors of each kind, kinds left out keep theirs
+    pub severities: BTreeMap<ErrorKind, Severity>,
+}
+
+impl EngineConfig {
+    pub fn from_toml(toml: &str) -> Result<EngineConfig> {
+        let config: EngineConfig =
+            toml::from_str(toml).map_err(|err| ErrorType::InvalidConfig {
+                message: err.to_string(),
+            })?;
+        config.check_amounts(&mut config.engine())?;
+        Ok(config)
+    }
+
+    /// A new engine with these settings
+    pub fn build(&self) -> Engine {
+        let mut engine = self.engine();
+        self.apply(&mut engine);
+        engine
+    }
+
+    fn engine(&self) -> Engine {
+        match self.minor_units {
+            Some(scale) => Engine::with_minor_units(scale),
+            None => Engine::new(),
+        }
+    }
+
+    /// Sets the fees and limits the engine can reject (e.g. negative, or more precise than its minor units),
+    /// stopping at the first one it rejects
+    fn check_amounts(&self, engine: &mut Engine) -> Result<()> {
+        if self.min_withdrawal.is_some() || self.max_withdrawal.is_some() {
+            engine.set_withdrawal_bounds(self.min_withdrawal, self.max_withdrawal)?;
+        }
+        if self.daily_withdrawal_limit.is_some() {
+            engine.set_daily_withdrawal_limit(self.daily_withdrawal_limit)?;
+        }
+        if self.max_transaction_amount.is_some() {
+            engine.set_max_transaction_amount(self.max_transaction_amount)?;
+        }
+        if let Some(fee) = self.chargeback_fee {
+            engine.set_chargeback_fee(fee)?;
+        }
+        if let Some(fee) = self.withdrawal_fee {
+            engine.set_withdrawal_fee(fee)?;
+        }
+        Ok(())
+    }
+
+    /// Changes only the settings present in this config, everything else is left as it is.
+    ///
+    /// `from_toml` already rejects amounts a new engine would, one the given engine still rejects is logged and
+    /// the amounts after it are skipped.
+    pub fn apply(&self, engine: &mut Engine) {
+        if let Some(global_dispute) = self.global_dispute {
+            engine.set_global_dispute(global_dispute);
+        }
+        if let Some(output_scale) = self.output_scale {
+            engine.set_output_scale(output_scale);
+        }
+        if let Some(rounding) = self.rounding {
+            engine.set_rounding(rounding);
+        }
+        if let Err(err) = self.check_amounts(engine) {
+            log::error!("Ignoring the rest of the configured amounts: {}", err);
+        }
+        if self.dispute_window.is_some() {
+            engine.set_dispute_window(self.dispute_window);
+        }
+        if let Some(prune_settled) = self.prune_settled {
+            engine.set_prune_settled(prune_settled);
+        }
+        if let Some(require_flag) = self.require_flag_before_dispute {
+            engine.set_require_flag_before_dispute(require_flag);
+        }
+        if let Some(idempotent) = self.idempotent {
+            engine.set_idempotent(idempotent);
+        }
+        if let Some(reject_zero_amount) = self.reject_zero_amount {
+            engine.set_reject_zero_amount(reject_zero_amount);
+        }
+        if let Some(reject) = self.reject_unknown_account_withdrawals {
+            engine.set_reject_unknown_account_withdrawals(reject);
+        }
+        if let Some(allow) = self.allow_negative_available_on_dispute {
+            engine.set_allow_negative_available_on_dispute(allow);
+        }
+        if self.max_clients.is_some() {
+            engine.set_max_clients(self.max_clients);
+        }
+        if self.max_open_disputes_per_client.is_some() {
+            engine.set_max_open_disputes_per_client(self.max_open_disputes_per_client);
+        }
+        if let Some(buffer) = self.buffer_early_disputes {
+            engine.set_buffer_early_disputes(buffer);
+        }
+        if self.rollback_limit.is_some() {
+            engine.set_rollback_limit(self.rollback_limit);
+        }
+        for (&kind, &severity) in &self.severities {
+            engine.set_error_severity(kind, severity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn example_config() -> Result<()> {
+        let config = EngineConfig::from_toml(include_str!("../config.example.toml"))?;
+        assert_eq!(
+            config,
+            EngineConfig {
+                global_dispute: Some(false),
+                output_scale: Some(2),
+                rounding: Some(RoundingMode::HalfUp),
+                max_withdrawal: Some(dec!(10000)),
+                max_transaction_amount: Some(dec!(1000000)),
+                dispute_window: Some(86400),
+                chargeback_fee: Some(dec!(15.5)),
+                severities: BTreeMap::from([(ErrorKind::LockedAccount, Severity::Warning)]),
+                ..EngineConfig::default()
+            }
+        );
+
+        let engine = config.build();
+        assert_eq!(engine.output_scale(), 2);
+        assert_eq!(engine.rounding(), RoundingMode::HalfUp);
+        assert_eq!(
+            engine.error_severity(ErrorKind::LockedAccount),
+            Severity::Warning
+        );
+        assert_eq!(EngineConfig::from_toml("")?, EngineConfig::default());
+        for invalid in [
+            "global_dipsute = true",
+            "withdrawal_fee = \"-1\"",
+            "minor_units = 2\nchargeback_fee = \"0.001\"",
+            "[severities]\nLockedAccount = \"info\"",
+            "[severities]\nLockedAcount = \"warning\"",
+        ] {
+            assert_eq!(
+                EngineConfig::from_toml(invalid)
+                    .unwrap_err()
+                    .error_type
+                    .kind(),
+                ErrorKind::InvalidConfig
+            );
+        }
+        Ok(())
+    }
+}
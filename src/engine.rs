@@ -1,218 +1,2322 @@
-use std::collections::HashMap;
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+    io::{self, BufRead, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use indexmap::IndexMap;
 use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
 
-use crate::{errors::ErrorType, AccountInfo, ClientId, Event, Result, Transaction, TransactionId};
+use crate::{
+    balance::{self, Balance},
+    errors::{Error, ErrorKind, ErrorType, Severity},
+    normalize_zero, AccountInfo, ClientId, Currency, EngineObserver, Event, Result, RoundingMode,
+    SignedAmount, Timestamp, Transaction, TransactionId, TransactionStore,
+};
+
+/// Number of decimal places amounts computed by the engine (like interest) are rounded to
+pub const DEFAULT_OUTPUT_SCALE: u32 = 4;
+
+/// Number of handled transactions that can be rolled back by default, see `Engine::set_rollback_limit`
+pub const DEFAULT_ROLLBACK_LIMIT: usize = 100;
+
+/// Source of the current time for the engine, in seconds since the unix epoch
+pub type Clock = Box<dyn Fn() -> i64 + Send>;
+
+fn system_clock() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as i64)
+}
 
-#[derive(Default)]
 pub struct Engine {
-    state: HashMap<ClientId, ClientState>,
-    funds_transactions: HashMap<TransactionId, TransactionInfo>,
+    /// In the order the accounts were created in, see `accounts_in_insertion_order`
+    state: IndexMap<ClientId, ClientState>,
+    /// Accounts changed since the last `take_dirty_accounts`
+    dirty: HashSet<ClientId>,
+    funds_transactions: Box<dyn TransactionStore>,
+    holds: HashMap<TransactionId, HoldInfo>,
     global_dispute: bool,
+    severities: HashMap<ErrorKind, Severity>,
+    output_scale: u32,
+    rounding: RoundingMode,
+    overdraft_limits: HashMap<ClientId, Decimal>,
+    min_withdrawal: Option<Decimal>,
+    max_withdrawal: Option<Decimal>,
+    daily_withdrawal_limit: Option<Decimal>,
+    max_transaction_amount: Option<Decimal>,
+    observer: Option<Box<dyn EngineObserver>>,
+    journal: Option<Box<dyn Write + Send>>,
+    clock: Clock,
+    dispute_window: Option<i64>,
+    prune_settled: bool,
+    chargeback_fee: Decimal,
+    withdrawal_fee: Decimal,
+    on_lock: Option<Box<dyn FnMut(ClientId, TransactionId) + Send>>,
+    /// Locks waiting for `on_lock` until the batch being applied succeeds
+    batch_locks: Option<Vec<(ClientId, TransactionId)>>,
+    /// Transactions applied by the batch being applied, in order, to be journaled once it succeeds
+    batch_applied: Option<Vec<Transaction>>,
+    require_flag_before_dispute: bool,
+    idempotent: bool,
+    reject_zero_amount: bool,
+    reject_unknown_account_withdrawals: bool,
+    allow_negative_available_on_dispute: bool,
+    max_clients: Option<usize>,
+    max_open_disputes_per_client: Option<usize>,
+    minor_units: Option<u32>,
+    buffer_early_disputes: bool,
+    early_disputes: HashMap<TransactionId, BufferedDisputes>,
+    history: VecDeque<Undo>,
+    rollback_limit: Option<usize>,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Engine {
     pub fn new() -> Self {
         Self {
-            state: HashMap::new(),
-            funds_transactions: HashMap::new(),
+            state: IndexMap::new(),
+            dirty: HashSet::new(),
+            funds_transactions: Box::new(HashMap::new()),
+            holds: HashMap::new(),
             global_dispute: false,
+            severities: HashMap::new(),
+            output_scale: DEFAULT_OUTPUT_SCALE,
+            rounding: RoundingMode::HalfEven,
+            overdraft_limits: HashMap::new(),
+            min_withdrawal: None,
+            max_withdrawal: None,
+            daily_withdrawal_limit: None,
+            max_transaction_amount: None,
+            observer: None,
+            journal: None,
+            clock: Box::new(system_clock),
+            dispute_window: None,
+            prune_settled: false,
+            chargeback_fee: Decimal::ZERO,
+            withdrawal_fee: Decimal::ZERO,
+            on_lock: None,
+            batch_locks: None,
+            batch_applied: None,
+            require_flag_before_dispute: false,
+            idempotent: false,
+            reject_zero_amount: false,
+            reject_unknown_account_withdrawals: false,
+            allow_negative_available_on_dispute: true,
+            max_clients: None,
+            max_open_disputes_per_client: None,
+            minor_units: None,
+            buffer_early_disputes: false,
+            early_disputes: HashMap::new(),
+            history: VecDeque::new(),
+            rollback_limit: Some(DEFAULT_ROLLBACK_LIMIT),
+        }
+    }
+
+    /// An engine where every amount is a whole number of minor units, e.g. cents with `scale` 2.
+    ///
+    /// Amounts with more than `scale` decimal places are rejected with `InexactAmount`, fees and limits with more
+    /// are rejected by their setters with `InvalidConfig`, and interest is rounded to `scale`, so every balance
+    /// stays an exact multiple of `10^-scale`. Balances are then kept as an `i128` count of minor units and
+    /// changed with integer math only, they are turned back into `Decimal` for `AccountInfo`. Their range is that
+    /// of a `Decimal` with `scale` decimal places, and `scale` is at most 28, like any `Decimal`'s.
+    pub fn with_minor_units(scale: u32) -> Self {
+        Self {
+            minor_units: Some(scale.min(balance::MAX_SCALE)),
+            ..Self::new()
+        }
+    }
+
+    /// An engine keeping its recorded transactions in `store` instead of in memory, see `TransactionStore`.
+    ///
+    /// Snapshots (`to_bytes`) still include every transaction, and `from_bytes` restores them into memory.
+    pub fn with_transaction_store(store: impl TransactionStore + 'static) -> Self {
+        Self {
+            funds_transactions: Box::new(store),
+            ..Self::new()
+        }
+    }
+
+    /// The scale given to `with_minor_units`, if any
+    pub fn minor_units(&self) -> Option<u32> {
+        self.minor_units
+    }
+
+    /// Fees and limits can't be more precise than `with_minor_units` allows
+    fn check_minor_units(&self, setting: &str, amount: Decimal) -> Result<()> {
+        match self.minor_units {
+            Some(scale) if amount.normalize().scale() > scale => Err(ErrorType::InvalidConfig {
+                message: format!("the {setting} {amount} has more than {scale} decimal places"),
+            }
+            .into()),
+            _ => Ok(()),
         }
     }
 
+    /// Replays `transactions` on a new engine, exactly as if each was passed to `handle` in order.
+    ///
+    /// Failed transactions are collected along with their position in the iterator.
+    pub fn from_transactions(
+        transactions: impl IntoIterator<Item = Transaction>,
+    ) -> (Engine, Vec<(usize, Error)>) {
+        let mut engine = Engine::new();
+        let errors = transactions
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, transaction)| {
+                engine.handle(transaction).err().map(|err| (index, err))
+            })
+            .collect();
+        (engine, errors)
+    }
+
+    /// Same as `from_transactions`, for a journal written by `set_journal` with one JSON transaction per line.
+    ///
+    /// Empty lines are skipped, any line that isn't a valid transaction fails the whole replay with `InvalidJson`.
+    pub fn from_json_lines(input: impl BufRead) -> Result<(Engine, Vec<(usize, Error)>)> {
+        let transactions = input
+            .lines()
+            .filter(|line| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+            .map(|line| {
+                let line = line.map_err(|err| err.to_string())?;
+                serde_json::from_str(&line).map_err(|err| err.to_string())
+            })
+            .collect::<std::result::Result<Vec<Transaction>, String>>()
+            .map_err(|message| ErrorType::InvalidJson { message })?;
+        Ok(Engine::from_transactions(transactions))
+    }
+
+    /// Same as `from_transactions`, for scenarios kept as a JSON array of transactions, e.g.
+    /// `[{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}]`
+    pub fn from_json_transactions(json: &str) -> Result<(Engine, Vec<(usize, Error)>)> {
+        let transactions: Vec<Transaction> =
+            serde_json::from_str(json).map_err(|err| ErrorType::InvalidJson {
+                message: err.to_string(),
+            })?;
+        Ok(Engine::from_transactions(transactions))
+    }
+
+    /// Lets any client dispute, resolve or charge back another client's deposits.
+    ///
+    /// Funds are always moved on the owner's account, the submitting client's account is never touched.
     pub fn set_global_dispute(&mut self, global_dispute: bool) {
         self.global_dispute = global_dispute;
     }
 
+    pub fn global_dispute(&self) -> bool {
+        self.global_dispute
+    }
+
+    pub fn set_output_scale(&mut self, output_scale: u32) {
+        self.output_scale = output_scale;
+    }
+
+    pub fn output_scale(&self) -> u32 {
+        self.output_scale
+    }
+
+    /// How amounts are rounded to the output scale, for display and interest. Banker's rounding by default
+    pub fn set_rounding(&mut self, rounding: RoundingMode) {
+        self.rounding = rounding;
+    }
+
+    pub fn rounding(&self) -> RoundingMode {
+        self.rounding
+    }
+
+    /// Lets withdrawals take the client's available funds as low as `-limit`.
+    ///
+    /// Disputes can still push available below that, unless `set_allow_negative_available_on_dispute` is disabled.
+    /// Fails with `InvalidConfig` if `limit` is more precise than the minor units, see `with_minor_units`.
+    pub fn set_overdraft_limit(&mut self, client: ClientId, limit: Decimal) -> Result<()> {
+        self.check_minor_units("overdraft limit", limit)?;
+        self.overdraft_limits.insert(client, limit);
+        Ok(())
+    }
+
+    /// The available balance `client` would be left with after withdrawing `amount`, or the error the withdrawal
+    /// would fail with, without applying anything. No transaction id is used up, errors that name one report `0`.
+    ///
+    /// The checks that need the transaction itself (reused ids, the minor units scale) can't be previewed.
+    pub fn preview_withdrawal(&self, client: ClientId, amount: Decimal) -> Result<Decimal> {
+        const TX: TransactionId = 0;
+        if amount < Decimal::ZERO {
+            return Err(ErrorType::NegativeWithdrawal { tx: TX }.into());
+        }
+        if amount.is_zero() && self.reject_zero_amount {
+            return Err(ErrorType::ZeroAmount { tx: TX }.into());
+        }
+        if self.max_transaction_amount.is_some_and(|max| amount > max) {
+            return Err(ErrorType::AmountTooLarge { tx: TX, amount }.into());
+        }
+        if self.min_withdrawal.is_some_and(|min| amount < min)
+            || self.max_withdrawal.is_some_and(|max| amount > max)
+        {
+            return Err(ErrorType::WithdrawalOutOfBounds { tx: TX, amount }.into());
+        }
+        self.check_unknown_account_withdrawal(client)?;
+        self.check_new_client(client)?;
+        let new_account = ClientState::new(None, self.minor_units);
+        let account = self.state.get(&client).unwrap_or(&new_account);
+        available_after_withdrawal(
+            account,
+            self.overdraft_limit(client),
+            self.daily_withdrawal_limit,
+            client,
+            TX,
+            amount,
+            self.withdrawal_fee,
+        )
+        .map(Balance::to_decimal)
+    }
+
+    /// Zero unless configured with `set_overdraft_limit`
+    pub fn overdraft_limit(&self, client: ClientId) -> Decimal {
+        self.overdraft_limits
+            .get(&client)
+            .copied()
+            .unwrap_or(Decimal::ZERO)
+    }
+
+    /// Withdrawals requesting less than `min` or more than `max` are rejected, without touching any balance.
+    /// Fails with `InvalidConfig` if either is more precise than the minor units, see `with_minor_units`.
+    pub fn set_withdrawal_bounds(
+        &mut self,
+        min: Option<Decimal>,
+        max: Option<Decimal>,
+    ) -> Result<()> {
+        for bound in min.iter().chain(max.iter()) {
+            self.check_minor_units("withdrawal bound", *bound)?;
+        }
+        self.min_withdrawal = min;
+        self.max_withdrawal = max;
+        Ok(())
+    }
+
+    /// The `(min, max)` set with `set_withdrawal_bounds`
+    pub fn withdrawal_bounds(&self) -> (Option<Decimal>, Option<Decimal>) {
+        (self.min_withdrawal, self.max_withdrawal)
+    }
+
+    /// Withdrawals that would take a client's withdrawals since the last `reset_daily_counters` past `limit` fail
+    /// with `DailyLimitExceeded` (default is unlimited). Withdrawals are counted even without a limit.
+    ///
+    /// Fails with `InvalidConfig` if `limit` is more precise than the minor units, see `with_minor_units`.
+    pub fn set_daily_withdrawal_limit(&mut self, limit: Option<Decimal>) -> Result<()> {
+        if let Some(limit) = limit {
+            self.check_minor_units("daily withdrawal limit", limit)?;
+        }
+        self.daily_withdrawal_limit = limit;
+        Ok(())
+    }
+
+    pub fn daily_withdrawal_limit(&self) -> Option<Decimal> {
+        self.daily_withdrawal_limit
+    }
+
+    /// Starts a new day for `set_daily_withdrawal_limit`, forgetting what every client withdrew so far
+    pub fn reset_daily_counters(&mut self) {
+        for account in self.state.values_mut() {
+            account.withdrawn_today = Decimal::ZERO;
+        }
+    }
+
+    /// Deposits and withdrawals above `max` are rejected with `AmountTooLarge`, to catch corrupted feeds rather
+    /// than to cap balances. Unlimited by default
+    ///
+    /// Fails with `InvalidConfig` if `max` is more precise than the minor units, see `with_minor_units`.
+    pub fn set_max_transaction_amount(&mut self, max: Option<Decimal>) -> Result<()> {
+        if let Some(max) = max {
+            self.check_minor_units("max transaction amount", max)?;
+        }
+        self.max_transaction_amount = max;
+        Ok(())
+    }
+
+    pub fn max_transaction_amount(&self) -> Option<Decimal> {
+        self.max_transaction_amount
+    }
+
+    /// Replaces the system clock, mostly useful to drive time dependent features deterministically in tests
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    /// Transactions can only be disputed for this many seconds after they were recorded, forever if `None`
+    pub fn set_dispute_window(&mut self, dispute_window: Option<i64>) {
+        self.dispute_window = dispute_window;
+    }
+
+    pub fn dispute_window(&self) -> Option<i64> {
+        self.dispute_window
+    }
+
+    /// Processing fee taken from the available funds on every chargeback, even if that makes them negative.
+    /// Reversing the chargeback doesn't refund it.
+    ///
+    /// Fails with `InvalidConfig` if `fee` is more precise than the minor units, see `with_minor_units`.
+    pub fn set_chargeback_fee(&mut self, fee: Decimal) -> Result<()> {
+        self.check_minor_units("chargeback fee", fee)?;
+        self.chargeback_fee = fee;
+        Ok(())
+    }
+
+    pub fn chargeback_fee(&self) -> Decimal {
+        self.chargeback_fee
+    }
+
+    /// Fee taken from the available funds together with every withdrawal, which fails with `InsufficientFunds`
+    /// unless both are available. Charging the withdrawal back refunds the fee, reversing the chargeback takes it
+    /// again, while a dispute that is resolved leaves it collected.
+    ///
+    /// Fails with `InvalidConfig` for negative fees, or ones more precise than the minor units (see
+    /// `with_minor_units`), leaving the fee as it was.
+    pub fn set_withdrawal_fee(&mut self, fee: Decimal) -> Result<()> {
+        if fee < Decimal::ZERO {
+            return Err(ErrorType::InvalidConfig {
+                message: format!("the withdrawal fee can't be negative, got {fee}"),
+            }
+            .into());
+        }
+        self.check_minor_units("withdrawal fee", fee)?;
+        self.withdrawal_fee = fee;
+        Ok(())
+    }
+
+    pub fn withdrawal_fee(&self) -> Decimal {
+        self.withdrawal_fee
+    }
+
+    /// Sum of the withdrawal fees of the recorded withdrawals, without the refunded ones of charged back
+    /// withdrawals (or those of pruned withdrawals)
+    pub fn total_fees_collected(&self) -> Decimal {
+        self.funds_transactions
+            .iter()
+            .filter(|(_, info)| info.status != Status::Reversed)
+            .map(|(_, info)| info.fee)
+            .sum()
+    }
+
+    /// When disabled, disputes that would take the client's available funds below zero (or below their overdraft
+    /// limit) fail with `DisputeWouldOverdraw` instead, e.g. because the disputed deposit was already withdrawn
+    pub fn set_allow_negative_available_on_dispute(&mut self, allow: bool) {
+        self.allow_negative_available_on_dispute = allow;
+    }
+
+    pub fn allow_negative_available_on_dispute(&self) -> bool {
+        self.allow_negative_available_on_dispute
+    }
+
+    /// Disputes of unknown transactions are kept (and accepted) instead of failing with
+    /// `UnknownTransactionForDispute`, and applied as soon as the transaction is successfully applied, for feeds
+    /// where a dispute can arrive before its transaction.
+    ///
+    /// Buffered disputes are journaled and reported to the observer once they are applied, as their own
+    /// transaction (which can be rolled back on its own). One that fails then is only logged. Disputes that never
+    /// find their transaction, or whose transaction is rejected, are kept in memory forever, check
+    /// `unmatched_disputes` at the end of the stream.
+    pub fn set_buffer_early_disputes(&mut self, buffer_early_disputes: bool) {
+        self.buffer_early_disputes = buffer_early_disputes;
+    }
+
+    pub fn buffer_early_disputes(&self) -> bool {
+        self.buffer_early_disputes
+    }
+
+    /// Buffered disputes still waiting for their transaction, see `set_buffer_early_disputes`
+    pub fn unmatched_disputes(&self) -> Vec<TransactionId> {
+        let mut unmatched: Vec<_> = self.early_disputes.keys().copied().collect();
+        unmatched.sort();
+        unmatched
+    }
+
+    /// Transactions that would create an account once there are already `max_clients` accounts fail with
+    /// `TooManyClients`, without recording their id. Existing accounts are unaffected (default is unlimited).
+    pub fn set_max_clients(&mut self, max_clients: Option<usize>) {
+        self.max_clients = max_clients;
+    }
+
+    pub fn max_clients(&self) -> Option<usize> {
+        self.max_clients
+    }
+
+    /// Disputes that would leave a client with more than `max` transactions under dispute at once fail with
+    /// `TooManyOpenDisputes` and hold nothing, resolving or charging back a dispute makes room for another one.
+    /// Counted by the client the transaction belongs to (default is unlimited).
+    pub fn set_max_open_disputes_per_client(&mut self, max: Option<usize>) {
+        self.max_open_disputes_per_client = max;
+    }
+
+    pub fn max_open_disputes_per_client(&self) -> Option<usize> {
+        self.max_open_disputes_per_client
+    }
+
+    /// Deposits and withdrawals of exactly zero are rejected with `ZeroAmount`, before their id is recorded
+    pub fn set_reject_zero_amount(&mut self, reject_zero_amount: bool) {
+        self.reject_zero_amount = reject_zero_amount;
+    }
+
+    pub fn reject_zero_amount(&self) -> bool {
+        self.reject_zero_amount
+    }
+
+    /// Withdrawals by clients without an account fail with `UnknownAccount`, without creating the account or
+    /// recording their id. By default such a withdrawal opens an empty account and fails with `InsufficientFunds`.
+    pub fn set_reject_unknown_account_withdrawals(&mut self, reject: bool) {
+        self.reject_unknown_account_withdrawals = reject;
+    }
+
+    pub fn reject_unknown_account_withdrawals(&self) -> bool {
+        self.reject_unknown_account_withdrawals
+    }
+
+    /// Resubmitting a deposit or withdrawal with the same id, client and amount is accepted without applying it
+    /// again, instead of failing with `ReusedTransactionId`. An id reused with any other client or amount still fails,
+    /// and so does resubmitting a transaction that was rejected.
+    ///
+    /// Such duplicates are ignored entirely, the observer isn't called and they can't be rolled back.
+    pub fn set_idempotent(&mut self, idempotent: bool) {
+        self.idempotent = idempotent;
+    }
+
+    pub fn idempotent(&self) -> bool {
+        self.idempotent
+    }
+
+    /// Transactions have to be flagged for review (with a `Flag` event) before a dispute can hold their funds
+    pub fn set_require_flag_before_dispute(&mut self, require_flag_before_dispute: bool) {
+        self.require_flag_before_dispute = require_flag_before_dispute;
+    }
+
+    pub fn require_flag_before_dispute(&self) -> bool {
+        self.require_flag_before_dispute
+    }
+
+    /// Drops transactions as soon as their chargeback is reversed, instead of letting them be disputed again.
+    ///
+    /// Charged back transactions are kept until then, so the chargeback can still be reversed and the account
+    /// unlocked. This keeps memory bounded for long running processes, at the cost of forgetting the transaction
+    /// entirely: a later dispute for it will fail with `UnknownTransactionForDispute`, and its id can be reused by
+    /// a new deposit or withdrawal. See `prune_before` for transactions that were never charged back.
+    pub fn set_prune_settled(&mut self, prune_settled: bool) {
+        self.prune_settled = prune_settled;
+    }
+
+    pub fn prune_settled(&self) -> bool {
+        self.prune_settled
+    }
+
+    /// Forgets every transaction with an id below `tx_threshold` that isn't currently flagged, under dispute or
+    /// charged back (so the chargeback can still be reversed), returning how many were dropped.
+    ///
+    /// Pruned transactions can no longer be disputed (that fails with `UnknownTransactionForDispute`) and their
+    /// ids can be reused, so only call this once older transactions are out of the dispute period.
+    pub fn prune_before(&mut self, tx_threshold: TransactionId) -> usize {
+        let before = self.funds_transactions.len();
+        self.funds_transactions.retain(&mut |tx, info| {
+            tx >= tx_threshold
+                || matches!(
+                    info.status,
+                    Status::Flagged | Status::UnderDispute | Status::Reversed
+                )
+        });
+        before - self.funds_transactions.len()
+    }
+
+    /// Forgets every account, transaction, hold, buffered dispute and the rollback history, as if the engine was
+    /// new, while keeping all of its settings (including overdraft limits), the observer, journal and clock.
+    pub fn clear(&mut self) {
+        self.state.clear();
+        self.dirty.clear();
+        self.funds_transactions.clear();
+        self.holds.clear();
+        self.early_disputes.clear();
+        self.history.clear();
+    }
+
+    /// Only the last `limit` handled transactions can be rolled back, older ones are forgotten (default is
+    /// `DEFAULT_ROLLBACK_LIMIT`, `Some(0)` keeps no history).
+    ///
+    /// Every kept transaction holds a copy of the accounts, transaction and hold it touched, so `None` keeps one
+    /// for each transaction ever handled and memory grows with the whole input.
+    pub fn set_rollback_limit(&mut self, limit: Option<usize>) {
+        self.rollback_limit = limit;
+        self.trim_history();
+    }
+
+    pub fn rollback_limit(&self) -> Option<usize> {
+        self.rollback_limit
+    }
+
+    /// Undoes the last `n` transactions passed to `handle`, restoring the accounts, transactions, holds and
+    /// buffered disputes they touched to what they were before.
+    ///
+    /// Rejected transactions count as well, since they can still record their id or create an account. Nothing
+    /// is undone if there are fewer than `n` transactions to roll back. Changes made outside of `handle`, like
+    /// interest or pruning, are not tracked, so rolling back a transaction restores its accounts from before them.
+    pub fn rollback(&mut self, n: usize) -> Result<()> {
+        if n > self.history.len() {
+            return Err(ErrorType::RollbackTooFar {
+                requested: n,
+                available: self.history.len(),
+            }
+            .into());
+        }
+        for undo in self.history.drain(self.history.len() - n..).rev() {
+            for (client, state) in undo.accounts {
+                self.dirty.insert(client);
+                match state {
+                    Some(state) => self.state.insert(client, state),
+                    // shifts the accounts created after it, so the order of the others is kept
+                    None => self.state.shift_remove(&client),
+                };
+            }
+            if let Some((tx, info)) = undo.transaction {
+                match info {
+                    Some(info) => self.funds_transactions.insert(tx, info),
+                    None => self.funds_transactions.remove(tx),
+                };
+            }
+            if let Some((tx, hold)) = undo.hold {
+                restore(&mut self.holds, tx, hold);
+            }
+            if let Some((tx, disputes)) = undo.early_disputes {
+                restore(&mut self.early_disputes, tx, disputes);
+            }
+        }
+        Ok(())
+    }
+
+    fn trim_history(&mut self) {
+        if let Some(limit) = self.rollback_limit {
+            let excess = self.history.len().saturating_sub(limit);
+            self.history.drain(..excess);
+        }
+    }
+
+    /// Copies everything `transaction` could change, before it is applied
+    fn undo_for(&self, transaction: &Transaction) -> Undo {
+        let mut accounts = vec![transaction.client];
+        let mut undo = Undo::default();
+        match transaction.event {
+            Event::Deposit { tx, .. }
+            | Event::Withdrawal { tx, .. }
+            | Event::Adjustment { tx, .. } => {
+                undo.transaction = Some((tx, self.funds_transactions.get(tx).cloned()));
+                // the disputes buffered for it are applied right after it
+                undo.early_disputes = Some((tx, self.early_disputes.get(&tx).cloned()));
+            }
+            Event::Flag { tx }
+            | Event::Dispute { tx, .. }
+            | Event::Resolve { tx }
+            | Event::PartialResolve { tx, .. }
+            | Event::Chargeback { tx }
+            | Event::ReverseChargeback { tx }
+            | Event::Void { tx } => {
+                let info = self.funds_transactions.get(tx);
+                accounts.extend(info.map(|info| info.client));
+                undo.transaction = Some((tx, info.cloned()));
+                if let Event::Dispute { .. } = transaction.event {
+                    undo.early_disputes = Some((tx, self.early_disputes.get(&tx).cloned()));
+                }
+            }
+            Event::Hold { tx, .. } | Event::ReleaseHold { tx } => {
+                let hold = self.holds.get(&tx);
+                accounts.extend(hold.map(|hold| hold.client));
+                undo.hold = Some((tx, hold.cloned()));
+            }
+        }
+        accounts.dedup();
+        undo.accounts = accounts
+            .into_iter()
+            .map(|client| (client, self.state.get(&client).cloned()))
+            .collect();
+        undo
+    }
+
+    pub fn set_observer(&mut self, observer: Box<dyn EngineObserver>) {
+        self.observer = Some(observer);
+    }
+
+    /// Every successfully handled transaction is appended to `journal` as a line of JSON, so the state can be
+    /// rebuilt after a crash by replaying it with `from_json_lines`.
+    ///
+    /// Rejected transactions are not journaled. Failing to write the journal doesn't fail the transaction, which
+    /// is already applied at that point, it is only logged.
+    pub fn set_journal(&mut self, journal: Box<dyn Write + Send>) {
+        self.journal = Some(journal);
+    }
+
+    /// Called with the client and the chargeback's transaction id whenever a chargeback locks an account.
+    ///
+    /// It isn't called again for chargebacks on an account that is already locked.
+    pub fn set_on_lock(&mut self, on_lock: impl FnMut(ClientId, TransactionId) + Send + 'static) {
+        self.on_lock = Some(Box::new(on_lock));
+    }
+
+    /// Errors of the given kind returned by `handle` will carry this severity (default is `Severity::Error`)
+    pub fn set_error_severity(&mut self, kind: ErrorKind, severity: Severity) {
+        self.severities.insert(kind, severity);
+    }
+
+    pub fn error_severity(&self, kind: ErrorKind) -> Severity {
+        self.severities.get(&kind).copied().unwrap_or_default()
+    }
+
     /// transaction is moved here so that it won't accidently be double used
-    pub fn handle(&mut self, transaction: Transaction) -> Result<()> {
+    ///
+    /// Deposits and withdrawals are checked in a fixed order, so the same input always yields the same error:
+    /// first the amount itself, then the limit on new clients, then the transaction id being reused, then the
+    /// account being locked (or closed) and finally the available funds. A reused id never overwrites the original
+    /// transaction, but once the id check passed the transaction is recorded, even if it is then rejected.
+    ///
+    /// Transactions that are accepted but change nothing, like idempotent duplicates or disputes buffered until
+    /// their transaction arrives, return `Applied::NoOp`.
+    pub fn handle(&mut self, transaction: Transaction) -> Result<Applied> {
+        self.process(&transaction)
+    }
+
+    /// Applies all of `transactions` or none of them.
+    ///
+    /// The transactions are handled in order, and if one fails every change made by the batch is rolled back and
+    /// `BatchFailed` is returned with the position of the failed transaction and its error. The journal, observer
+    /// and lock callback only hear about the batch once all of it succeeded (or about the failed transaction).
+    pub fn apply_batch(&mut self, transactions: Vec<Transaction>) -> Result<()> {
+        let observer = self.observer.take();
+        let journal = self.journal.take();
+        let rollback_limit = self.rollback_limit.take();
+        self.batch_locks = Some(Vec::new());
+        self.batch_applied = Some(Vec::with_capacity(transactions.len()));
+        let start = self.history.len();
+
+        let mut failed = None;
+        for (index, transaction) in transactions.into_iter().enumerate() {
+            if let Err(err) = self.process(&transaction) {
+                failed = Some((index, err));
+                break;
+            }
+        }
+
+        let locks = self.batch_locks.take().unwrap_or_default();
+        let applied = self.batch_applied.take().unwrap_or_default();
+        self.observer = observer;
+        self.journal = journal;
+        let result = match failed {
+            Some((index, err)) => {
+                self.rollback(self.history.len() - start)
+                    .expect("nothing is trimmed from the history during a batch");
+                if let Some(observer) = self.observer.as_mut() {
+                    observer.on_rejected(&err);
+                }
+                Err(Error {
+                    error_type: ErrorType::BatchFailed {
+                        index,
+                        error: Box::new(err.error_type),
+                    },
+                    severity: err.severity,
+                })
+            }
+            None => {
+                for transaction in &applied {
+                    self.write_journal(transaction);
+                    if let Some(observer) = self.observer.as_mut() {
+                        notify(observer.as_mut(), transaction);
+                    }
+                }
+                if let Some(on_lock) = self.on_lock.as_mut() {
+                    for (client, tx) in locks {
+                        on_lock(client, tx);
+                    }
+                }
+                Ok(())
+            }
+        };
+        self.rollback_limit = rollback_limit;
+        self.trim_history();
+        result
+    }
+
+    fn process(&mut self, transaction: &Transaction) -> Result<Applied> {
+        if self.idempotent && self.is_duplicate(transaction) {
+            return Ok(Applied::NoOp);
+        }
+        // journaled and reported once applied, after the transaction it disputes
+        let buffered = matches!(transaction.event, Event::Dispute { tx, .. }
+            if self.buffer_early_disputes && !self.funds_transactions.contains(tx));
+        let undo = self.undo_for(transaction);
+        let result = self.apply(transaction).map_err(|mut err| {
+            if let Some(&severity) = self.severities.get(&err.error_type.kind()) {
+                err.severity = severity;
+            }
+            err
+        });
+        if let (
+            Ok(()),
+            Event::Deposit { tx, .. } | Event::Withdrawal { tx, .. } | Event::Adjustment { tx, .. },
+        ) = (&result, &transaction.event)
+        {
+            if let Some(info) = self.funds_transactions.get_mut(*tx) {
+                info.applied = true;
+            }
+        }
+        if let (Ok(()), Some(timestamp)) = (&result, transaction.timestamp) {
+            if let Some(account) = self.state.get_mut(&transaction.client) {
+                account.last_activity = account.last_activity.max(Some(timestamp));
+            }
+        }
+        for (client, before) in &undo.accounts {
+            // rejected transactions only change an account by creating it
+            if (result.is_ok() || before.is_none()) && self.state.contains_key(client) {
+                self.dirty.insert(*client);
+            }
+        }
+        let result = result.map(|()| match self.is_unchanged(&undo) {
+            true => Applied::NoOp,
+            false => Applied::Mutated,
+        });
+        self.history.push_back(undo);
+        self.trim_history();
+        if result.is_ok() && !buffered {
+            match self.batch_applied.as_mut() {
+                Some(applied) => applied.push(transaction.clone()),
+                None => self.write_journal(transaction),
+            }
+        }
+        if let Some(observer) = self.observer.as_mut() {
+            match &result {
+                Ok(_) if buffered => {}
+                Ok(_) => notify(observer.as_mut(), transaction),
+                Err(err) => observer.on_rejected(err),
+            }
+        }
+        if let (
+            Ok(_),
+            Event::Deposit { tx, .. } | Event::Withdrawal { tx, .. } | Event::Adjustment { tx, .. },
+        ) = (&result, &transaction.event)
+        {
+            self.apply_early_disputes(*tx);
+        }
+        result
+    }
+
+    /// Whether everything `undo` saved is still the same
+    fn is_unchanged(&self, undo: &Undo) -> bool {
+        let accounts = undo
+            .accounts
+            .iter()
+            .all(|(client, before)| before.as_ref() == self.state.get(client));
+        let transaction = undo
+            .transaction
+            .as_ref()
+            .is_none_or(|(tx, before)| before.as_ref() == self.funds_transactions.get(*tx));
+        let hold = undo
+            .hold
+            .as_ref()
+            .is_none_or(|(tx, before)| before.as_ref() == self.holds.get(tx));
+        // buffering a dispute changes nothing until it is applied
+        accounts && transaction && hold
+    }
+
+    fn write_journal(&mut self, transaction: &Transaction) {
+        let Some(journal) = self.journal.as_mut() else {
+            return;
+        };
+        let written = serde_json::to_writer(&mut *journal, transaction)
+            .map_err(io::Error::from)
+            .and_then(|()| journal.write_all(b"\n"));
+        if let Err(err) = written {
+            log::error!("Error writing transaction to the journal: {}", err);
+        }
+    }
+
+    /// Handles the disputes buffered for `tx` like any other transaction, once it was applied
+    fn apply_early_disputes(&mut self, tx: TransactionId) {
+        for (client, reason) in self.early_disputes.remove(&tx).unwrap_or_default() {
+            if let Err(err) = self.process(&Transaction::new(client, Event::Dispute { tx, reason }))
+            {
+                log::log!(
+                    err.severity.level(),
+                    "Error applying buffered dispute: {}",
+                    err
+                );
+            }
+        }
+    }
+
+    fn check_new_client(&self, client: ClientId) -> Result<()> {
+        if self
+            .max_clients
+            .is_some_and(|max| self.state.len() >= max && !self.state.contains_key(&client))
+        {
+            return Err(ErrorType::TooManyClients { client }.into());
+        }
+        Ok(())
+    }
+
+    fn check_unknown_account_withdrawal(&self, client: ClientId) -> Result<()> {
+        if self.reject_unknown_account_withdrawals && !self.state.contains_key(&client) {
+            return Err(ErrorType::UnknownAccount { client }.into());
+        }
+        Ok(())
+    }
+
+    /// Whether this exact deposit or withdrawal was already applied
+    fn is_duplicate(&self, transaction: &Transaction) -> bool {
+        let (tx, amount) = match transaction.event {
+            Event::Deposit { tx, amount } => (tx, SignedAmount::credit(amount)),
+            Event::Withdrawal { tx, amount } => (tx, SignedAmount::debit(amount)),
+            _ => return false,
+        };
+        self.funds_transactions.get(tx).is_some_and(|info| {
+            info.applied && info.client == transaction.client && info.amount == amount
+        })
+    }
+
+    fn apply(&mut self, transaction: &Transaction) -> Result<()> {
+        if let (Some(scale), Some((tx, amount))) = (self.minor_units, transaction.event.amount()) {
+            if amount.normalize().scale() > scale {
+                return Err(ErrorType::InexactAmount { tx, amount }.into());
+            }
+        }
         match transaction.event {
             Event::Deposit { tx, amount } if amount < Decimal::ZERO => {
                 Err(ErrorType::NegativeDeposit { tx }.into())
             }
+            Event::Deposit { tx, amount } | Event::Withdrawal { tx, amount }
+                if amount.is_zero() && self.reject_zero_amount =>
+            {
+                Err(ErrorType::ZeroAmount { tx }.into())
+            }
+            Event::Deposit { tx, amount } | Event::Withdrawal { tx, amount }
+                if self.max_transaction_amount.is_some_and(|max| amount > max) =>
+            {
+                Err(ErrorType::AmountTooLarge { tx, amount }.into())
+            }
             Event::Deposit { tx, amount } => {
-                let old = self
-                    .funds_transactions
-                    .insert(tx, TransactionInfo::new(transaction.client, amount));
-                if old.is_some() {
+                self.check_new_client(transaction.client)?;
+                let now = (self.clock)();
+                if self.funds_transactions.contains(tx) {
                     return Err(ErrorType::ReusedTransactionId { tx }.into());
                 }
-                let account = self.state.entry(transaction.client).or_default();
+                self.funds_transactions.insert(
+                    tx,
+                    TransactionInfo::new(transaction.client, SignedAmount::credit(amount), now),
+                );
+                let account = self
+                    .state
+                    .entry(transaction.client)
+                    .or_insert_with(|| ClientState::new(transaction.currency, self.minor_units));
                 if account.locked {
                     return Err(ErrorType::LockedAccount {
                         client: transaction.client,
                     }
                     .into());
                 }
-                account.available += amount;
+                if account.closed {
+                    return Err(ErrorType::ClosedAccount {
+                        client: transaction.client,
+                    }
+                    .into());
+                }
+                account.available =
+                    account
+                        .available
+                        .checked_add(amount)
+                        .ok_or(ErrorType::ArithmeticOverflow {
+                            tx,
+                            client: transaction.client,
+                        })?;
                 Ok(())
             }
             Event::Withdrawal { tx, amount } if amount < Decimal::ZERO => {
                 Err(ErrorType::NegativeWithdrawal { tx }.into())
             }
             Event::Withdrawal { tx, amount } => {
-                let old = self
-                    .funds_transactions
-                    .insert(tx, TransactionInfo::new(transaction.client, -amount));
-                if old.is_some() {
+                if self.min_withdrawal.is_some_and(|min| amount < min)
+                    || self.max_withdrawal.is_some_and(|max| amount > max)
+                {
+                    return Err(ErrorType::WithdrawalOutOfBounds { tx, amount }.into());
+                }
+                self.check_unknown_account_withdrawal(transaction.client)?;
+                self.check_new_client(transaction.client)?;
+                let now = (self.clock)();
+                if self.funds_transactions.contains(tx) {
+                    return Err(ErrorType::ReusedTransactionId { tx }.into());
+                }
+                self.funds_transactions.insert(
+                    tx,
+                    TransactionInfo::new(transaction.client, SignedAmount::debit(amount), now),
+                );
+                let overdraft_limit = self.overdraft_limit(transaction.client);
+                let account = self
+                    .state
+                    .entry(transaction.client)
+                    .or_insert_with(|| ClientState::new(transaction.currency, self.minor_units));
+                account.available = available_after_withdrawal(
+                    account,
+                    overdraft_limit,
+                    self.daily_withdrawal_limit,
+                    transaction.client,
+                    tx,
+                    amount,
+                    self.withdrawal_fee,
+                )?;
+                account.withdrawn_today = account.withdrawn_today.saturating_add(amount);
+                // failed withdrawals keep their id, but don't collect any fee
+                if let Some(info) = self.funds_transactions.get_mut(tx) {
+                    info.fee = self.withdrawal_fee;
+                }
+                Ok(())
+            }
+            Event::Adjustment {
+                tx,
+                amount,
+                ref reason,
+            } => {
+                self.check_new_client(transaction.client)?;
+                let now = (self.clock)();
+                if self.funds_transactions.contains(tx) {
                     return Err(ErrorType::ReusedTransactionId { tx }.into());
                 }
-                let account = self.state.entry(transaction.client).or_default();
+                self.funds_transactions.insert(
+                    tx,
+                    TransactionInfo {
+                        reason: Some(reason.clone()),
+                        ..TransactionInfo::new(
+                            transaction.client,
+                            SignedAmount::from_balance_change(amount),
+                            now,
+                        )
+                    },
+                );
+                let account = self
+                    .state
+                    .entry(transaction.client)
+                    .or_insert_with(|| ClientState::new(transaction.currency, self.minor_units));
                 if account.locked {
                     return Err(ErrorType::LockedAccount {
                         client: transaction.client,
                     }
                     .into());
                 }
-                if account.available < amount {
-                    Err(ErrorType::InsufficientFunds {
+                if account.closed {
+                    return Err(ErrorType::ClosedAccount {
                         client: transaction.client,
-                        tx,
                     }
-                    .into())
-                } else {
-                    account.available -= amount;
-                    Ok(())
+                    .into());
                 }
+                account.available =
+                    account
+                        .available
+                        .checked_add(amount)
+                        .ok_or(ErrorType::ArithmeticOverflow {
+                            tx,
+                            client: transaction.client,
+                        })?;
+                Ok(())
             }
-            Event::Dispute { tx } => {
+            Event::Dispute { tx, ref reason } => {
+                if self.buffer_early_disputes && !self.funds_transactions.contains(tx) {
+                    self.early_disputes
+                        .entry(tx)
+                        .or_default()
+                        .push((transaction.client, reason.clone()));
+                    return Ok(());
+                }
                 let info = self
                     .funds_transactions
-                    .get_mut(&tx)
+                    .get_mut(tx)
                     .ok_or(ErrorType::UnknownTransactionForDispute { tx })?;
 
-                if info.client != transaction.client && !self.global_dispute {
-                    return Err(ErrorType::TransactionDoesNotMatchClient {
+                info.check_client(transaction.client, tx, self.global_dispute)?;
+                if !info.applied {
+                    return Err(ErrorType::TransactionNotApplied { tx }.into());
+                }
+
+                if self
+                    .dispute_window
+                    .is_some_and(|window| (self.clock)() - info.recorded_at > window)
+                {
+                    return Err(ErrorType::DisputeWindowExpired { tx }.into());
+                }
+
+                if self.require_flag_before_dispute && info.status == Status::None {
+                    return Err(ErrorType::TransactionNotFlagged { tx }.into());
+                }
+                let status = info.status.transition(DisputeAction::Dispute, tx)?;
+                let open_disputes = self
+                    .state
+                    .get(&info.client)
+                    .map_or(0, |account| account.open_disputes);
+                if self
+                    .max_open_disputes_per_client
+                    .is_some_and(|max| open_disputes as usize >= max)
+                {
+                    return Err(ErrorType::TooManyOpenDisputes {
+                        client: info.client,
+                    }
+                    .into());
+                }
+                if info.amount.is_debit() {
+                    log::warn!("Disputing client {}'s withdrawal of {}(in transaction {}), it's likely the client has already taken the funds.", transaction.client, info.amount.magnitude(), tx);
+                }
+                let account = self
+                    .state
+                    .entry(info.client)
+                    .or_insert_with(|| ClientState::new(None, self.minor_units));
+                if account.closed {
+                    return Err(ErrorType::ClosedAccount {
+                        client: info.client,
+                    }
+                    .into());
+                }
+                let Some((available, held)) = info
+                    .amount
+                    .checked_move_between_balances(account.available, account.held)
+                else {
+                    return Err(ErrorType::ArithmeticOverflow {
                         tx,
-                        client: transaction.client,
+                        client: info.client,
+                    }
+                    .into());
+                };
+                let overdraft_limit = self
+                    .overdraft_limits
+                    .get(&info.client)
+                    .copied()
+                    .unwrap_or(Decimal::ZERO);
+                if !self.allow_negative_available_on_dispute
+                    && info.amount.is_credit()
+                    && available.to_decimal() + overdraft_limit < Decimal::ZERO
+                {
+                    return Err(ErrorType::DisputeWouldOverdraw {
+                        tx,
+                        client: info.client,
                     }
                     .into());
                 }
+                account.held = held;
+                account.available = available;
+                account.open_disputes = account.open_disputes.saturating_add(1);
+                info.held = info.amount;
+                info.disputed_at = Some(transaction.timestamp.unwrap_or_else(|| (self.clock)()));
+                info.dispute_reason = reason.clone();
+                info.status = status;
+                Ok(())
+            }
+            Event::Flag { tx } => {
+                let info = self
+                    .funds_transactions
+                    .get_mut(tx)
+                    .ok_or(ErrorType::UnknownTransactionForDispute { tx })?;
 
-                if info.status != Status::None {
-                    return Err(ErrorType::TransactionAlreadyUnderDispute { tx })?;
+                info.check_client(transaction.client, tx, self.global_dispute)?;
+                if !info.applied {
+                    return Err(ErrorType::TransactionNotApplied { tx }.into());
                 }
-                info.status = Status::UnderDispute;
-                if info.amount < Decimal::ZERO {
-                    log::warn!("Disputing client {}'s withdrawal of {}(in transaction {}), it's likely the client has already taken the funds.", transaction.client, -info.amount, tx);
-                }
-                let account = self.state.entry(info.client).or_default();
-                account.held += info.amount;
-                account.available -= info.amount;
+
+                info.status = info.status.transition(DisputeAction::Flag, tx)?;
                 Ok(())
             }
             Event::Resolve { tx } => {
                 let info = self
                     .funds_transactions
-                    .get_mut(&tx)
+                    .get_mut(tx)
                     .ok_or(ErrorType::UnknownTransactionForDispute { tx })?;
 
-                if info.client != transaction.client && !self.global_dispute {
-                    return Err(ErrorType::TransactionDoesNotMatchClient {
+                info.check_client(transaction.client, tx, self.global_dispute)?;
+
+                let status = info.status.transition(DisputeAction::Resolve, tx)?;
+                let account = self
+                    .state
+                    .entry(info.client)
+                    .or_insert_with(|| ClientState::new(None, self.minor_units));
+                let Some((held, available)) = info
+                    .held
+                    .checked_move_between_balances(account.held, account.available)
+                else {
+                    return Err(ErrorType::ArithmeticOverflow {
                         tx,
-                        client: transaction.client,
+                        client: info.client,
                     }
                     .into());
-                }
+                };
+                info.status = status;
+                (account.held, account.available) = (held, available);
+                account.open_disputes = account.open_disputes.saturating_sub(1);
+                info.held = SignedAmount::ZERO;
+                Ok(())
+            }
+            Event::PartialResolve { tx, amount } if amount < Decimal::ZERO => {
+                Err(ErrorType::NegativePartialResolve { tx }.into())
+            }
+            Event::PartialResolve { tx, amount } => {
+                let info = self
+                    .funds_transactions
+                    .get_mut(tx)
+                    .ok_or(ErrorType::UnknownTransactionForDispute { tx })?;
+
+                info.check_client(transaction.client, tx, self.global_dispute)?;
 
                 if info.status != Status::UnderDispute {
-                    return Err(ErrorType::TransactionNotUnderDispute { tx })?;
+                    return Err(ErrorType::TransactionNotUnderDispute { tx }.into());
+                }
+                if amount > info.held.magnitude() {
+                    return Err(ErrorType::PartialResolveTooLarge { tx }.into());
+                }
+                let released = info.held.with_magnitude(amount);
+                let account = self
+                    .state
+                    .entry(info.client)
+                    .or_insert_with(|| ClientState::new(None, self.minor_units));
+                let Some((held, available)) =
+                    released.checked_move_between_balances(account.held, account.available)
+                else {
+                    return Err(ErrorType::ArithmeticOverflow {
+                        tx,
+                        client: info.client,
+                    }
+                    .into());
+                };
+                (account.held, account.available) = (held, available);
+                info.held = info.held - released;
+                if info.held.is_zero() {
+                    info.status = info.status.transition(DisputeAction::Resolve, tx)?;
+                    account.open_disputes = account.open_disputes.saturating_sub(1);
                 }
-                info.status = Status::None;
-                let account = self.state.entry(info.client).or_default();
-                account.held -= info.amount;
-                account.available += info.amount;
                 Ok(())
             }
             Event::Chargeback { tx } => {
                 let info = self
                     .funds_transactions
-                    .get_mut(&tx)
+                    .get_mut(tx)
+                    .ok_or(ErrorType::UnknownTransactionForDispute { tx })?;
+
+                info.check_client(transaction.client, tx, self.global_dispute)?;
+
+                // a transaction can outlive its account in an inconsistent snapshot, which shouldn't leave behind
+                // a new empty (and locked) account
+                let account =
+                    self.state
+                        .get_mut(&info.client)
+                        .ok_or(ErrorType::UnknownAccount {
+                            client: info.client,
+                        })?;
+                let status = info.status.transition(DisputeAction::Chargeback, tx)?;
+                let overflow = ErrorType::ArithmeticOverflow {
+                    tx,
+                    client: info.client,
+                };
+                // the held funds leave the account
+                let held = account
+                    .held
+                    .checked_sub(info.held.balance_change())
+                    .ok_or(overflow.clone())?;
+                // a charged back withdrawal didn't happen, so neither did its fee
+                let available = account
+                    .available
+                    .checked_add(info.fee)
+                    .and_then(|available| available.checked_sub(self.chargeback_fee))
+                    .ok_or(overflow)?;
+                info.status = status;
+                account.held = held;
+                account.available = available;
+                info.charged_back = info.held;
+                info.held = SignedAmount::ZERO;
+                account.chargebacks = account.chargebacks.saturating_add(1);
+                account.open_disputes = account.open_disputes.saturating_sub(1);
+                let newly_locked = !account.locked;
+                account.locked = true;
+                if newly_locked {
+                    match (self.batch_locks.as_mut(), self.on_lock.as_mut()) {
+                        (Some(locks), _) => locks.push((info.client, tx)),
+                        (None, Some(on_lock)) => on_lock(info.client, tx),
+                        (None, None) => {}
+                    }
+                }
+                Ok(())
+            }
+            Event::ReverseChargeback { tx } => {
+                let info = self
+                    .funds_transactions
+                    .get_mut(tx)
                     .ok_or(ErrorType::UnknownTransactionForDispute { tx })?;
 
-                if info.client != transaction.client && !self.global_dispute {
+                info.check_client(transaction.client, tx, self.global_dispute)?;
+
+                info.status = info
+                    .status
+                    .transition(DisputeAction::ReverseChargeback, tx)?;
+                let client = info.client;
+                let account = self
+                    .state
+                    .entry(client)
+                    .or_insert_with(|| ClientState::new(None, self.minor_units));
+                // only what was charged back, without what partial resolves released before, and the chargeback
+                // fee is kept
+                account.available = account
+                    .available
+                    .checked_add(info.charged_back.balance_change())
+                    .and_then(|available| available.checked_sub(info.fee))
+                    .ok_or(ErrorType::ArithmeticOverflow { tx, client })?;
+                info.charged_back = SignedAmount::ZERO;
+                // the account stays locked as long as any other of its transactions is still charged back
+                account.chargebacks = account.chargebacks.saturating_sub(1);
+                account.locked = account.chargebacks > 0 || account.seeded_locked;
+                if self.prune_settled {
+                    self.funds_transactions.remove(tx);
+                }
+                Ok(())
+            }
+            Event::Hold { tx, amount } if amount < Decimal::ZERO => {
+                Err(ErrorType::NegativeHold { tx }.into())
+            }
+            Event::Hold { tx, amount } => {
+                self.check_new_client(transaction.client)?;
+                let Entry::Vacant(entry) = self.holds.entry(tx) else {
+                    return Err(ErrorType::ReusedTransactionId { tx }.into());
+                };
+                let account = self
+                    .state
+                    .entry(transaction.client)
+                    .or_insert_with(|| ClientState::new(transaction.currency, self.minor_units));
+                if account.locked {
+                    return Err(ErrorType::LockedAccount {
+                        client: transaction.client,
+                    }
+                    .into());
+                }
+                if account.closed {
+                    return Err(ErrorType::ClosedAccount {
+                        client: transaction.client,
+                    }
+                    .into());
+                }
+                if amount > account.available.to_decimal() {
+                    return Err(ErrorType::InsufficientFunds {
+                        client: transaction.client,
+                        tx,
+                    }
+                    .into());
+                }
+                let (Some(held), Some(available)) = (
+                    account.held.checked_add(amount),
+                    account.available.checked_sub(amount),
+                ) else {
+                    return Err(ErrorType::ArithmeticOverflow {
+                        tx,
+                        client: transaction.client,
+                    }
+                    .into());
+                };
+                account.held = held;
+                account.available = available;
+                entry.insert(HoldInfo {
+                    client: transaction.client,
+                    amount,
+                });
+                Ok(())
+            }
+            Event::ReleaseHold { tx } => {
+                let Entry::Occupied(entry) = self.holds.entry(tx) else {
+                    return Err(ErrorType::UnknownHold { tx }.into());
+                };
+                if entry.get().client != transaction.client {
                     return Err(ErrorType::TransactionDoesNotMatchClient {
                         tx,
                         client: transaction.client,
+                        owner: entry.get().client,
                     }
                     .into());
                 }
-
-                if info.status != Status::UnderDispute {
-                    return Err(ErrorType::TransactionNotUnderDispute { tx })?;
+                let account = self
+                    .state
+                    .entry(entry.get().client)
+                    .or_insert_with(|| ClientState::new(None, self.minor_units));
+                let amount = entry.get().amount;
+                let (Some(held), Some(available)) = (
+                    account.held.checked_sub(amount),
+                    account.available.checked_add(amount),
+                ) else {
+                    return Err(ErrorType::ArithmeticOverflow {
+                        tx,
+                        client: transaction.client,
+                    }
+                    .into());
+                };
+                account.held = held;
+                account.available = available;
+                entry.remove();
+                Ok(())
+            }
+            Event::Void { tx } => {
+                let info = self
+                    .funds_transactions
+                    .get(tx)
+                    .ok_or(ErrorType::UnknownTransaction { tx })?;
+                info.check_client(transaction.client, tx, false)?;
+                if !info.applied {
+                    return Err(ErrorType::TransactionNotApplied { tx }.into());
                 }
-                info.status = Status::Reversed;
-                let account = self.state.entry(info.client).or_default();
-                account.held -= info.amount;
-                account.locked = true;
+                if info.status != Status::None {
+                    return Err(ErrorType::CannotVoidDisputed { tx }.into());
+                }
+                let client = info.client;
+                let amount = info.amount;
+                let account = self
+                    .state
+                    .get_mut(&client)
+                    .ok_or(ErrorType::UnknownAccount { client })?;
+                // like adjustments, voids are administrative and don't check the funds
+                account.available = account
+                    .available
+                    .checked_sub(amount.balance_change())
+                    .and_then(|available| available.checked_add(info.fee))
+                    .ok_or(ErrorType::ArithmeticOverflow { tx, client })?;
+                self.funds_transactions.remove(tx);
                 Ok(())
             }
         }
     }
 
-    pub fn account_info(&self, client: ClientId) -> AccountInfo {
-        let Some(state) = self.state.get(&client) else {
-            return AccountInfo {
-                client,
-                available: Decimal::ZERO,
-                held: Decimal::ZERO,
-                total: Decimal::ZERO,
-                locked: false,
-            };
+    /// Credits `available * rate` (rounded to the output scale, or the minor units) to every positive balance.
+    ///
+    /// Locked and closed accounts are skipped and held funds don't accrue anything.
+    /// Returns the total interest credited across all accounts.
+    ///
+    /// Fails with `InterestOverflow` for the first account whose interest or new balance (or the total) would
+    /// overflow, without crediting any interest.
+    pub fn accrue_interest(&mut self, rate: Decimal) -> Result<Decimal> {
+        let mut total = Decimal::ZERO;
+        let mut credited = Vec::new();
+        for (&client, account) in &self.state {
+            if account.locked || account.closed || account.available.to_decimal() <= Decimal::ZERO {
+                continue;
+            }
+            let interest = account
+                .available
+                .to_decimal()
+                .checked_mul(rate)
+                .map(|interest| {
+                    interest.round_dp_with_strategy(
+                        self.minor_units.unwrap_or(self.output_scale),
+                        self.rounding.into(),
+                    )
+                })
+                .ok_or(ErrorType::InterestOverflow { client })?;
+            let available = account
+                .available
+                .checked_add(interest)
+                .ok_or(ErrorType::InterestOverflow { client })?;
+            total = total
+                .checked_add(interest)
+                .ok_or(ErrorType::InterestOverflow { client })?;
+            if !interest.is_zero() {
+                credited.push((client, available));
+            }
+        }
+        for (client, available) in credited {
+            self.dirty.insert(client);
+            if let Some(account) = self.state.get_mut(&client) {
+                account.available = available;
+            }
+        }
+        Ok(total)
+    }
+
+    /// Resolves every dispute opened more than `max_age` before `now`, returning their funds to available.
+    ///
+    /// Each is resolved as if its owner sent a resolve through `handle`, so it is journaled, reported to the
+    /// observer and can be rolled back. Returns the ids of the resolved transactions, in increasing order.
+    pub fn auto_resolve_expired(
+        &mut self,
+        now: Timestamp,
+        max_age: Duration,
+    ) -> Vec<TransactionId> {
+        let max_age = i64::try_from(max_age.as_secs()).unwrap_or(i64::MAX);
+        let mut expired: Vec<_> = self
+            .funds_transactions
+            .iter()
+            .filter(|(_, info)| {
+                info.status == Status::UnderDispute
+                    && info
+                        .disputed_at
+                        .is_some_and(|disputed_at| now.saturating_sub(disputed_at) > max_age)
+            })
+            .map(|(tx, info)| (tx, info.client))
+            .collect();
+        expired.sort_unstable();
+        expired
+            .into_iter()
+            .filter_map(|(tx, client)| {
+                let resolve = Transaction::new(client, Event::Resolve { tx });
+                match self.handle(resolve) {
+                    Ok(_) => Some(tx),
+                    Err(err) => {
+                        log::log!(
+                            err.severity.level(),
+                            "Error resolving expired dispute: {}",
+                            err
+                        );
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Number of accounts, without going through them like `all_accounts().count()`
+    pub fn account_count(&self) -> usize {
+        self.state.len()
+    }
+
+    /// Number of recorded transaction ids, those `contains_transaction` is true for.
+    ///
+    /// This is not the number of applied transactions: deposits and withdrawals rejected after their id was
+    /// recorded count too, like withdrawals that failed on the client's funds or deposits to a locked account.
+    /// Transactions rejected before that (invalid amounts, reused ids) don't. Pruned and voided transactions no
+    /// longer count.
+    pub fn transaction_count(&self) -> usize {
+        self.funds_transactions.len()
+    }
+
+    /// Whether a deposit, withdrawal or adjustment with this id was already recorded, resubmitting any of them
+    /// with it would fail with `ReusedTransactionId`.
+    ///
+    /// Only those three are recorded. Flags, disputes, resolves, chargebacks and voids only refer to them, and
+    /// holds have ids of their own, so none of their ids count here.
+    pub fn contains_transaction(&self, tx: TransactionId) -> bool {
+        self.funds_transactions.contains(tx)
+    }
+
+    /// Amount of a recorded deposit or withdrawal, withdrawals are negative (like `DisputeView::held`)
+    pub fn amount_of(&self, tx: TransactionId) -> Result<Decimal> {
+        self.funds_transactions
+            .get(tx)
+            .map(|info| info.amount.balance_change())
+            .ok_or_else(|| ErrorType::UnknownTransaction { tx }.into())
+    }
+
+    /// The reason given for an adjustment, `None` for any other (or unknown) transaction
+    pub fn adjustment_reason(&self, tx: TransactionId) -> Option<&str> {
+        self.funds_transactions
+            .get(tx)
+            .and_then(|info| info.reason.as_deref())
+    }
+
+    /// All transactions currently under dispute, in no particular order
+    pub fn disputed_transactions(&self) -> impl Iterator<Item = DisputeView> + '_ {
+        self.funds_transactions
+            .iter()
+            .filter(|(_, info)| info.status == Status::UnderDispute)
+            .map(|(tx, info)| DisputeView {
+                tx,
+                client: info.client,
+                held: info.held.balance_change(),
+                reason: info.dispute_reason.clone(),
+            })
+    }
+
+    /// Every deposit, withdrawal and adjustment made by `client`, ordered by transaction id.
+    ///
+    /// This scans all recorded transactions, so it is O(n) in the total number of transactions (not just the
+    /// client's), for large datasets consider pruning old transactions with `prune_before`.
+    pub fn client_transactions(&self, client: ClientId) -> Vec<TransactionView> {
+        let mut transactions: Vec<_> = self
+            .funds_transactions
+            .iter()
+            .filter(|(_, info)| info.client == client)
+            .map(|(tx, info)| TransactionView {
+                tx,
+                amount: info.amount.balance_change(),
+                status: info.status,
+            })
+            .collect();
+        transactions.sort_by_key(|view| view.tx);
+        transactions
+    }
+
+    /// Creates an account with opening balances, e.g. when migrating from a legacy system instead of replaying
+    /// its whole history. Fails with `AccountAlreadyExists` if the client already has an account.
+    ///
+    /// Afterwards the account behaves exactly like one built from transactions, but no transactions are recorded
+    /// for the seeded balances, so they can't be disputed. Seeded held funds can't be released by any resolve.
+    ///
+    /// The balances must be exact in minor units (see `with_minor_units`), otherwise this fails with
+    /// `InexactSeed`, and must fit a balance and add up to a total that fits a `Decimal`, otherwise this fails
+    /// with `SeedOverflow`.
+    pub fn seed_account(
+        &mut self,
+        client: ClientId,
+        available: Decimal,
+        held: Decimal,
+        locked: bool,
+    ) -> Result<()> {
+        if self.state.contains_key(&client) {
+            return Err(ErrorType::AccountAlreadyExists { client }.into());
+        }
+        self.check_new_client(client)?;
+        if let Some(scale) = self.minor_units {
+            if let Some(amount) = [available, held]
+                .into_iter()
+                .find(|amount| amount.normalize().scale() > scale)
+            {
+                return Err(ErrorType::InexactSeed { client, amount }.into());
+            }
+        }
+        let zero = Balance::zero(self.minor_units);
+        let (Some(available), Some(held), Some(_)) = (
+            zero.checked_add(available),
+            zero.checked_add(held),
+            available.checked_add(held),
+        ) else {
+            return Err(ErrorType::SeedOverflow { client }.into());
         };
-        AccountInfo {
+        self.state.insert(
             client,
-            available: state.available,
-            held: state.held,
-            total: state.available + state.held,
-            locked: state.locked,
+            ClientState {
+                available,
+                held,
+                locked,
+                seeded_locked: locked,
+                ..Default::default()
+            },
+        );
+        self.dirty.insert(client);
+        Ok(())
+    }
+
+    /// Closes the client's account for good, every later deposit, withdrawal, adjustment, hold or dispute on it
+    /// fails with `ClosedAccount`.
+    ///
+    /// Only accounts without held funds can be closed, so nothing may be under dispute or on hold, otherwise this
+    /// fails with `CannotCloseWithOpenDisputes`. Closing isn't a transaction, so it can't be rolled back.
+    pub fn close_account(&mut self, client: ClientId) -> Result<()> {
+        let account = self
+            .state
+            .get(&client)
+            .ok_or(ErrorType::UnknownAccount { client })?;
+        if !account.held.is_zero() || account.open_disputes > 0 {
+            return Err(ErrorType::CannotCloseWithOpenDisputes { client }.into());
+        }
+        if let Some(account) = self.state.get_mut(&client) {
+            account.closed = true;
+            self.dirty.insert(client);
         }
+        Ok(())
     }
 
-    pub fn all_accounts(&self) -> impl Iterator<Item = AccountInfo> + '_ {
-        self.state.iter().map(|(&client, state)| AccountInfo {
-            client,
-            available: state.available,
-            held: state.held,
-            total: state.available + state.held,
-            locked: state.locked,
+    /// Adds the accounts, transactions and holds of `other`, e.g. to combine independently processed shards.
+    ///
+    /// Transaction ids are globally unique, so if both engines recorded the same transaction (or hold) id the
+    /// merge fails with `ReusedTransactionId` for the lowest such id and nothing is changed. Clients known to
+    /// both engines get their available and held funds summed, and are locked if either account was locked. The
+    /// currency of this engine's account is kept if both have one. If a sum overflows the merge fails with
+    /// `MergeOverflow` for the first such client of `other`, and nothing is changed either.
+    ///
+    /// Only state is merged, the settings of `other` are dropped and this engine's rollback history is cleared,
+    /// since it could no longer be applied.
+    pub fn merge(&mut self, other: Engine) -> Result<()> {
+        let collision = other
+            .funds_transactions
+            .iter()
+            .map(|(tx, _)| tx)
+            .filter(|&tx| self.funds_transactions.contains(tx))
+            .chain(
+                other
+                    .holds
+                    .keys()
+                    .copied()
+                    .filter(|tx| self.holds.contains_key(tx)),
+            )
+            .min();
+        if let Some(tx) = collision {
+            return Err(ErrorType::ReusedTransactionId { tx }.into());
+        }
+        // every sum is checked before anything is merged
+        let mut sums = HashMap::new();
+        for (&client, state) in &other.state {
+            if let Some(account) = self.state.get(&client) {
+                let available = account.available.checked_add(state.available.to_decimal());
+                let held = account.held.checked_add(state.held.to_decimal());
+                let sum = available
+                    .zip(held)
+                    .ok_or(ErrorType::MergeOverflow { client })?;
+                sums.insert(client, sum);
+            }
+        }
+
+        for (client, state) in other.state {
+            self.dirty.insert(client);
+            match self.state.get_mut(&client) {
+                None => {
+                    self.state.insert(client, state);
+                }
+                Some(account) => {
+                    (account.available, account.held) = sums[&client];
+                    account.locked |= state.locked;
+                    account.chargebacks = account.chargebacks.saturating_add(state.chargebacks);
+                    account.open_disputes =
+                        account.open_disputes.saturating_add(state.open_disputes);
+                    account.seeded_locked |= state.seeded_locked;
+                    account.closed |= state.closed;
+                    account.withdrawn_today = account
+                        .withdrawn_today
+                        .saturating_add(state.withdrawn_today);
+                    account.currency = account.currency.or(state.currency);
+                    account.last_activity = account.last_activity.max(state.last_activity);
+                }
+            }
+        }
+        for (tx, info) in other.funds_transactions.iter() {
+            self.funds_transactions.insert(tx, info.clone());
+        }
+        self.holds.extend(other.holds);
+        self.history.clear();
+        Ok(())
+    }
+
+    /// What makes up the client's held funds: every transaction of theirs under dispute ordered by id, then
+    /// every hold placed on their account ordered by hold id, so the amounts add up to `AccountInfo::held`
+    pub fn account_holds(&self, client: ClientId) -> Vec<HeldFunds> {
+        let mut disputes: Vec<_> = self
+            .funds_transactions
+            .iter()
+            .filter(|(_, info)| info.client == client && info.status == Status::UnderDispute)
+            .map(|(tx, info)| HeldFunds::Dispute {
+                tx,
+                amount: info.held.balance_change(),
+            })
+            .collect();
+        let mut holds: Vec<_> = self
+            .holds
+            .iter()
+            .filter(|(_, hold)| hold.client == client)
+            .map(|(&id, hold)| HeldFunds::Hold {
+                id,
+                amount: hold.amount,
+            })
+            .collect();
+        disputes.sort_by_key(HeldFunds::id);
+        holds.sort_by_key(HeldFunds::id);
+        disputes.append(&mut holds);
+        disputes
+    }
+
+    /// Checks that the held funds of every account add up to what its open disputes and holds hold, failing with
+    /// `InvariantViolation` on the first one that doesn't, e.g. after `merge` or `from_bytes`.
+    ///
+    /// Also fails if a transaction that isn't under dispute still holds funds, or if a dispute or hold belongs to
+    /// a client without an account. This goes through every recorded transaction, so it's meant for occasional
+    /// self-checks rather than after every transaction.
+    pub fn verify_invariants(&self) -> Result<()> {
+        let violation = |message: String| Err(ErrorType::InvariantViolation { message }.into());
+        let mut held: HashMap<ClientId, Decimal> = HashMap::new();
+        for (tx, info) in self.funds_transactions.iter() {
+            if info.status != Status::UnderDispute {
+                if !info.held.is_zero() {
+                    return violation(format!(
+                        "transaction {tx} holds {} without being under dispute",
+                        info.held.balance_change()
+                    ));
+                }
+                continue;
+            }
+            if !self.state.contains_key(&info.client) {
+                return violation(format!(
+                    "transaction {tx} is under dispute, but client {} has no account",
+                    info.client
+                ));
+            }
+            let client_held = held.entry(info.client).or_default();
+            *client_held = client_held.saturating_add(info.held.balance_change());
+        }
+        for (&tx, hold) in &self.holds {
+            if !self.state.contains_key(&hold.client) {
+                return violation(format!(
+                    "hold {tx} is placed on client {}, who has no account",
+                    hold.client
+                ));
+            }
+            let client_held = held.entry(hold.client).or_default();
+            *client_held = client_held.saturating_add(hold.amount);
+        }
+        for (&client, state) in &self.state {
+            let expected = held.get(&client).copied().unwrap_or_default();
+            if state.held.to_decimal() != expected {
+                return violation(format!(
+                    "client {client} holds {}, but their open disputes and holds add up to {expected}",
+                    state.held.to_decimal()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Hash of the accounts, transactions and holds that only depends on their contents, not on the order they
+    /// were created in, so replicas can cheaply check they hold the same state.
+    ///
+    /// It is stable across runs and processes, but not across platforms with a different endianness. The time
+    /// transactions were recorded and disputed at is left out, since that can depend on each replica's clock.
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = Fnv1a::default();
+        let mut accounts: Vec<_> = self.state.iter().collect();
+        accounts.sort_by_key(|&(&client, _)| client);
+        for (client, state) in accounts {
+            let (available, held) = (state.available.to_decimal(), state.held.to_decimal());
+            (client, available, held, state.locked).hash(&mut hasher);
+            (state.currency, state.last_activity, state.closed).hash(&mut hasher);
+            (
+                state.withdrawn_today,
+                state.chargebacks,
+                state.seeded_locked,
+                state.open_disputes,
+            )
+                .hash(&mut hasher);
+        }
+        let mut transactions: Vec<_> = self.funds_transactions.iter().collect();
+        transactions.sort_by_key(|&(tx, _)| tx);
+        for (tx, info) in transactions {
+            (tx, info.client, info.amount, info.status).hash(&mut hasher);
+            (&info.reason, info.held, info.fee, info.applied).hash(&mut hasher);
+            info.charged_back.hash(&mut hasher);
+        }
+        let mut holds: Vec<_> = self.holds.iter().collect();
+        holds.sort_by_key(|&(&tx, _)| tx);
+        for (tx, hold) in holds {
+            (tx, hold.client, hold.amount).hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// Compact binary checkpoint of the accounts, transactions and holds, to be loaded with `from_bytes`.
+    ///
+    /// Settings (like `set_global_dispute`), the observer and the rollback history are not included.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let transactions: HashMap<_, _> = self.funds_transactions.iter().collect();
+        bincode::serialize(&(&self.state, transactions, &self.holds))
+            .expect("engine state is always serializable")
+    }
+
+    /// A new engine, with default settings, holding the state saved by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Engine> {
+        let (state, funds_transactions, holds): (
+            IndexMap<ClientId, ClientState>,
+            HashMap<TransactionId, TransactionInfo>,
+            _,
+        ) = bincode::deserialize(bytes).map_err(|err| ErrorType::InvalidSnapshot {
+            message: err.to_string(),
+        })?;
+        Ok(Engine {
+            dirty: state.keys().copied().collect(),
+            state,
+            funds_transactions: Box::new(funds_transactions),
+            holds,
+            ..Engine::new()
         })
     }
+
+    /// Latest timestamp of the client's successfully applied transactions, transactions without a timestamp
+    /// don't count
+    pub fn last_activity(&self, client: ClientId) -> Option<Timestamp> {
+        self.state
+            .get(&client)
+            .and_then(|state| state.last_activity)
+    }
+
+    pub fn account_info(&self, client: ClientId) -> AccountInfo {
+        match self.state.get(&client) {
+            Some(state) => state.info(client),
+            None => ClientState::default().info(client),
+        }
+    }
+
+    /// Ids of every known client, cheaper than `all_accounts` when the balances aren't needed.
+    ///
+    /// A client is only known once one of their transactions created the account, which happens for every
+    /// deposit or withdrawal that gets past the amount and transaction id checks.
+    pub fn client_ids(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.state.keys().copied()
+    }
+
+    /// Every account, in no particular order (see `all_accounts_sorted` and `accounts_in_insertion_order`)
+    pub fn all_accounts(&self) -> impl Iterator<Item = AccountInfo> + '_ {
+        self.state.iter().map(|(&client, state)| state.info(client))
+    }
+
+    /// Every account in the order it was created in, by the client's first transaction or `seed_account`.
+    ///
+    /// `rollback` keeps the order of the accounts that remain, an account rolled back and created again goes
+    /// last. After a `merge` the accounts only the other engine knew come after all of this engine's ones.
+    pub fn accounts_in_insertion_order(&self) -> impl Iterator<Item = AccountInfo> + '_ {
+        self.state.iter().map(|(&client, state)| state.info(client))
+    }
+
+    /// Accounts changed since the previous call (or since the engine was created, or restored with `from_bytes`),
+    /// ordered by client id, e.g. to only export what changed.
+    ///
+    /// Every change counts: applied transactions, rolling them back, interest, seeding, closing and merging
+    /// accounts. Rejected transactions only count if they created the account.
+    pub fn take_dirty_accounts(&mut self) -> Vec<AccountInfo> {
+        let mut dirty: Vec<_> = self.dirty.drain().collect();
+        dirty.sort();
+        dirty
+            .into_iter()
+            .filter_map(|client| self.state.get(&client).map(|state| state.info(client)))
+            .collect()
+    }
+
+    /// Accounts locked by a chargeback (or seeded as locked), e.g. for compliance reports
+    pub fn locked_accounts(&self) -> impl Iterator<Item = AccountInfo> + '_ {
+        self.accounts_where(true)
+    }
+
+    /// Every account that isn't locked, see `locked_accounts`
+    pub fn unlocked_accounts(&self) -> impl Iterator<Item = AccountInfo> + '_ {
+        self.accounts_where(false)
+    }
+
+    fn accounts_where(&self, locked: bool) -> impl Iterator<Item = AccountInfo> + '_ {
+        self.state
+            .iter()
+            .filter(move |(_, state)| state.locked == locked)
+            .map(|(&client, state)| state.info(client))
+    }
+
+    /// Same as `all_accounts`, ordered by client id
+    pub fn all_accounts_sorted(&self) -> Vec<AccountInfo> {
+        let mut accounts: Vec<_> = self.all_accounts().collect();
+        accounts.sort_by_key(|info| info.client);
+        accounts
+    }
+
+    /// Every account as a JSON array ordered by client id, with the same fields as the CSV output, to embed the
+    /// results in a larger JSON report
+    pub fn accounts_json(&self) -> serde_json::Value {
+        serde_json::to_value(self.all_accounts_sorted())
+            .expect("AccountInfo always serializes to JSON")
+    }
+}
+
+/// The checks on the account itself shared by withdrawals and `Engine::preview_withdrawal`
+fn available_after_withdrawal(
+    account: &ClientState,
+    overdraft_limit: Decimal,
+    daily_limit: Option<Decimal>,
+    client: ClientId,
+    tx: TransactionId,
+    amount: Decimal,
+    fee: Decimal,
+) -> Result<Balance> {
+    if account.locked {
+        return Err(ErrorType::LockedAccount { client }.into());
+    }
+    if account.closed {
+        return Err(ErrorType::ClosedAccount { client }.into());
+    }
+    // more than fits in a `Decimal` is over any limit
+    if daily_limit.is_some_and(|limit| {
+        account
+            .withdrawn_today
+            .checked_add(amount)
+            .is_none_or(|withdrawn| withdrawn > limit)
+    }) {
+        return Err(ErrorType::DailyLimitExceeded { client }.into());
+    }
+    let overflow = || ErrorType::ArithmeticOverflow { tx, client };
+    let debited = amount.checked_add(fee).ok_or_else(overflow)?;
+    let funds = account
+        .available
+        .to_decimal()
+        .checked_add(overdraft_limit)
+        .ok_or_else(overflow)?;
+    if funds < debited {
+        return Err(ErrorType::InsufficientFunds { client, tx }.into());
+    }
+    Ok(account
+        .available
+        .checked_sub(debited)
+        .ok_or_else(overflow)?)
+}
+
+fn restore<K: std::hash::Hash + Eq, V>(map: &mut HashMap<K, V>, key: K, value: Option<V>) {
+    match value {
+        Some(value) => map.insert(key, value),
+        None => map.remove(&key),
+    };
+}
+
+fn notify(observer: &mut dyn EngineObserver, transaction: &Transaction) {
+    let client = transaction.client;
+    match transaction.event {
+        Event::Deposit { tx, amount } => observer.on_deposit(client, tx, amount),
+        Event::Withdrawal { tx, amount } => observer.on_withdrawal(client, tx, amount),
+        Event::Adjustment { tx, amount, .. } => observer.on_adjustment(client, tx, amount),
+        Event::Flag { tx } => observer.on_flag(client, tx),
+        Event::Dispute { tx, .. } => observer.on_dispute(client, tx),
+        Event::Resolve { tx } => observer.on_resolve(client, tx),
+        Event::PartialResolve { tx, amount } => observer.on_partial_resolve(client, tx, amount),
+        Event::Chargeback { tx } => observer.on_chargeback(client, tx),
+        Event::ReverseChargeback { tx } => observer.on_reverse_chargeback(client, tx),
+        Event::Hold { tx, amount } => observer.on_hold(client, tx, amount),
+        Event::ReleaseHold { tx } => observer.on_release_hold(client, tx),
+        Event::Void { tx } => observer.on_void(client, tx),
+    }
+}
+
+/// 64 bit FNV-1a, unlike the std hashers (`RandomState` and `DefaultHasher`) it gives the same result in every
+/// process and with every Rust release
+pub struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// What an accepted transaction did to the engine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Applied {
+    /// Some account, transaction or hold changed
+    Mutated,
+    NoOp,
+}
+
+/// Part of a client's held funds, see `Engine::account_holds`.
+///
+/// Hold ids and transaction ids are separate namespaces, the same number can identify both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeldFunds {
+    /// Held by the dispute of transaction `tx`, negative when a withdrawal is disputed
+    Dispute { tx: TransactionId, amount: Decimal },
+    /// Held by the hold with this id
+    Hold { id: TransactionId, amount: Decimal },
+}
+
+impl HeldFunds {
+    /// The transaction or hold id
+    pub fn id(&self) -> TransactionId {
+        match *self {
+            HeldFunds::Dispute { tx, .. } => tx,
+            HeldFunds::Hold { id, .. } => id,
+        }
+    }
+
+    pub fn amount(&self) -> Decimal {
+        match *self {
+            HeldFunds::Dispute { amount, .. } | HeldFunds::Hold { amount, .. } => amount,
+        }
+    }
 }
 
-#[derive(PartialEq, Eq)]
-enum Status {
+/// A transaction under dispute, `held` is negative when a withdrawal is disputed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisputeView {
+    pub tx: TransactionId,
+    pub client: ClientId,
+    pub held: Decimal,
+    /// The reason code the dispute came with, if any
+    pub reason: Option<String>,
+}
+
+/// A recorded transaction, `amount` is positive for deposits and negative for withdrawals
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransactionView {
+    pub tx: TransactionId,
+    pub amount: Decimal,
+    pub status: Status,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Status {
+    /// Never disputed, or the dispute was resolved
     None,
+    /// Flagged for review, funds are not held yet
+    Flagged,
     UnderDispute,
+    /// Charged back
     Reversed,
 }
-struct TransactionInfo {
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DisputeAction {
+    Flag,
+    Dispute,
+    Resolve,
+    Chargeback,
+    ReverseChargeback,
+}
+
+impl Status {
+    /// The only legal transitions are None -> [Flagged ->] UnderDispute -> {None, Reversed} and Reversed -> None.
+    ///
+    /// Both a resolve and a reversed chargeback take the transaction back to None, so it can be disputed (and
+    /// resolved or charged back) again any number of times.
+    fn transition(self, action: DisputeAction, tx: TransactionId) -> Result<Status> {
+        match (self, action) {
+            (Status::None, DisputeAction::Flag) => Ok(Status::Flagged),
+            (Status::None | Status::Flagged, DisputeAction::Dispute) => Ok(Status::UnderDispute),
+            (Status::UnderDispute, DisputeAction::Resolve) => Ok(Status::None),
+            (Status::UnderDispute, DisputeAction::Chargeback) => Ok(Status::Reversed),
+            (Status::Reversed, DisputeAction::ReverseChargeback) => Ok(Status::None),
+            (Status::Flagged | Status::Reversed, DisputeAction::Flag) => {
+                Err(ErrorType::CannotFlag { tx }.into())
+            }
+            (_, DisputeAction::Flag | DisputeAction::Dispute) => {
+                Err(ErrorType::TransactionAlreadyUnderDispute { tx }.into())
+            }
+            (_, DisputeAction::Resolve | DisputeAction::Chargeback) => {
+                Err(ErrorType::TransactionNotUnderDispute { tx }.into())
+            }
+            (_, DisputeAction::ReverseChargeback) => {
+                Err(ErrorType::TransactionNotChargedBack { tx }.into())
+            }
+        }
+    }
+}
+/// A recorded deposit, withdrawal or adjustment and its dispute state, as kept in a `TransactionStore`
+#[derive(Clone, Serialize, Deserialize, PartialEq)]
+pub struct TransactionInfo {
     client: ClientId,
-    amount: Decimal,
+    #[serde(with = "signed_amount_bytes")]
+    amount: SignedAmount,
     status: Status,
+    /// Time according to the engine's clock
+    recorded_at: i64,
+    /// Why an adjustment was made, kept for audit
+    reason: Option<String>,
+    /// What is still held while under dispute, partial resolves release part of it
+    #[serde(with = "signed_amount_bytes")]
+    held: SignedAmount,
+    /// When the latest dispute was opened, from the dispute's timestamp or else the engine's clock
+    disputed_at: Option<Timestamp>,
+    /// The reason code given with the latest dispute
+    dispute_reason: Option<String>,
+    /// Withdrawal fee taken with the transaction, zero once refunded
+    #[serde(with = "decimal_bytes")]
+    fee: Decimal,
+    /// Rejected transactions are recorded too once they pass the id check, but never changed the balance
+    applied: bool,
+    /// What the latest chargeback took from the account, a reversed chargeback gives back exactly this
+    #[serde(with = "signed_amount_bytes")]
+    charged_back: SignedAmount,
 }
 
 impl TransactionInfo {
-    fn new(client: ClientId, amount: Decimal) -> Self {
+    fn new(client: ClientId, amount: SignedAmount, recorded_at: i64) -> Self {
         Self {
             client,
             amount,
             status: Status::None,
+            recorded_at,
+            reason: None,
+            held: SignedAmount::ZERO,
+            disputed_at: None,
+            dispute_reason: None,
+            fee: Decimal::ZERO,
+            applied: false,
+            charged_back: SignedAmount::ZERO,
         }
     }
-}
-#[derive(Default)]
-struct ClientState {
-    available: Decimal,
-    held: Decimal,
-    locked: bool,
-}
-
-#[cfg(test)]
-mod tests {
-    use rust_decimal_macros::dec;
+
+    /// Only the owner can dispute (or resolve, or charge back) a transaction, unless `global_dispute` is set.
+    ///
+    /// Withdrawals always require the owner, so one client can't dispute another's withdrawal.
+    fn check_client(
+        &self,
+        client: ClientId,
+        tx: TransactionId,
+        global_dispute: bool,
+    ) -> Result<()> {
+        let is_withdrawal = self.amount.is_debit();
+        if self.client != client && (!global_dispute || is_withdrawal) {
+            return Err(ErrorType::TransactionDoesNotMatchClient {
+                tx,
+                client,
+                owner: self.client,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+/// Funds moved from available to held by an operator, unrelated to any dispute
+#[derive(Clone, Serialize, Deserialize, PartialEq)]
+struct HoldInfo {
+    client: ClientId,
+    #[serde(with = "decimal_bytes")]
+    amount: Decimal,
+}
+
+/// The client and reason of each dispute waiting for a transaction that wasn't handled yet
+type BufferedDisputes = Vec<(ClientId, Option<String>)>;
+
+/// Everything a single handled transaction could have changed, `None` meaning the entry didn't exist yet
+#[derive(Default)]
+struct Undo {
+    accounts: Vec<(ClientId, Option<ClientState>)>,
+    transaction: Option<(TransactionId, Option<TransactionInfo>)>,
+    hold: Option<(TransactionId, Option<HoldInfo>)>,
+    /// The disputes buffered for the transaction, see `set_buffer_early_disputes`
+    early_disputes: Option<(TransactionId, Option<BufferedDisputes>)>,
+}
+
+#[derive(Default, Clone, Serialize, Deserialize, PartialEq)]
+struct ClientState {
+    #[serde(with = "balance_bytes")]
+    available: Balance,
+    #[serde(with = "balance_bytes")]
+    held: Balance,
+    locked: bool,
+    currency: Option<Currency>,
+    last_activity: Option<Timestamp>,
+    closed: bool,
+    /// Withdrawn since the last `Engine::reset_daily_counters`
+    #[serde(with = "decimal_bytes")]
+    withdrawn_today: Decimal,
+    /// Transactions charged back and not reversed, the account stays locked while any are
+    chargebacks: u32,
+    /// Seeded as locked, which no reversed chargeback unlocks
+    seeded_locked: bool,
+    /// Transactions of the client under dispute, kept so `max_open_disputes_per_client` doesn't scan them all
+    open_disputes: u32,
+}
+
+impl ClientState {
+    /// An empty account, keeping its balances in minor units of `minor_units` if there are any
+    fn new(currency: Option<Currency>, minor_units: Option<u32>) -> Self {
+        Self {
+            available: Balance::zero(minor_units),
+            held: Balance::zero(minor_units),
+            currency,
+            ..Default::default()
+        }
+    }
+
+    fn info(&self, client: ClientId) -> AccountInfo {
+        let (available, held) = (self.available.to_decimal(), self.held.to_decimal());
+        AccountInfo {
+            client,
+            available: normalize_zero(available),
+            held: normalize_zero(held),
+            // disputed funds can push the two past what their sum can hold
+            total: normalize_zero(available.saturating_add(held)),
+            locked: self.locked,
+            currency: self.currency,
+            closed: self.closed,
+        }
+    }
+}
+
+/// `Decimal` is (de)serialized as a string by default, which bincode can't read back, so snapshots use its
+/// fixed 16 byte representation instead
+mod decimal_bytes {
+    use rust_decimal::Decimal;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(amount: &Decimal, serializer: S) -> Result<S::Ok, S::Error> {
+        amount.serialize().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Decimal, D::Error> {
+        <[u8; 16]>::deserialize(deserializer).map(Decimal::deserialize)
+    }
+}
+
+/// A `Decimal` balance is written as its `decimal_bytes`, one in minor units as its scale and the 16 bytes of its
+/// units
+mod balance_bytes {
+    use rust_decimal::Decimal;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::balance::Balance;
+
+    pub fn serialize<S: Serializer>(balance: &Balance, serializer: S) -> Result<S::Ok, S::Error> {
+        match *balance {
+            Balance::Decimal(amount) => (None::<u32>, amount.serialize()).serialize(serializer),
+            Balance::Minor { units, scale } => {
+                (Some(scale), units.to_le_bytes()).serialize(serializer)
+            }
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Balance, D::Error> {
+        let (scale, bytes) = <(Option<u32>, [u8; 16])>::deserialize(deserializer)?;
+        Ok(match scale {
+            Some(scale) => Balance::Minor {
+                units: i128::from_le_bytes(bytes),
+                scale,
+            },
+            None => Balance::Decimal(Decimal::deserialize(bytes)),
+        })
+    }
+}
+
+/// Same format as `decimal_bytes`, so snapshots don't depend on how amounts are typed in memory
+mod signed_amount_bytes {
+    use serde::{Deserializer, Serializer};
+
+    use super::decimal_bytes;
+    use crate::SignedAmount;
+
+    pub fn serialize<S: Serializer>(
+        amount: &SignedAmount,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        decimal_bytes::serialize(&amount.balance_change(), serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<SignedAmount, D::Error> {
+        decimal_bytes::deserialize(deserializer).map(SignedAmount::from_balance_change)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicI64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    };
+
+    use rust_decimal_macros::dec;
 
     use super::*;
+    use crate::TransactionIdAllocator;
+
+    impl Engine {
+        /// An engine holding exactly these accounts and transactions, which don't have to be consistent
+        fn from_parts(
+            accounts: impl IntoIterator<Item = (ClientId, ClientState)>,
+            transactions: impl IntoIterator<Item = (TransactionId, TransactionInfo)>,
+        ) -> Engine {
+            Engine {
+                state: accounts.into_iter().collect(),
+                funds_transactions: Box::new(transactions.into_iter().collect::<HashMap<_, _>>()),
+                ..Engine::new()
+            }
+        }
+    }
 
     #[test]
     fn test_simple() {
@@ -220,251 +2324,3482 @@ mod tests {
         let client = 1;
         // add some funds
         engine
-            .handle(Transaction {
+            .handle(Transaction::new(
                 client,
-                event: Event::Deposit {
+                Event::Deposit {
                     tx: 1,
                     amount: dec!(1.2345),
                 },
-            })
+            ))
+            .unwrap();
+
+        // withdraw too much
+        assert_eq!(
+            engine
+                .handle(Transaction::new(
+                    client,
+                    Event::Withdrawal {
+                        tx: 2,
+                        amount: 2.into()
+                    }
+                ))
+                .unwrap_err()
+                .error_type,
+            ErrorType::InsufficientFunds { client, tx: 2 }
+        );
+        engine
+            .handle(Transaction::new(
+                client,
+                Event::Withdrawal {
+                    tx: 3,
+                    amount: dec!(0.1234),
+                },
+            ))
             .unwrap();
 
-        // withdraw too much
+        let info: Vec<_> = engine.all_accounts().collect();
+        assert_eq!(
+            info,
+            vec![AccountInfo {
+                client,
+                available: dec!(1.1111),
+                held: Decimal::ZERO,
+                total: dec!(1.1111),
+                locked: false,
+                currency: None,
+                closed: false,
+            }]
+        )
+    }
+
+    struct Wrapper {
+        engine: Engine,
+        ids: TransactionIdAllocator,
+    }
+
+    #[derive(Debug, Copy, Clone)]
+    struct CommitedTransaction {
+        client: ClientId,
+        tx: TransactionId,
+    }
+
+    impl Wrapper {
+        fn new() -> Self {
+            Self {
+                engine: Engine::new(),
+                ids: TransactionIdAllocator::new(),
+            }
+        }
+
+        fn deposit(
+            &mut self,
+            client: ClientId,
+            amount: impl Into<Decimal>,
+        ) -> Result<CommitedTransaction> {
+            let tx = self.ids.next_id()?;
+
+            self.engine
+                .handle(Transaction::new(
+                    client,
+                    Event::Deposit {
+                        tx,
+                        amount: amount.into(),
+                    },
+                ))
+                .map(|_| CommitedTransaction { client, tx })
+        }
+
+        fn withdraw(
+            &mut self,
+            client: ClientId,
+            amount: impl Into<Decimal>,
+        ) -> Result<CommitedTransaction> {
+            let tx = self.ids.next_id()?;
+
+            self.engine
+                .handle(Transaction::new(
+                    client,
+                    Event::Withdrawal {
+                        tx,
+                        amount: amount.into(),
+                    },
+                ))
+                .map(|_| CommitedTransaction { client, tx })
+        }
+
+        fn dispute(&mut self, transaction: CommitedTransaction) -> Result<Applied> {
+            self.engine.handle(Transaction::new(
+                transaction.client,
+                Event::Dispute {
+                    tx: transaction.tx,
+                    reason: None,
+                },
+            ))
+        }
+
+        fn resolve(&mut self, transaction: CommitedTransaction) -> Result<Applied> {
+            self.engine.handle(Transaction::new(
+                transaction.client,
+                Event::Resolve { tx: transaction.tx },
+            ))
+        }
+
+        fn chargeback(&mut self, transaction: CommitedTransaction) -> Result<Applied> {
+            self.engine.handle(Transaction::new(
+                transaction.client,
+                Event::Chargeback { tx: transaction.tx },
+            ))
+        }
+
+        fn reverse_chargeback(&mut self, transaction: CommitedTransaction) -> Result<Applied> {
+            self.engine.handle(Transaction::new(
+                transaction.client,
+                Event::ReverseChargeback { tx: transaction.tx },
+            ))
+        }
+
+        fn account_info(&self, client: ClientId) -> AccountInfo {
+            self.engine.account_info(client)
+        }
+    }
+
+    #[test]
+    fn test_multiple() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let client_a = 1;
+        let client_b = 2;
+
+        engine.deposit(client_a, 5)?;
+        assert_eq!(engine.account_info(client_a).available, dec!(5));
+        assert_eq!(engine.account_info(client_b).available, dec!(0));
+
+        engine.deposit(client_b, 10)?;
+        assert_eq!(engine.account_info(client_a).available, dec!(5));
+        assert_eq!(engine.account_info(client_b).available, dec!(10));
+
+        // this shouldn't work, even though b has enough, they have separate accounts
+        assert!(engine.withdraw(client_a, 10).is_err());
+        assert_eq!(engine.account_info(client_a).available, dec!(5));
+        assert_eq!(engine.account_info(client_b).available, dec!(10));
+
+        engine.withdraw(client_b, 10)?;
+        engine.withdraw(client_a, 5)?;
+        assert_eq!(engine.account_info(client_a).available, dec!(0));
+        assert_eq!(engine.account_info(client_b).available, dec!(0));
+
+        Ok(())
+    }
+
+    #[test]
+    #[allow(clippy::bool_assert_comparison)]
+    fn test_negative_balance() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let client = 1;
+        let deposit = engine.deposit(client, 10)?;
+        engine.withdraw(client, 8)?;
+
+        engine.dispute(deposit)?;
+        assert!(engine.withdraw(client, 2).is_err()); // last of the funds, but they are held
+        assert_eq!(engine.account_info(client).available, dec!(-8));
+        assert_eq!(engine.account_info(client).held, dec!(10));
+
+        // put some funds back
+        engine.deposit(client, 5)?;
+        engine.deposit(client, 7)?;
+        assert_eq!(engine.account_info(client).available, dec!(4));
+        assert_eq!(engine.account_info(client).held, dec!(10));
+
+        engine.resolve(deposit)?;
+        assert_eq!(engine.account_info(client).available, dec!(14));
+        assert_eq!(engine.account_info(client).locked, false);
+        Ok(())
+    }
+
+    #[test]
+    #[allow(clippy::bool_assert_comparison)]
+    fn locked_account() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let client = 1;
+        engine.deposit(client, 100)?;
+        let fraudulent = engine.deposit(client, 100)?;
+        let shady = engine.deposit(client, 100)?;
+
+        assert_eq!(engine.account_info(client).available, dec!(300));
+        assert_eq!(engine.account_info(client).locked, false);
+
+        engine.withdraw(client, 50)?;
+        engine.dispute(fraudulent)?;
+        engine.chargeback(fraudulent)?;
+
+        // Still has funds available
+        assert_eq!(engine.account_info(client).available, dec!(150));
+        assert_eq!(engine.account_info(client).locked, true);
+
+        // Further deposits and/or withdrawals should fail
+        assert!(engine.withdraw(client, 10).is_err());
+        assert!(engine.deposit(client, 10000).is_err());
+
+        // Other transactions can be disputed in the meantime
+        engine.dispute(shady)?;
+        assert_eq!(
+            engine.account_info(client),
+            AccountInfo {
+                client,
+                available: dec!(50),
+                held: dec!(100),
+                total: dec!(150),
+                locked: true,
+                currency: None,
+                closed: false,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn dispute_withdrawal_on_locked_account() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let client = 1;
+        engine.deposit(client, 100)?;
+        let fraudulent = engine.deposit(client, 100)?;
+        let withdrawal = engine.withdraw(client, 50)?;
+        engine.dispute(fraudulent)?;
+        engine.chargeback(fraudulent)?;
+        let balances = |engine: &Wrapper| {
+            let info = engine.account_info(client);
+            assert!(info.locked);
+            (info.available, info.held, info.total)
+        };
+        assert_eq!(balances(&engine), (dec!(50), dec!(0), dec!(50)));
+
+        // the withdrawn funds are held back as a negative amount, the total doesn't change until the chargeback
+        engine.dispute(withdrawal)?;
+        assert_eq!(balances(&engine), (dec!(100), dec!(-50), dec!(50)));
+        engine.resolve(withdrawal)?;
+        assert_eq!(balances(&engine), (dec!(50), dec!(0), dec!(50)));
+
+        engine.dispute(withdrawal)?;
+        engine.chargeback(withdrawal)?;
+        assert_eq!(balances(&engine), (dec!(100), dec!(0), dec!(100)));
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_on_locked_account() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let client = 1;
+        let earlier = engine.deposit(client, 100)?;
+        let fraudulent = engine.deposit(client, 100)?;
+        engine.withdraw(client, 150)?;
+        engine.dispute(fraudulent)?;
+        engine.chargeback(fraudulent)?;
+
+        // disputing can take available below zero, resolving puts it back as it was
+        engine.dispute(earlier)?;
+        let info = engine.account_info(client);
+        assert_eq!(
+            (info.available, info.held, info.total),
+            (dec!(-150), dec!(100), dec!(-50))
+        );
+        engine.resolve(earlier)?;
+        let info = engine.account_info(client);
+        assert_eq!(
+            (info.available, info.held, info.total),
+            (dec!(-50), dec!(0), dec!(-50))
+        );
+        assert!(info.locked);
+
+        // but the funds still can't leave the account
+        assert_eq!(
+            engine.withdraw(client, 1).unwrap_err().error_type,
+            ErrorType::LockedAccount { client }
+        );
+        Ok(())
+    }
+
+    struct CapturingLogger;
+
+    static CAPTURED: Mutex<Vec<(log::Level, String)>> = Mutex::new(Vec::new());
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            CAPTURED
+                .lock()
+                .unwrap()
+                .push((record.level(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn error_severity() -> Result<()> {
+        let tally = Arc::new(Mutex::new(Tally::default()));
+        let mut engine = Wrapper::new();
+        engine.engine.set_observer(Box::new(tally.clone()));
+        engine
+            .engine
+            .set_error_severity(ErrorKind::InsufficientFunds, Severity::Warning);
+        let client = 1;
+        engine.deposit(client, 1)?;
+
+        // a warning is still a rejection, only reported differently
+        let err = engine.withdraw(client, 2).unwrap_err();
+        assert_eq!(err.severity, Severity::Warning);
+        assert_eq!(engine.account_info(client).available, dec!(1));
+        assert_eq!(tally.lock().unwrap().rejected, 1);
+
+        // other kinds are left untouched
+        assert_eq!(
+            engine.deposit(client, -1).unwrap_err().severity,
+            Severity::Error
+        );
+
+        // a failed batch carries the severity of the transaction that failed it
+        let tx = engine.ids.peek().unwrap();
+        let err = engine
+            .engine
+            .apply_batch(vec![
+                Transaction::new(
+                    client,
+                    Event::Deposit {
+                        tx,
+                        amount: dec!(1),
+                    },
+                ),
+                Transaction::new(
+                    client,
+                    Event::Withdrawal {
+                        tx: tx + 1,
+                        amount: dec!(5),
+                    },
+                ),
+            ])
+            .unwrap_err();
+        assert_eq!(err.severity, Severity::Warning);
+        assert_eq!(engine.account_info(client).available, dec!(1));
+        Ok(())
+    }
+
+    #[test]
+    fn buffered_dispute_severity() -> Result<()> {
+        static LOGGER: CapturingLogger = CapturingLogger;
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let mut engine = Wrapper::new();
+        engine.engine.set_buffer_early_disputes(true);
+        engine
+            .engine
+            .set_error_severity(ErrorKind::TransactionDoesNotMatchClient, Severity::Warning);
+        let tx = engine.ids.peek().unwrap();
+        // accepted while buffered, fails once the deposit shows it belongs to another client
+        // a client id no other test uses, since the logger is shared by all of them
+        let other = 4242;
+        engine
+            .engine
+            .handle(Transaction::new(other, Event::Dispute { tx, reason: None }))?;
+        engine.deposit(1, 10)?;
+        assert_eq!(engine.account_info(1).held, dec!(0));
+
+        let captured: Vec<_> = CAPTURED
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, message)| {
+                message.starts_with("Error applying buffered dispute")
+                    && message.contains("client: 4242")
+            })
+            .map(|(level, _)| *level)
+            .collect();
+        assert_eq!(captured, vec![log::Level::Warn]);
+        Ok(())
+    }
+
+    #[test]
+    fn interest() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let (saver, spender, fraudster, newcomer) = (1, 2, 3, 4);
+        engine.deposit(saver, dec!(1000))?;
+        let held = engine.deposit(saver, dec!(500))?;
+        engine.dispute(held)?;
+
+        engine.deposit(spender, dec!(10))?;
+        engine.withdraw(spender, dec!(10))?;
+
+        let fraudulent = engine.deposit(fraudster, dec!(200))?;
+        engine.deposit(fraudster, dec!(300))?;
+        engine.dispute(fraudulent)?;
+        engine.chargeback(fraudulent)?;
+
+        engine.deposit(newcomer, dec!(0.0123))?;
+        let closed = 5;
+        engine.deposit(closed, dec!(100))?;
+        engine.engine.close_account(closed)?;
+
+        let total = engine.engine.accrue_interest(dec!(0.0125))?;
+        assert_eq!(total, dec!(12.5002));
+        assert_eq!(engine.account_info(closed).available, dec!(100));
+
+        assert_eq!(engine.account_info(saver).available, dec!(1012.5));
+        assert_eq!(engine.account_info(saver).held, dec!(500));
+        assert_eq!(engine.account_info(spender).available, dec!(0));
+        assert_eq!(engine.account_info(fraudster).available, dec!(300));
+        // 0.00015375 of interest is rounded to 0.0002
+        assert_eq!(engine.account_info(newcomer).available, dec!(0.0125));
+
+        // nobody gets interest if anyone's would overflow
+        engine.deposit(6, Decimal::MAX / dec!(2))?;
+        assert_eq!(
+            engine
+                .engine
+                .accrue_interest(dec!(3))
+                .unwrap_err()
+                .error_type,
+            ErrorType::InterestOverflow { client: 6 }
+        );
+        assert_eq!(engine.account_info(saver).available, dec!(1012.5));
+        Ok(())
+    }
+
+    #[test]
+    fn open_disputes() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let (client_a, client_b) = (1, 2);
+        let resolved = engine.deposit(client_a, 10)?;
+        let open = engine.deposit(client_a, 20)?;
+        let charged_back = engine.deposit(client_b, 30)?;
+        let withdrawal = engine.withdraw(client_a, 5)?;
+        engine.deposit(client_b, 40)?;
+
+        engine.dispute(resolved)?;
+        engine.dispute(open)?;
+        engine.dispute(charged_back)?;
+        engine.dispute(withdrawal)?;
+        engine.resolve(resolved)?;
+        engine.chargeback(charged_back)?;
+
+        let mut disputes: Vec<_> = engine.engine.disputed_transactions().collect();
+        disputes.sort_by_key(|dispute| dispute.tx);
+        assert_eq!(
+            disputes,
+            vec![
+                DisputeView {
+                    tx: open.tx,
+                    client: client_a,
+                    held: dec!(20),
+                    reason: None,
+                },
+                DisputeView {
+                    tx: withdrawal.tx,
+                    client: client_a,
+                    held: dec!(-5),
+                    reason: None,
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn overflow() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let client = 1;
+        let first = engine.deposit(client, Decimal::MAX - Decimal::ONE)?;
+        let second = engine.deposit(client, Decimal::MAX).unwrap_err();
+        assert_eq!(
+            second.error_type,
+            ErrorType::ArithmeticOverflow {
+                tx: first.tx + 1,
+                client
+            }
+        );
+        assert_eq!(
+            engine.account_info(client).available,
+            Decimal::MAX - Decimal::ONE
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_overflow() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let client = 1;
+        let deposit = engine.deposit(client, Decimal::MAX)?;
+        engine.dispute(deposit)?;
+        engine.deposit(client, Decimal::MAX)?;
+
+        let overflow = ErrorType::ArithmeticOverflow {
+            tx: deposit.tx,
+            client,
+        };
+        assert_eq!(engine.resolve(deposit).unwrap_err().error_type, overflow);
+        let partial = Event::PartialResolve {
+            tx: deposit.tx,
+            amount: dec!(1),
+        };
+        assert_eq!(
+            engine
+                .engine
+                .handle(Transaction::new(client, partial.clone()))
+                .unwrap_err()
+                .error_type,
+            overflow
+        );
+        let info = engine.account_info(client);
+        assert_eq!((info.available, info.held), (Decimal::MAX, Decimal::MAX));
+
+        // still under dispute, so it resolves once there is room
+        engine.withdraw(client, 1)?;
+        engine.engine.handle(Transaction::new(client, partial))?;
+        engine.withdraw(client, Decimal::MAX)?;
+        engine.resolve(deposit)?;
+        assert_eq!(
+            engine.account_info(client).available,
+            Decimal::MAX - Decimal::ONE
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn overdraft() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let client = 1;
+        engine.engine.set_overdraft_limit(client, dec!(50))?;
+        assert_eq!(engine.engine.overdraft_limit(client), dec!(50));
+        assert_eq!(engine.engine.overdraft_limit(2), dec!(0));
+
+        let deposit = engine.deposit(client, 10)?;
+        engine.withdraw(client, 55)?;
+        assert_eq!(engine.account_info(client).available, dec!(-45));
+        assert!(engine.withdraw(client, 10).is_err());
+
+        // the dispute goes beyond the overdraft limit, but then no more withdrawals
+        engine.dispute(deposit)?;
+        assert_eq!(engine.account_info(client).available, dec!(-55));
+        assert!(engine.withdraw(client, 1).is_err());
+
+        engine.resolve(deposit)?;
+        engine.withdraw(client, 5)?;
+        assert_eq!(engine.account_info(client).available, dec!(-50));
+        assert!(engine.withdraw(client, dec!(0.0001)).is_err());
+        Ok(())
+    }
+
+    #[derive(Default, Debug, PartialEq)]
+    struct Tally {
+        deposits: usize,
+        withdrawals: usize,
+        disputes: usize,
+        resolves: usize,
+        chargebacks: usize,
+        rejected: usize,
+    }
+
+    impl EngineObserver for Arc<Mutex<Tally>> {
+        fn on_deposit(&mut self, _client: ClientId, _tx: TransactionId, _amount: Decimal) {
+            self.lock().unwrap().deposits += 1;
+        }
+
+        fn on_withdrawal(&mut self, _client: ClientId, _tx: TransactionId, _amount: Decimal) {
+            self.lock().unwrap().withdrawals += 1;
+        }
+
+        fn on_dispute(&mut self, _client: ClientId, _tx: TransactionId) {
+            self.lock().unwrap().disputes += 1;
+        }
+
+        fn on_resolve(&mut self, _client: ClientId, _tx: TransactionId) {
+            self.lock().unwrap().resolves += 1;
+        }
+
+        fn on_chargeback(&mut self, _client: ClientId, _tx: TransactionId) {
+            self.lock().unwrap().chargebacks += 1;
+        }
+
+        fn on_rejected(&mut self, _error: &crate::errors::Error) {
+            self.lock().unwrap().rejected += 1;
+        }
+    }
+
+    fn assert_send<T: Send>() {}
+
+    #[test]
+    fn engine_is_send() {
+        // so the engine, with everything it was given, can be moved to another thread
+        assert_send::<Engine>();
+        assert_send::<Box<dyn EngineObserver>>();
+        assert_send::<Box<dyn TransactionStore>>();
+    }
+
+    #[test]
+    fn observer() -> Result<()> {
+        let tally = Arc::new(Mutex::new(Tally::default()));
+        let mut engine = Wrapper::new();
+        engine.engine.set_observer(Box::new(tally.clone()));
+        let client = 1;
+
+        let first = engine.deposit(client, 10)?;
+        let second = engine.deposit(client, 10)?;
+        engine.withdraw(client, 5)?;
+        assert!(engine.withdraw(client, 100).is_err());
+        engine.dispute(first)?;
+        engine.resolve(first)?;
+        engine.dispute(second)?;
+        engine.chargeback(second)?;
+        assert!(engine.deposit(client, 10).is_err());
+
+        assert_eq!(
+            *tally.lock().unwrap(),
+            Tally {
+                deposits: 2,
+                withdrawals: 1,
+                disputes: 2,
+                resolves: 1,
+                chargebacks: 1,
+                rejected: 2,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn status_transitions() {
+        let tx = 1;
+        let cases = [
+            (
+                Status::None,
+                DisputeAction::Dispute,
+                Ok(Status::UnderDispute),
+            ),
+            (
+                Status::None,
+                DisputeAction::Resolve,
+                Err(ErrorType::TransactionNotUnderDispute { tx }),
+            ),
+            (
+                Status::None,
+                DisputeAction::Chargeback,
+                Err(ErrorType::TransactionNotUnderDispute { tx }),
+            ),
+            (
+                Status::UnderDispute,
+                DisputeAction::Dispute,
+                Err(ErrorType::TransactionAlreadyUnderDispute { tx }),
+            ),
+            (
+                Status::UnderDispute,
+                DisputeAction::Resolve,
+                Ok(Status::None),
+            ),
+            (
+                Status::UnderDispute,
+                DisputeAction::Chargeback,
+                Ok(Status::Reversed),
+            ),
+            (
+                Status::Reversed,
+                DisputeAction::Dispute,
+                Err(ErrorType::TransactionAlreadyUnderDispute { tx }),
+            ),
+            (
+                Status::Reversed,
+                DisputeAction::Resolve,
+                Err(ErrorType::TransactionNotUnderDispute { tx }),
+            ),
+            (
+                Status::Reversed,
+                DisputeAction::Chargeback,
+                Err(ErrorType::TransactionNotUnderDispute { tx }),
+            ),
+            (
+                Status::Reversed,
+                DisputeAction::ReverseChargeback,
+                Ok(Status::None),
+            ),
+            (
+                Status::None,
+                DisputeAction::ReverseChargeback,
+                Err(ErrorType::TransactionNotChargedBack { tx }),
+            ),
+            (
+                Status::UnderDispute,
+                DisputeAction::ReverseChargeback,
+                Err(ErrorType::TransactionNotChargedBack { tx }),
+            ),
+        ];
+        for (status, action, expected) in cases {
+            let result = status.transition(action, tx).map_err(|err| err.error_type);
+            assert_eq!(result, expected, "{status:?} on {action:?}");
+        }
+    }
+
+    #[test]
+    fn replay() {
+        let transactions = || {
+            let deposit = |tx, amount: i64| {
+                Transaction::new(
+                    1,
+                    Event::Deposit {
+                        tx,
+                        amount: amount.into(),
+                    },
+                )
+            };
+            let withdrawal = |tx, amount: i64| {
+                Transaction::new(
+                    1,
+                    Event::Withdrawal {
+                        tx,
+                        amount: amount.into(),
+                    },
+                )
+            };
+            vec![
+                deposit(1, 10),
+                withdrawal(2, 20),
+                deposit(3, 5),
+                Transaction::new(
+                    1,
+                    Event::Dispute {
+                        tx: 3,
+                        reason: None,
+                    },
+                ),
+                deposit(1, 10),
+                withdrawal(4, 12),
+            ]
+        };
+
+        let (replayed, errors) = Engine::from_transactions(transactions());
+        let errors: Vec<_> = errors
+            .into_iter()
+            .map(|(index, err)| (index, err.error_type))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (1, ErrorType::InsufficientFunds { client: 1, tx: 2 }),
+                (4, ErrorType::ReusedTransactionId { tx: 1 }),
+                (5, ErrorType::InsufficientFunds { client: 1, tx: 4 }),
+            ]
+        );
+
+        let mut engine = Engine::new();
+        for transaction in transactions() {
+            let _ = engine.handle(transaction);
+        }
+        assert_eq!(replayed.account_info(1), engine.account_info(1));
+        assert_eq!(
+            replayed.account_info(1),
+            AccountInfo {
+                client: 1,
+                available: dec!(10),
+                held: dec!(5),
+                total: dec!(15),
+                locked: false,
+                currency: None,
+                closed: false,
+            }
+        );
+    }
+
+    #[test]
+    fn dispute_again_after_resolve() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let client = 1;
+        let deposit = engine.deposit(client, 10)?;
+        let withdrawal = engine.withdraw(client, 3)?;
+        let original = engine.account_info(client);
+
+        for _ in 0..3 {
+            engine.dispute(deposit)?;
+            assert_eq!(engine.account_info(client).available, dec!(-3));
+            assert_eq!(engine.account_info(client).held, dec!(10));
+            assert!(engine.dispute(deposit).is_err());
+
+            engine.dispute(withdrawal)?;
+            assert_eq!(engine.account_info(client).available, dec!(0));
+            assert_eq!(engine.account_info(client).held, dec!(7));
+
+            engine.resolve(deposit)?;
+            engine.resolve(withdrawal)?;
+            assert_eq!(engine.account_info(client), original);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn json_scenario() -> Result<()> {
+        let (engine, errors) = Engine::from_json_transactions(
+            r#"[
+                {"type": "deposit", "client": 1, "tx": 1, "amount": "10.5"},
+                {"type": "deposit", "client": 2, "tx": 2, "amount": 3},
+                {"type": "withdrawal", "client": 1, "tx": 3, "amount": 20},
+                {"type": "dispute", "client": 1, "tx": 1}
+            ]"#,
+        )?;
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, 2);
+        assert_eq!(
+            errors[0].1.error_type,
+            ErrorType::InsufficientFunds { client: 1, tx: 3 }
+        );
+        assert_eq!(engine.account_info(1).held, dec!(10.5));
+        assert_eq!(engine.account_info(2).available, dec!(3));
+
+        let err = Engine::from_json_transactions(r#"[{"type": "deposit"}]"#)
+            .err()
+            .unwrap();
+        assert!(matches!(err.error_type, ErrorType::InvalidJson { .. }));
+        Ok(())
+    }
+
+    #[test]
+    fn withdrawal_bounds() -> Result<()> {
+        let mut engine = Wrapper::new();
+        engine
+            .engine
+            .set_withdrawal_bounds(Some(dec!(1)), Some(dec!(100)))?;
+        let client = 1;
+        engine.deposit(client, 1000)?;
+
+        let too_small = engine.withdraw(client, dec!(0.5)).unwrap_err();
+        assert_eq!(
+            too_small.error_type,
+            ErrorType::WithdrawalOutOfBounds {
+                tx: 2,
+                amount: dec!(0.5)
+            }
+        );
+        engine.withdraw(client, 50)?;
+        let too_large = engine.withdraw(client, 200).unwrap_err();
+        assert_eq!(
+            too_large.error_type,
+            ErrorType::WithdrawalOutOfBounds {
+                tx: 4,
+                amount: dec!(200)
+            }
+        );
+        assert_eq!(engine.account_info(client).available, dec!(950));
+        Ok(())
+    }
+
+    #[test]
+    fn error_order_on_locked_account() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let client = 1;
+        let deposit = engine.deposit(client, 10)?;
+        let fraudulent = engine.deposit(client, 20)?;
+        engine.dispute(fraudulent)?;
+        engine.chargeback(fraudulent)?;
+
+        let reuse = |event| Transaction::new(client, event);
+        assert_eq!(
+            engine
+                .engine
+                .handle(reuse(Event::Deposit {
+                    tx: deposit.tx,
+                    amount: dec!(1),
+                }))
+                .unwrap_err()
+                .error_type,
+            ErrorType::ReusedTransactionId { tx: deposit.tx }
+        );
+        assert_eq!(
+            engine
+                .engine
+                .handle(reuse(Event::Withdrawal {
+                    tx: fraudulent.tx,
+                    amount: dec!(1),
+                }))
+                .unwrap_err()
+                .error_type,
+            ErrorType::ReusedTransactionId { tx: fraudulent.tx }
+        );
+        assert_eq!(
+            engine.deposit(client, 1).unwrap_err().error_type,
+            ErrorType::LockedAccount { client }
+        );
+        assert_eq!(
+            engine.withdraw(client, 1).unwrap_err().error_type,
+            ErrorType::LockedAccount { client }
+        );
+        // a negative amount is reported before anything else
+        assert_eq!(
+            engine
+                .engine
+                .handle(reuse(Event::Deposit {
+                    tx: deposit.tx,
+                    amount: dec!(-1),
+                }))
+                .unwrap_err()
+                .error_type,
+            ErrorType::NegativeDeposit { tx: deposit.tx }
+        );
+
+        // the reused ids didn't replace the original transactions
+        engine.dispute(deposit)?;
+        assert_eq!(engine.account_info(client).held, dec!(10));
+        Ok(())
+    }
+
+    #[test]
+    fn seen_transactions() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let client = 1;
+        let deposit = engine.deposit(client, 10)?;
+        assert!(engine.engine.contains_transaction(deposit.tx));
+        assert!(!engine.engine.contains_transaction(deposit.tx + 1));
+
+        // rejected for insufficient funds, but still recorded
+        let tx = engine.ids.peek().unwrap();
+        assert!(engine.withdraw(client, 20).is_err());
+        assert!(engine.engine.contains_transaction(tx));
+
+        // disputes only refer to existing transactions
+        assert!(engine
+            .dispute(CommitedTransaction { client, tx: 100 })
+            .is_err());
+        assert!(!engine.engine.contains_transaction(100));
+        Ok(())
+    }
+
+    #[test]
+    fn currency() -> Result<()> {
+        let mut engine = Engine::new();
+        let euro: Currency = "eur".parse().unwrap();
+        let dollar: Currency = "USD".parse().unwrap();
+        let deposit = |client, tx, currency| Transaction {
+            currency,
+            ..Transaction::new(
+                client,
+                Event::Deposit {
+                    tx,
+                    amount: 10.into(),
+                },
+            )
+        };
+
+        engine.handle(deposit(1, 1, Some(euro)))?;
+        // only the transaction creating the account sets the currency
+        engine.handle(deposit(1, 2, Some(dollar)))?;
+        engine.handle(deposit(2, 3, None))?;
+        engine.handle(deposit(2, 4, Some(dollar)))?;
+
+        assert_eq!(engine.account_info(1).currency, Some(euro));
+        assert_eq!(euro.to_string(), "EUR");
+        assert_eq!(engine.account_info(2).currency, None);
+        assert_eq!(engine.account_info(3).currency, None);
+        Ok(())
+    }
+
+    #[test]
+    fn dispute_window() -> Result<()> {
+        let now = Arc::new(AtomicI64::new(1_000));
+        let mut engine = Wrapper::new();
+        engine.engine.set_clock(Box::new({
+            let now = now.clone();
+            move || now.load(Ordering::Relaxed)
+        }));
+        engine.engine.set_dispute_window(Some(60));
+        let client = 1;
+
+        let old = engine.deposit(client, 10)?;
+        now.store(1_030, Ordering::Relaxed);
+        let recent = engine.deposit(client, 10)?;
+
+        now.store(1_060, Ordering::Relaxed);
+        engine.dispute(old)?;
+        engine.resolve(old)?;
+
+        now.store(1_061, Ordering::Relaxed);
+        assert_eq!(
+            engine.dispute(old).unwrap_err().error_type,
+            ErrorType::DisputeWindowExpired { tx: old.tx }
+        );
+        engine.dispute(recent)?;
+        assert_eq!(engine.account_info(client).held, dec!(10));
+
+        // the window only applies to opening disputes
+        now.store(5_000, Ordering::Relaxed);
+        engine.resolve(recent)?;
+        Ok(())
+    }
+
+    #[test]
+    fn globally_unique_transactions() {
+        let mut engine = Engine::new();
+        let client_a = 1;
+        let client_b = 2;
+        let tx = 1;
+        engine
+            .handle(Transaction::new(
+                client_a,
+                Event::Deposit {
+                    tx,
+                    amount: 10.into(),
+                },
+            ))
+            .unwrap();
+        assert_eq!(
+            engine
+                .handle(Transaction::new(
+                    client_b,
+                    Event::Deposit {
+                        tx,
+                        amount: 10.into()
+                    }
+                ))
+                .unwrap_err()
+                .error_type,
+            ErrorType::ReusedTransactionId { tx }
+        );
+    }
+
+    #[test]
+    fn holds() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let client = 1;
+        let deposit = engine.deposit(client, 10)?;
+        // holds have their own ids, they don't clash with the deposit's
+        let hold = deposit.tx;
+        engine.engine.handle(Transaction::new(
+            client,
+            Event::Hold {
+                tx: hold,
+                amount: 4.into(),
+            },
+        ))?;
+        assert_eq!(engine.account_info(client).available, dec!(6));
+        assert_eq!(engine.account_info(client).held, dec!(4));
+        assert_eq!(engine.engine.disputed_transactions().count(), 0);
+
+        // only available funds can be held
+        assert_eq!(
+            engine
+                .engine
+                .handle(Transaction::new(
+                    client,
+                    Event::Hold {
+                        tx: hold + 1,
+                        amount: 7.into(),
+                    },
+                ))
+                .unwrap_err()
+                .error_type,
+            ErrorType::InsufficientFunds {
+                client,
+                tx: hold + 1
+            }
+        );
+        assert_eq!(engine.account_info(client).available, dec!(6));
+
+        // disputes don't touch the hold
+        engine.dispute(deposit)?;
+        engine.resolve(deposit)?;
+        assert_eq!(engine.account_info(client).held, dec!(4));
+
+        assert_eq!(
+            engine
+                .engine
+                .handle(Transaction::new(2, Event::ReleaseHold { tx: hold }))
+                .unwrap_err()
+                .error_type,
+            ErrorType::TransactionDoesNotMatchClient {
+                tx: hold,
+                client: 2,
+                owner: client,
+            }
+        );
+        engine
+            .engine
+            .handle(Transaction::new(client, Event::ReleaseHold { tx: hold }))?;
+        assert_eq!(engine.account_info(client).available, dec!(10));
+        assert_eq!(engine.account_info(client).held, dec!(0));
+
+        assert_eq!(
+            engine
+                .engine
+                .handle(Transaction::new(client, Event::ReleaseHold { tx: hold }))
+                .unwrap_err()
+                .error_type,
+            ErrorType::UnknownHold { tx: hold }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn error_tally() {
+        let (_, errors) = Engine::from_transactions([
+            Transaction::new(
+                1,
+                Event::Deposit {
+                    tx: 1,
+                    amount: 5.into(),
+                },
+            ),
+            Transaction::new(
+                1,
+                Event::Withdrawal {
+                    tx: 2,
+                    amount: 6.into(),
+                },
+            ),
+            Transaction::new(
+                1,
+                Event::Withdrawal {
+                    tx: 3,
+                    amount: 7.into(),
+                },
+            ),
+            Transaction::new(
+                1,
+                Event::Dispute {
+                    tx: 4,
+                    reason: None,
+                },
+            ),
+            Transaction::new(
+                1,
+                Event::Dispute {
+                    tx: 4,
+                    reason: None,
+                },
+            ),
+            Transaction::new(
+                1,
+                Event::Deposit {
+                    tx: 1,
+                    amount: 5.into(),
+                },
+            ),
+        ]);
+        let mut tally: HashMap<ErrorType, usize> = HashMap::new();
+        for (_, err) in errors {
+            *tally.entry(err.error_type).or_default() += 1;
+        }
+        assert_eq!(
+            tally,
+            HashMap::from([
+                (ErrorType::InsufficientFunds { client: 1, tx: 2 }, 1),
+                (ErrorType::InsufficientFunds { client: 1, tx: 3 }, 1),
+                (ErrorType::UnknownTransactionForDispute { tx: 4 }, 2),
+                (ErrorType::ReusedTransactionId { tx: 1 }, 1),
+            ])
+        );
+    }
+
+    #[test]
+    fn client_ids() -> Result<()> {
+        let mut engine = Wrapper::new();
+        engine.deposit(1, 5)?;
+        engine.withdraw(2, 5).unwrap_err();
+        engine.deposit(3, -5).unwrap_err();
+        let mut clients: Vec<_> = engine.engine.client_ids().collect();
+        clients.sort();
+        assert_eq!(clients, vec![1, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn amount_of() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let deposit = engine.deposit(1, dec!(2.5))?;
+        let withdrawal = engine.withdraw(1, 1)?;
+        assert_eq!(engine.engine.amount_of(deposit.tx)?, dec!(2.5));
+        assert_eq!(engine.engine.amount_of(withdrawal.tx)?, dec!(-1));
+        assert_eq!(
+            engine.engine.amount_of(42).unwrap_err().error_type,
+            ErrorType::UnknownTransaction { tx: 42 }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn prune() -> Result<()> {
+        let mut engine = Wrapper::new();
+        engine.engine.set_prune_settled(true);
+        let client = 1;
+        let charged_back = engine.deposit(client, 10)?;
+        engine.dispute(charged_back)?;
+        engine.chargeback(charged_back)?;
+        // kept until the chargeback is reversed, otherwise the account could never be unlocked
+        assert!(engine.engine.contains_transaction(charged_back.tx));
+        assert!(engine.account_info(client).locked);
+        engine.reverse_chargeback(charged_back)?;
+        assert!(!engine.account_info(client).locked);
+        assert_eq!(engine.account_info(client).available, dec!(10));
+        assert!(!engine.engine.contains_transaction(charged_back.tx));
+        assert_eq!(
+            engine.dispute(charged_back).unwrap_err().error_type,
+            ErrorType::UnknownTransactionForDispute {
+                tx: charged_back.tx
+            }
+        );
+
+        let client = 2;
+        let old = engine.deposit(client, 10)?;
+        let disputed = engine.deposit(client, 10)?;
+        let charged_back = engine.deposit(client, 10)?;
+        let recent = engine.deposit(client, 10)?;
+        engine.dispute(disputed)?;
+        engine.dispute(charged_back)?;
+        engine.chargeback(charged_back)?;
+        assert_eq!(engine.engine.prune_before(recent.tx), 1);
+        assert!(!engine.engine.contains_transaction(old.tx));
+        engine.reverse_chargeback(charged_back)?;
+        assert_eq!(
+            engine.dispute(old).unwrap_err().error_type,
+            ErrorType::UnknownTransactionForDispute { tx: old.tx }
+        );
+        engine.resolve(disputed)?;
+        engine.dispute(recent)?;
+        assert_eq!(engine.account_info(client).available, dec!(30));
+        Ok(())
+    }
+
+    #[test]
+    fn global_dispute_of_withdrawal() -> Result<()> {
+        let mut engine = Wrapper::new();
+        engine.engine.set_global_dispute(true);
+        let (owner, other) = (1, 2);
+        let deposit = engine.deposit(owner, 10)?;
+        let withdrawal = engine.withdraw(owner, 4)?;
+
+        let foreign = |transaction: CommitedTransaction| CommitedTransaction {
+            client: other,
+            ..transaction
+        };
+        for result in [
+            engine.dispute(foreign(withdrawal)),
+            engine.resolve(foreign(withdrawal)),
+            engine.chargeback(foreign(withdrawal)),
+        ] {
+            assert_eq!(
+                result.unwrap_err().error_type,
+                ErrorType::TransactionDoesNotMatchClient {
+                    tx: withdrawal.tx,
+                    client: other,
+                    owner: withdrawal.client,
+                }
+            );
+        }
+
+        engine.dispute(withdrawal)?;
+        engine.resolve(withdrawal)?;
+        // deposits can still be disputed by anyone
+        engine.dispute(foreign(deposit))?;
+        engine.resolve(foreign(deposit))?;
+        Ok(())
+    }
+
+    #[test]
+    fn rollback() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let client = 1;
+        let deposit = engine.deposit(client, 10)?;
+        engine.withdraw(client, 3)?;
+        engine.dispute(deposit)?;
+        engine.chargeback(deposit)?;
+        assert!(engine.account_info(client).locked);
+
+        engine.engine.rollback(1)?;
+        let info = engine.account_info(client);
+        assert!(!info.locked);
+        assert_eq!(info.available, dec!(-3));
+        assert_eq!(info.held, dec!(10));
+        // back under dispute, so it can be resolved instead
+        engine.resolve(deposit)?;
+        assert_eq!(engine.account_info(client).available, dec!(7));
+
+        // the failed deposit and the resolve, then the dispute and the withdrawal
+        engine.deposit(client, -1).unwrap_err();
+        engine.engine.rollback(4)?;
+        assert_eq!(engine.account_info(client).available, dec!(10));
+        assert_eq!(engine.account_info(client).held, dec!(0));
+
+        assert_eq!(
+            engine.engine.rollback(2).unwrap_err().error_type,
+            ErrorType::RollbackTooFar {
+                requested: 2,
+                available: 1
+            }
+        );
+        engine.engine.rollback(1)?;
+        assert_eq!(engine.engine.client_ids().count(), 0);
+        assert!(!engine.engine.contains_transaction(deposit.tx));
+        Ok(())
+    }
+
+    #[test]
+    fn rollback_limit() -> Result<()> {
+        let mut engine = Wrapper::new();
+        for _ in 0..DEFAULT_ROLLBACK_LIMIT + 1 {
+            engine.deposit(1, 1)?;
+        }
+        assert!(engine.engine.rollback(DEFAULT_ROLLBACK_LIMIT + 1).is_err());
+        engine.engine.rollback(DEFAULT_ROLLBACK_LIMIT)?;
+        assert_eq!(engine.account_info(1).available, dec!(1));
+
+        let mut engine = Wrapper::new();
+        engine.engine.set_rollback_limit(Some(2));
+        engine.deposit(1, 1)?;
+        engine.deposit(1, 2)?;
+        engine.deposit(1, 3)?;
+        assert!(engine.engine.rollback(3).is_err());
+        engine.engine.rollback(2)?;
+        assert_eq!(engine.account_info(1).available, dec!(1));
+        Ok(())
+    }
+
+    #[test]
+    fn global_dispute_moves_owner_funds() -> Result<()> {
+        let mut engine = Wrapper::new();
+        engine.engine.set_global_dispute(true);
+        let (owner, submitter) = (1, 2);
+        let deposit = engine.deposit(owner, 10)?;
+        engine.deposit(submitter, 5)?;
+        let by_submitter = CommitedTransaction {
+            client: submitter,
+            ..deposit
+        };
+
+        engine.dispute(by_submitter)?;
+        assert_eq!(engine.account_info(owner).available, dec!(0));
+        assert_eq!(engine.account_info(owner).held, dec!(10));
+        assert_eq!(engine.account_info(submitter).available, dec!(5));
+        assert_eq!(engine.account_info(submitter).held, dec!(0));
+
+        engine.chargeback(by_submitter)?;
+        assert_eq!(engine.account_info(owner).total, dec!(0));
+        assert!(engine.account_info(owner).locked);
+        assert_eq!(engine.account_info(submitter).total, dec!(5));
+        assert!(!engine.account_info(submitter).locked);
+        Ok(())
+    }
+
+    #[test]
+    fn binary_snapshot() -> Result<()> {
+        let mut engine = Wrapper::new();
+        engine.deposit(1, dec!(10.5))?;
+        engine.withdraw(1, dec!(0.25))?;
+        let disputed = engine.deposit(2, 3)?;
+        engine.dispute(disputed)?;
+        let charged_back = engine.deposit(3, 1)?;
+        engine.dispute(charged_back)?;
+        engine.chargeback(charged_back)?;
+        engine.engine.handle(Transaction {
+            currency: Some("USD".parse().unwrap()),
+            ..Transaction::new(
+                4,
+                Event::Hold {
+                    tx: 1,
+                    amount: 0.into(),
+                },
+            )
+        })?;
+
+        let bytes = engine.engine.to_bytes();
+        let mut restored = Engine::from_bytes(&bytes)?;
+        assert_eq!(
+            restored.all_accounts_sorted(),
+            engine.engine.all_accounts_sorted()
+        );
+        // transactions and holds come along, so they can still be resolved and released
+        restored.handle(Transaction::new(2, Event::Resolve { tx: disputed.tx }))?;
+        restored.handle(Transaction::new(4, Event::ReleaseHold { tx: 1 }))?;
+        assert_eq!(restored.account_info(2).available, dec!(3));
+
+        assert!(matches!(
+            Engine::from_bytes(&bytes[..bytes.len() / 2])
+                .err()
+                .unwrap()
+                .error_type,
+            ErrorType::InvalidSnapshot { .. }
+        ));
+        assert!(Engine::from_bytes(&[0xff; 64]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn adjustment() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let client = 1;
+        engine.deposit(client, 5)?;
+        let adjust = |tx, amount: Decimal| {
+            Transaction::new(
+                client,
+                Event::Adjustment {
+                    tx,
+                    amount,
+                    reason: "upstream correction".to_string(),
+                },
+            )
+        };
+
+        // debits skip the funds check
+        engine.engine.handle(adjust(10, dec!(-8)))?;
+        assert_eq!(engine.account_info(client).available, dec!(-3));
+        engine.engine.handle(adjust(11, dec!(4)))?;
+        assert_eq!(engine.account_info(client).available, dec!(1));
+        assert_eq!(
+            engine.engine.adjustment_reason(10),
+            Some("upstream correction")
+        );
+        assert_eq!(engine.engine.adjustment_reason(1), None);
+        assert_eq!(
+            engine
+                .engine
+                .handle(adjust(11, dec!(4)))
+                .unwrap_err()
+                .error_type,
+            ErrorType::ReusedTransactionId { tx: 11 }
+        );
+
+        let credit = CommitedTransaction { client, tx: 11 };
+        engine.dispute(credit)?;
+        assert_eq!(engine.account_info(client).held, dec!(4));
+        engine.chargeback(credit)?;
+        assert_eq!(
+            engine
+                .engine
+                .handle(adjust(12, dec!(1)))
+                .unwrap_err()
+                .error_type,
+            ErrorType::LockedAccount { client }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn chargeback_fee() -> Result<()> {
+        let mut engine = Wrapper::new();
+        engine.engine.set_chargeback_fee(dec!(25.00))?;
+        let client = 1;
+        engine.deposit(client, 10)?;
+        let deposit = engine.deposit(client, 100)?;
+        engine.dispute(deposit)?;
+        engine.chargeback(deposit)?;
+        let info = engine.account_info(client);
+        assert_eq!(info.available, dec!(-15));
+        assert_eq!(info.held, dec!(0));
+        assert!(info.locked);
+        Ok(())
+    }
+
+    #[test]
+    fn client_transactions() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let (client_a, client_b) = (1, 2);
+        let deposit = engine.deposit(client_a, 10)?;
+        engine.deposit(client_b, 7)?;
+        let withdrawal = engine.withdraw(client_a, 3)?;
+        engine.withdraw(client_b, 1)?;
+        engine.dispute(deposit)?;
+
+        assert_eq!(
+            engine.engine.client_transactions(client_a),
+            vec![
+                TransactionView {
+                    tx: deposit.tx,
+                    amount: dec!(10),
+                    status: Status::UnderDispute
+                },
+                TransactionView {
+                    tx: withdrawal.tx,
+                    amount: dec!(-3),
+                    status: Status::None
+                },
+            ]
+        );
+        assert_eq!(
+            engine
+                .engine
+                .client_transactions(client_b)
+                .iter()
+                .map(|view| view.amount)
+                .collect::<Vec<_>>(),
+            vec![dec!(7), dec!(-1)]
+        );
+        assert!(engine.engine.client_transactions(3).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn negative_zero() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let client = 1;
+        engine.deposit(client, dec!(1.50))?;
+        let withdrawal = engine.withdraw(client, dec!(0.00))?;
+        engine.dispute(withdrawal)?;
+        engine.withdraw(client, dec!(1.5))?;
+        let info = engine.account_info(client);
+        for amount in [info.available, info.held, info.total] {
+            assert!(!amount.is_sign_negative());
+            assert_eq!(amount.to_string(), "0");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn on_lock() -> Result<()> {
+        let locks = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = Wrapper::new();
+        engine.engine.set_on_lock({
+            let locks = locks.clone();
+            move |client, tx| locks.lock().unwrap().push((client, tx))
+        });
+        let client = 1;
+        let first = engine.deposit(client, 10)?;
+        let second = engine.deposit(client, 10)?;
+        engine.dispute(first)?;
+        engine.dispute(second)?;
+        engine.chargeback(first)?;
+        engine.chargeback(second)?;
+        assert_eq!(*locks.lock().unwrap(), vec![(client, first.tx)]);
+        Ok(())
+    }
+
+    #[test]
+    fn flag_before_dispute() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let client = 1;
+        let direct = engine.deposit(client, 10)?;
+        // flagging is optional by default
+        engine.dispute(direct)?;
+        engine.resolve(direct)?;
+
+        engine.engine.set_require_flag_before_dispute(true);
+        assert_eq!(
+            engine.dispute(direct).unwrap_err().error_type,
+            ErrorType::TransactionNotFlagged { tx: direct.tx }
+        );
+        let flag = Transaction::new(client, Event::Flag { tx: direct.tx });
+        engine.engine.handle(flag)?;
+        assert_eq!(engine.account_info(client).held, dec!(0));
+        assert_eq!(
+            engine
+                .engine
+                .handle(Transaction::new(client, Event::Flag { tx: direct.tx }))
+                .unwrap_err()
+                .error_type,
+            ErrorType::CannotFlag { tx: direct.tx }
+        );
+        assert_eq!(
+            engine.resolve(direct).unwrap_err().error_type,
+            ErrorType::TransactionNotUnderDispute { tx: direct.tx }
+        );
+
+        engine.dispute(direct)?;
+        assert_eq!(engine.account_info(client).held, dec!(10));
+        assert_eq!(
+            engine
+                .engine
+                .handle(Transaction::new(client, Event::Flag { tx: direct.tx }))
+                .unwrap_err()
+                .error_type,
+            ErrorType::TransactionAlreadyUnderDispute { tx: direct.tx }
+        );
+        engine.resolve(direct)?;
+        // resolving clears the flag too
+        assert_eq!(
+            engine.dispute(direct).unwrap_err().error_type,
+            ErrorType::TransactionNotFlagged { tx: direct.tx }
+        );
+
+        engine
+            .engine
+            .handle(Transaction::new(client, Event::Flag { tx: direct.tx }))?;
+        engine.dispute(direct)?;
+        engine.chargeback(direct)?;
+        assert_eq!(
+            engine
+                .engine
+                .handle(Transaction::new(client, Event::Flag { tx: direct.tx }))
+                .unwrap_err()
+                .error_type,
+            ErrorType::CannotFlag { tx: direct.tx }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn idempotent() -> Result<()> {
+        let mut engine = Wrapper::new();
+        engine.engine.set_idempotent(true);
+        let client = 1;
+        let deposit =
+            |client, amount: Decimal| Transaction::new(client, Event::Deposit { tx: 1, amount });
+        let withdrawal =
+            |amount: Decimal| Transaction::new(client, Event::Withdrawal { tx: 2, amount });
+
+        engine.engine.handle(deposit(client, dec!(10)))?;
+        engine.engine.handle(deposit(client, dec!(10.00)))?;
+        engine.engine.handle(withdrawal(dec!(3)))?;
+        engine.engine.handle(withdrawal(dec!(3)))?;
+        assert_eq!(engine.account_info(client).available, dec!(7));
+
+        // same id, but not the same transaction
+        for transaction in [
+            deposit(client, dec!(11)),
+            deposit(2, dec!(10)),
+            withdrawal(dec!(10)),
+        ] {
+            assert!(matches!(
+                engine.engine.handle(transaction).unwrap_err().error_type,
+                ErrorType::ReusedTransactionId { .. }
+            ));
+        }
+        assert_eq!(engine.account_info(client).available, dec!(7));
+
+        // a rejected withdrawal is recorded, but redelivering it doesn't turn it into a success
+        let rejected = || {
+            Transaction::new(
+                client,
+                Event::Withdrawal {
+                    tx: 3,
+                    amount: dec!(50),
+                },
+            )
+        };
+        engine.engine.handle(rejected()).unwrap_err();
+        assert!(engine.engine.handle(rejected()).is_err());
+        assert_eq!(engine.account_info(client).available, dec!(7));
+        Ok(())
+    }
+
+    #[test]
+    fn zero_amount() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let client = 1;
+        let deposit = engine.deposit(client, 0)?;
+        engine.withdraw(client, dec!(0.00))?;
+        assert!(engine.engine.contains_transaction(deposit.tx));
+
+        engine.engine.set_reject_zero_amount(true);
+        let tx = engine.ids.peek().unwrap();
+        assert_eq!(
+            engine.deposit(client, 0).unwrap_err().error_type,
+            ErrorType::ZeroAmount { tx }
+        );
+        assert_eq!(
+            engine
+                .withdraw(client, dec!(0.0000))
+                .unwrap_err()
+                .error_type,
+            ErrorType::ZeroAmount { tx: tx + 1 }
+        );
+        assert!(!engine.engine.contains_transaction(tx));
+        assert!(!engine.engine.contains_transaction(tx + 1));
+        engine.deposit(client, dec!(0.0001))?;
+        Ok(())
+    }
+
+    #[test]
+    fn merge() -> Result<()> {
+        let mut shard_a = Wrapper::new();
+        let mut shard_b = Wrapper::new();
+        shard_b.ids = TransactionIdAllocator::starting_at(100);
+        let (shared, only_a, only_b) = (1, 2, 3);
+
+        let disputed = shard_a.deposit(shared, 10)?;
+        shard_a.dispute(disputed)?;
+        shard_a.deposit(only_a, 1)?;
+        shard_b.deposit(shared, 5)?;
+        shard_b.withdraw(shared, 2)?;
+        let charged_back = shard_b.deposit(only_b, 7)?;
+        shard_b.dispute(charged_back)?;
+        shard_b.chargeback(charged_back)?;
+
+        let mut merged = shard_a.engine;
+        merged.merge(shard_b.engine)?;
+        let info = merged.account_info(shared);
+        assert_eq!((info.available, info.held), (dec!(3), dec!(10)));
+        assert_eq!(merged.account_info(only_a).total, dec!(1));
+        assert!(merged.account_info(only_b).locked);
+        assert!(merged.contains_transaction(charged_back.tx));
+        merged.handle(Transaction::new(shared, Event::Resolve { tx: disputed.tx }))?;
+        assert_eq!(merged.account_info(shared).available, dec!(13));
+
+        let mut colliding = Wrapper::new();
+        colliding.ids = TransactionIdAllocator::starting_at(100);
+        colliding.deposit(only_a, 1)?;
+        assert_eq!(
+            merged.merge(colliding.engine).unwrap_err().error_type,
+            ErrorType::ReusedTransactionId { tx: 100 }
+        );
+        assert_eq!(merged.account_info(only_a).total, dec!(1));
+
+        let mut overflowing = Wrapper::new();
+        overflowing.ids = TransactionIdAllocator::starting_at(200);
+        overflowing.deposit(4, 1)?;
+        overflowing.deposit(shared, Decimal::MAX)?;
+        assert_eq!(
+            merged.merge(overflowing.engine).unwrap_err().error_type,
+            ErrorType::MergeOverflow { client: shared }
+        );
+        assert_eq!(merged.account_info(shared).available, dec!(13));
+        assert!(!merged.contains_transaction(200));
+        assert_eq!(merged.account_count(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn last_activity() -> Result<()> {
+        let mut engine = Engine::new();
+        let client = 1;
+        let at = |timestamp, event| Transaction {
+            timestamp: Some(timestamp),
+            ..Transaction::new(client, event)
+        };
+        engine.handle(at(
+            1_000,
+            Event::Deposit {
+                tx: 1,
+                amount: 5.into(),
+            },
+        ))?;
+        assert_eq!(engine.last_activity(client), Some(1_000));
+
+        // failed transactions don't count, and neither do ones without a timestamp
+        engine
+            .handle(at(
+                2_000,
+                Event::Withdrawal {
+                    tx: 2,
+                    amount: 10.into(),
+                },
+            ))
+            .unwrap_err();
+        engine.handle(Transaction::new(
+            client,
+            Event::Dispute {
+                tx: 1,
+                reason: None,
+            },
+        ))?;
+        assert_eq!(engine.last_activity(client), Some(1_000));
+
+        engine.handle(at(3_000, Event::Resolve { tx: 1 }))?;
+        assert_eq!(engine.last_activity(client), Some(3_000));
+        assert_eq!(engine.last_activity(2), None);
+        Ok(())
+    }
+
+    #[test]
+    fn dispute_would_overdraw() -> Result<()> {
+        let mut engine = Wrapper::new();
+        engine.engine.set_allow_negative_available_on_dispute(false);
+        let client = 1;
+        let deposit = engine.deposit(client, 10)?;
+        engine.withdraw(client, 8)?;
+        assert_eq!(
+            engine.dispute(deposit).unwrap_err().error_type,
+            ErrorType::DisputeWouldOverdraw {
+                tx: deposit.tx,
+                client
+            }
+        );
+        let info = engine.account_info(client);
+        assert_eq!((info.available, info.held), (dec!(2), dec!(0)));
+
+        // fine within the overdraft limit
+        engine.engine.set_overdraft_limit(client, dec!(8))?;
+        engine.dispute(deposit)?;
+        assert_eq!(engine.account_info(client).available, dec!(-8));
+        Ok(())
+    }
+
+    #[test]
+    fn account_holds() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let client = 1;
+        let first = engine.deposit(client, 10)?;
+        let second = engine.deposit(client, 5)?;
+        let other = engine.deposit(2, 7)?;
+        engine.engine.handle(Transaction::new(
+            client,
+            Event::Hold {
+                tx: 50,
+                amount: 1.into(),
+            },
+        ))?;
+        engine.dispute(first)?;
+        engine.dispute(second)?;
+        engine.dispute(other)?;
+
+        let holds = engine.engine.account_holds(client);
+        assert_eq!(
+            holds,
+            vec![
+                HeldFunds::Dispute {
+                    tx: first.tx,
+                    amount: dec!(10)
+                },
+                HeldFunds::Dispute {
+                    tx: second.tx,
+                    amount: dec!(5)
+                },
+                HeldFunds::Hold {
+                    id: 50,
+                    amount: dec!(1)
+                }
+            ]
+        );
+        assert_eq!(
+            holds.iter().map(HeldFunds::amount).sum::<Decimal>(),
+            engine.account_info(client).held
+        );
+
+        // a hold can share its id with a disputed transaction
+        engine
+            .engine
+            .handle(Transaction::new(client, Event::ReleaseHold { tx: 50 }))?;
+        engine.engine.handle(Transaction::new(
+            client,
+            Event::Hold {
+                tx: first.tx,
+                amount: 0.into(),
+            },
+        ))?;
+        engine.resolve(first)?;
+        assert_eq!(
+            engine.engine.account_holds(client),
+            vec![
+                HeldFunds::Dispute {
+                    tx: second.tx,
+                    amount: dec!(5)
+                },
+                HeldFunds::Hold {
+                    id: first.tx,
+                    amount: dec!(0)
+                }
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn max_clients() -> Result<()> {
+        let mut engine = Wrapper::new();
+        engine.engine.set_max_clients(Some(2));
+        engine.deposit(1, 5)?;
+        engine.withdraw(2, 5).unwrap_err();
+        let tx = engine.ids.peek().unwrap();
+        assert_eq!(
+            engine.deposit(3, 5).unwrap_err().error_type,
+            ErrorType::TooManyClients { client: 3 }
+        );
+        assert!(!engine.engine.contains_transaction(tx));
+        assert_eq!(engine.engine.client_ids().count(), 2);
+        engine.deposit(2, 5)?;
+        engine.deposit(1, 5)?;
+        Ok(())
+    }
+
+    #[test]
+    fn partial_resolve() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let client = 1;
+        let deposit = engine.deposit(client, 10)?;
+        let partial_resolve = |amount: Decimal| {
+            Transaction::new(
+                client,
+                Event::PartialResolve {
+                    tx: deposit.tx,
+                    amount,
+                },
+            )
+        };
+
+        assert_eq!(
+            engine
+                .engine
+                .handle(partial_resolve(dec!(1)))
+                .unwrap_err()
+                .error_type,
+            ErrorType::TransactionNotUnderDispute { tx: deposit.tx }
+        );
+        engine.dispute(deposit)?;
+        engine.engine.handle(partial_resolve(dec!(3)))?;
+        engine.engine.handle(partial_resolve(dec!(2.5)))?;
+        let info = engine.account_info(client);
+        assert_eq!((info.available, info.held), (dec!(5.5), dec!(4.5)));
+        assert_eq!(
+            engine.engine.account_holds(client),
+            vec![HeldFunds::Dispute {
+                tx: deposit.tx,
+                amount: dec!(4.5)
+            }]
+        );
+        assert_eq!(
+            engine
+                .engine
+                .handle(partial_resolve(dec!(4.6)))
+                .unwrap_err()
+                .error_type,
+            ErrorType::PartialResolveTooLarge { tx: deposit.tx }
+        );
+
+        // releasing the rest ends the dispute, so it can be disputed again in full
+        engine.engine.handle(partial_resolve(dec!(4.5)))?;
+        assert_eq!(engine.engine.disputed_transactions().count(), 0);
+        engine.dispute(deposit)?;
+        engine.engine.handle(partial_resolve(dec!(6)))?;
+        engine.resolve(deposit)?;
+        let info = engine.account_info(client);
+        assert_eq!((info.available, info.held), (dec!(10), dec!(0)));
+
+        // a chargeback only takes what is still held
+        engine.dispute(deposit)?;
+        engine.engine.handle(partial_resolve(dec!(7)))?;
+        engine.chargeback(deposit)?;
+        let info = engine.account_info(client);
+        assert_eq!((info.available, info.held), (dec!(7), dec!(0)));
+        assert!(info.locked);
+        Ok(())
+    }
+
+    /// Compares the engine in minor units mode with plain integer cents, over random transactions
+    #[test]
+    fn minor_units_match_integer_math() {
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut random = move |bound: u64| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed % bound
+        };
+        for _ in 0..20 {
+            let mut engine = Engine::with_minor_units(2);
+            let mut available = [0i128; 3];
+            let mut held = [0i128; 3];
+            let mut deposits: Vec<(ClientId, TransactionId, i128, bool)> = Vec::new();
+            for tx in 1..200 {
+                let client = random(3) as ClientId;
+                let cents = random(100_000) as i128;
+                let amount = Decimal::from_i128_with_scale(cents, 2);
+                let slot = client as usize;
+                let transaction = match random(4) {
+                    0 => {
+                        deposits.push((client, tx, cents, false));
+                        available[slot] += cents;
+                        Transaction::new(client, Event::Deposit { tx, amount })
+                    }
+                    1 => {
+                        if available[slot] >= cents {
+                            available[slot] -= cents;
+                        }
+                        Transaction::new(client, Event::Withdrawal { tx, amount })
+                    }
+                    _ if deposits.is_empty() => continue,
+                    _ => {
+                        let index = random(deposits.len() as u64) as usize;
+                        let (client, tx, cents, disputed) = &mut deposits[index];
+                        let slot = *client as usize;
+                        *disputed = !*disputed;
+                        if *disputed {
+                            held[slot] += *cents;
+                            available[slot] -= *cents;
+                            Transaction::new(
+                                *client,
+                                Event::Dispute {
+                                    tx: *tx,
+                                    reason: None,
+                                },
+                            )
+                        } else {
+                            held[slot] -= *cents;
+                            available[slot] += *cents;
+                            Transaction::new(*client, Event::Resolve { tx: *tx })
+                        }
+                    }
+                };
+                let _ = engine.handle(transaction);
+            }
+            for client in 0..3 {
+                let info = engine.account_info(client);
+                let slot = client as usize;
+                assert_eq!(
+                    info.available,
+                    Decimal::from_i128_with_scale(available[slot], 2)
+                );
+                assert_eq!(info.held, Decimal::from_i128_with_scale(held[slot], 2));
+            }
+        }
+    }
+
+    /// Runs the same random transactions through an engine in minor units mode and one with `Decimal` balances,
+    /// which have to accept and reject the same ones and end up with the same accounts
+    #[test]
+    fn minor_units_match_decimal() {
+        let mut seed: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut random = move |bound: u64| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed % bound
+        };
+        let cents = |units: u64| Decimal::from_i128_with_scale(units as i128, 2);
+        for _ in 0..20 {
+            let mut minor = Engine::with_minor_units(2);
+            let mut decimal = Engine::new();
+            // interest is rounded to the output scale without minor units
+            decimal.set_output_scale(2);
+            for engine in [&mut minor, &mut decimal] {
+                engine.set_withdrawal_fee(dec!(0.25)).unwrap();
+                engine.set_chargeback_fee(dec!(1.50)).unwrap();
+            }
+            for tx in 1..300 {
+                let client = random(3) as ClientId;
+                let amount = cents(random(100_000));
+                let earlier = (random(tx as u64) as TransactionId).max(1);
+                let event = match random(11) {
+                    0 | 1 => Event::Deposit { tx, amount },
+                    2 => Event::Withdrawal { tx, amount },
+                    3 => Event::Adjustment {
+                        tx,
+                        amount: -amount,
+                        reason: "correction".to_string(),
+                    },
+                    4 => Event::Dispute {
+                        tx: earlier,
+                        reason: None,
+                    },
+                    5 => Event::Resolve { tx: earlier },
+                    6 => Event::PartialResolve {
+                        tx: earlier,
+                        amount: cents(random(10_000)),
+                    },
+                    7 => Event::Chargeback { tx: earlier },
+                    8 => Event::ReverseChargeback { tx: earlier },
+                    9 => Event::Hold { tx, amount },
+                    _ => Event::ReleaseHold { tx: earlier },
+                };
+                let transaction = Transaction::new(client, event);
+                assert_eq!(
+                    minor
+                        .handle(transaction.clone())
+                        .map_err(|err| err.error_type),
+                    decimal.handle(transaction).map_err(|err| err.error_type)
+                );
+            }
+            assert_eq!(
+                minor.accrue_interest(dec!(0.0137)).unwrap(),
+                decimal.accrue_interest(dec!(0.0137)).unwrap()
+            );
+            assert_eq!(minor.all_accounts_sorted(), decimal.all_accounts_sorted());
+            assert_eq!(minor.state_hash(), decimal.state_hash());
+            minor.verify_invariants().unwrap();
+            // nothing fell back to `Decimal` on the way
+            assert!(minor.state.values().all(|state| {
+                matches!(
+                    (state.available, state.held),
+                    (Balance::Minor { .. }, Balance::Minor { .. })
+                )
+            }));
+        }
+    }
+
+    #[test]
+    fn inexact_amount() -> Result<()> {
+        let mut engine = Wrapper {
+            engine: Engine::with_minor_units(2),
+            ids: TransactionIdAllocator::new(),
+        };
+        engine.deposit(1, dec!(1.25))?;
+        engine.deposit(1, dec!(1.2500))?;
+        let tx = engine.ids.peek().unwrap();
+        assert_eq!(
+            engine.withdraw(1, dec!(0.001)).unwrap_err().error_type,
+            ErrorType::InexactAmount {
+                tx,
+                amount: dec!(0.001)
+            }
+        );
+        assert!(!engine.engine.contains_transaction(tx));
+
+        // fees and limits can't be more precise than the balances either
+        engine.engine.set_withdrawal_fee(dec!(0.50))?;
+        engine.engine.set_daily_withdrawal_limit(Some(dec!(100)))?;
+        for result in [
+            engine.engine.set_withdrawal_fee(dec!(0.005)),
+            engine.engine.set_chargeback_fee(dec!(0.005)),
+            engine.engine.set_overdraft_limit(1, dec!(0.001)),
+            engine.engine.set_withdrawal_bounds(None, Some(dec!(0.001))),
+            engine.engine.set_daily_withdrawal_limit(Some(dec!(0.001))),
+            engine.engine.set_max_transaction_amount(Some(dec!(0.001))),
+        ] {
+            assert_eq!(
+                result.unwrap_err().error_type.kind(),
+                ErrorKind::InvalidConfig
+            );
+        }
+        assert_eq!(engine.engine.withdrawal_fee(), dec!(0.50));
+        assert_eq!(engine.engine.daily_withdrawal_limit(), Some(dec!(100)));
+        Ok(())
+    }
+
+    #[test]
+    fn early_disputes() -> Result<()> {
+        let mut engine = Engine::new();
+        engine.set_buffer_early_disputes(true);
+        let client = 1;
+        engine.handle(Transaction::new(
+            client,
+            Event::Dispute {
+                tx: 1,
+                reason: None,
+            },
+        ))?;
+        engine.handle(Transaction::new(
+            client,
+            Event::Dispute {
+                tx: 2,
+                reason: None,
+            },
+        ))?;
+        assert_eq!(engine.unmatched_disputes(), vec![1, 2]);
+
+        engine.handle(Transaction::new(
+            client,
+            Event::Deposit {
+                tx: 1,
+                amount: 10.into(),
+            },
+        ))?;
+        let info = engine.account_info(client);
+        assert_eq!((info.available, info.held), (dec!(0), dec!(10)));
+        assert_eq!(engine.unmatched_disputes(), vec![2]);
+        engine.handle(Transaction::new(client, Event::Resolve { tx: 1 }))?;
+        assert_eq!(engine.account_info(client).available, dec!(10));
+        Ok(())
+    }
+
+    #[test]
+    fn rollback_early_disputes() -> Result<()> {
+        let mut engine = Engine::new();
+        engine.set_buffer_early_disputes(true);
+        let client = 1;
+        let dispute = |tx| Transaction::new(client, Event::Dispute { tx, reason: None });
+        let deposit = Transaction::new(
+            client,
+            Event::Deposit {
+                tx: 1,
+                amount: dec!(10),
+            },
+        );
+        engine.handle(dispute(1))?;
+        engine.handle(dispute(2))?;
+        engine.rollback(1)?;
+        assert_eq!(engine.unmatched_disputes(), vec![1]);
+
+        // the buffered dispute is handled (and rolled back) on its own, right after the deposit
+        engine.handle(deposit.clone())?;
+        assert_eq!(engine.account_info(client).held, dec!(10));
+        engine.rollback(2)?;
+        assert_eq!(engine.unmatched_disputes(), vec![1]);
+        assert_eq!(engine.account_info(client).held, dec!(0));
+
+        engine.rollback(1)?;
+        assert!(engine.unmatched_disputes().is_empty());
+        engine.handle(deposit)?;
+        let info = engine.account_info(client);
+        assert_eq!((info.available, info.held), (dec!(10), dec!(0)));
+        Ok(())
+    }
+
+    #[test]
+    fn early_dispute_of_rejected_withdrawal() -> Result<()> {
+        let tally = Arc::new(Mutex::new(Tally::default()));
+        let mut engine = Wrapper::new();
+        engine.engine.set_buffer_early_disputes(true);
+        engine.engine.set_observer(Box::new(tally.clone()));
+        engine.deposit(1, 10)?;
+        let tx = engine.ids.peek().unwrap();
+        engine
+            .engine
+            .handle(Transaction::new(1, Event::Dispute { tx, reason: None }))?;
+        assert!(engine.withdraw(1, 50).is_err());
+
+        // the withdrawal never debited anything, so its dispute has nothing to give back
+        let info = engine.account_info(1);
+        assert_eq!((info.available, info.held), (dec!(10), dec!(0)));
+        assert_eq!(engine.engine.unmatched_disputes(), vec![tx]);
+        assert_eq!(tally.lock().unwrap().disputes, 0);
+
+        let tx = engine.ids.peek().unwrap();
+        engine
+            .engine
+            .handle(Transaction::new(1, Event::Dispute { tx, reason: None }))?;
+        engine.withdraw(1, 4)?;
+        let info = engine.account_info(1);
+        assert_eq!((info.available, info.held), (dec!(10), dec!(-4)));
+        assert_eq!(tally.lock().unwrap().disputes, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn fnv1a() {
+        let hash = |bytes: &[u8]| {
+            let mut hasher = Fnv1a::default();
+            hasher.write(bytes);
+            hasher.finish()
+        };
+        // published test vectors, so hashes stay comparable between builds
+        assert_eq!(hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(hash(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn state_hash() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let first = engine.deposit(1, 10)?;
+        engine.deposit(2, 5)?;
+        engine.dispute(first)?;
+        let hash = engine.engine.state_hash();
+
+        assert!(engine
+            .engine
+            .handle(Transaction::new(
+                1,
+                Event::Dispute {
+                    tx: 42,
+                    reason: None
+                }
+            ))
+            .is_err());
+        assert_eq!(engine.engine.state_hash(), hash);
+
+        // same state, reached in a different order
+        let mut other = Wrapper::new();
+        other.ids = TransactionIdAllocator::starting_at(2);
+        other.deposit(2, 5)?;
+        other.engine.handle(Transaction::new(
+            1,
+            Event::Deposit {
+                tx: first.tx,
+                amount: dec!(10.00),
+            },
+        ))?;
+        other.dispute(first)?;
+        assert_eq!(other.engine.state_hash(), hash);
+
+        engine.deposit(1, 1)?;
+        assert_ne!(engine.engine.state_hash(), hash);
+        Ok(())
+    }
+
+    #[test]
+    fn max_transaction_amount() {
+        let mut engine = Engine::new();
+        engine
+            .set_max_transaction_amount(Some(dec!(1_000_000)))
+            .unwrap();
+        engine
+            .handle(Transaction::new(
+                1,
+                Event::Deposit {
+                    tx: 1,
+                    amount: dec!(1_000_000),
+                },
+            ))
+            .unwrap();
+        engine
+            .handle(Transaction::new(
+                1,
+                Event::Withdrawal {
+                    tx: 2,
+                    amount: dec!(999_999),
+                },
+            ))
+            .unwrap();
+
+        for (tx, amount, event) in [
+            (
+                3,
+                dec!(100_000_000_000_000_000_000),
+                Event::Deposit {
+                    tx: 3,
+                    amount: dec!(100_000_000_000_000_000_000),
+                },
+            ),
+            (
+                4,
+                dec!(1_000_000.01),
+                Event::Withdrawal {
+                    tx: 4,
+                    amount: dec!(1_000_000.01),
+                },
+            ),
+        ] {
+            let err = engine.handle(Transaction::new(1, event)).unwrap_err();
+            assert_eq!(err.error_type, ErrorType::AmountTooLarge { tx, amount });
+        }
+        // rejected before recording anything, so the ids are still free
+        engine
+            .handle(Transaction::new(
+                1,
+                Event::Deposit {
+                    tx: 3,
+                    amount: dec!(5),
+                },
+            ))
+            .unwrap();
+        assert_eq!(engine.account_info(1).available, dec!(6));
+    }
+
+    #[test]
+    fn rechargeback_after_reverse() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let client = 1;
+        let deposit = engine.deposit(client, 100)?;
+        let other = engine.deposit(client, 10)?;
+
+        for _ in 0..2 {
+            engine.dispute(deposit)?;
+            engine.chargeback(deposit)?;
+            assert_eq!(engine.account_info(client).total, dec!(10));
+            assert!(engine.account_info(client).locked);
+
+            engine.reverse_chargeback(deposit)?;
+            assert_eq!(engine.account_info(client).available, dec!(110));
+            assert!(!engine.account_info(client).locked);
+            assert_eq!(
+                engine.reverse_chargeback(deposit).unwrap_err().error_type,
+                ErrorType::TransactionNotChargedBack { tx: deposit.tx }
+            );
+        }
+
+        // still locked while another transaction stays charged back
+        engine.dispute(other)?;
+        engine.chargeback(other)?;
+        engine.dispute(deposit)?;
+        engine.chargeback(deposit)?;
+        engine.reverse_chargeback(deposit)?;
+        assert!(engine.account_info(client).locked);
+        engine.reverse_chargeback(other)?;
+        assert!(!engine.account_info(client).locked);
+        assert_eq!(engine.account_info(client).total, dec!(110));
+        Ok(())
+    }
+
+    #[test]
+    fn reverse_partially_resolved_chargeback() -> Result<()> {
+        let mut engine = Wrapper::new();
+        engine.engine.set_chargeback_fee(dec!(1))?;
+        let client = 1;
+        let deposit = engine.deposit(client, 100)?;
+        engine.dispute(deposit)?;
+        engine.engine.handle(Transaction::new(
+            client,
+            Event::PartialResolve {
+                tx: deposit.tx,
+                amount: dec!(70),
+            },
+        ))?;
+        engine.chargeback(deposit)?;
+        assert_eq!(engine.account_info(client).total, dec!(69));
+        engine.reverse_chargeback(deposit)?;
+        let info = engine.account_info(client);
+        assert_eq!((info.available, info.held), (dec!(99), dec!(0)));
+        assert!(!info.locked);
+
+        // a second round only gives back what was charged back then
+        engine.dispute(deposit)?;
+        engine.chargeback(deposit)?;
+        engine.reverse_chargeback(deposit)?;
+        assert_eq!(engine.account_info(client).available, dec!(98));
+        Ok(())
+    }
+
+    #[test]
+    fn reverse_chargeback_keeps_other_locks() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let client = 1;
+        let standing = engine.deposit(client, 10)?;
+        let reversed = engine.deposit(client, 20)?;
+        engine.dispute(standing)?;
+        engine.chargeback(standing)?;
+        engine.dispute(reversed)?;
+        engine.chargeback(reversed)?;
+        engine.reverse_chargeback(reversed)?;
+        // the other chargeback still stands
+        assert!(engine.account_info(client).locked);
+        engine.reverse_chargeback(standing)?;
+        assert!(!engine.account_info(client).locked);
+
+        // seeded as locked, with a deposit of the client merged in from another shard
+        let mut shard = Engine::new();
+        shard.handle(Transaction::new(
+            2,
+            Event::Deposit {
+                tx: 100,
+                amount: dec!(5),
+            },
+        ))?;
+        engine.engine.seed_account(2, dec!(0), dec!(0), true)?;
+        engine.engine.merge(shard)?;
+        for event in [
+            Event::Dispute {
+                tx: 100,
+                reason: None,
+            },
+            Event::Chargeback { tx: 100 },
+            Event::ReverseChargeback { tx: 100 },
+        ] {
+            engine.engine.handle(Transaction::new(2, event))?;
+        }
+        let info = engine.account_info(2);
+        assert_eq!(info.available, dec!(5));
+        assert!(info.locked);
+        Ok(())
+    }
+
+    #[test]
+    fn seed_account() -> Result<()> {
+        let mut engine = Wrapper::new();
+        engine.engine.seed_account(1, dec!(100), dec!(20), false)?;
+        engine.engine.seed_account(2, dec!(5), dec!(0), true)?;
+        assert_eq!(
+            engine
+                .engine
+                .seed_account(1, dec!(1), dec!(0), false)
+                .unwrap_err()
+                .error_type,
+            ErrorType::AccountAlreadyExists { client: 1 }
+        );
+        assert_eq!(engine.account_info(1).total, dec!(120));
+
+        engine.withdraw(1, 100)?;
+        assert!(engine.withdraw(1, 1).is_err());
+        let deposit = engine.deposit(1, 10)?;
+        engine.dispute(deposit)?;
+        assert_eq!(engine.account_info(1).held, dec!(30));
+        assert_eq!(engine.account_info(1).available, dec!(0));
+
+        assert!(engine.account_info(2).locked);
+        assert_eq!(
+            engine.deposit(2, 1).unwrap_err().error_type,
+            ErrorType::LockedAccount { client: 2 }
+        );
+
+        assert_eq!(
+            engine
+                .engine
+                .seed_account(3, Decimal::MAX, dec!(1), false)
+                .unwrap_err()
+                .error_type,
+            ErrorType::SeedOverflow { client: 3 }
+        );
+        let mut engine = Engine::with_minor_units(2);
+        assert_eq!(
+            engine
+                .seed_account(1, dec!(1), dec!(0.005), false)
+                .unwrap_err()
+                .error_type,
+            ErrorType::InexactSeed {
+                client: 1,
+                amount: dec!(0.005)
+            }
+        );
+        engine.seed_account(1, dec!(1.10), dec!(0.05), false)?;
+        assert_eq!(engine.account_info(1).total, dec!(1.15));
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_json() -> Result<()> {
+        let mut engine = Wrapper::new();
+        engine.deposit(2, dec!(1.5))?;
+        let deposit = engine.deposit(1, 10)?;
+        engine.dispute(deposit)?;
+        assert_eq!(
+            engine.engine.accounts_json(),
+            serde_json::json!([
+                {"client": 1, "available": "0", "held": "10", "total": "10", "locked": false, "currency": null, "closed": false},
+                {"client": 2, "available": "1.5", "held": "0", "total": "1.5", "locked": false, "currency": null, "closed": false},
+            ])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rounding_mode() -> Result<()> {
+        let mut engine = Wrapper::new();
+        engine.deposit(1, 1)?;
+        assert_eq!(engine.engine.rounding(), RoundingMode::HalfEven);
+        // 0.00005 of interest is exactly halfway between 0 and 0.0001
+        assert_eq!(engine.engine.accrue_interest(dec!(0.00005))?, dec!(0));
+
+        engine.engine.set_rounding(RoundingMode::HalfUp);
+        assert_eq!(engine.engine.accrue_interest(dec!(0.00005))?, dec!(0.0001));
+        Ok(())
+    }
+
+    #[test]
+    fn journal_replay() -> Result<()> {
+        #[derive(Clone, Default)]
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let journal = Shared::default();
+        let mut engine = Wrapper::new();
+        engine.engine.set_journal(Box::new(journal.clone()));
+        let deposit = engine.deposit(1, dec!(10.5))?;
+        engine.withdraw(1, 3)?;
+        assert!(engine.withdraw(1, 100).is_err());
+        engine.dispute(deposit)?;
+        engine.engine.handle(Transaction {
+            currency: Some("EUR".parse().unwrap()),
+            timestamp: Some(1_700_000_000),
+            ..Transaction::new(
+                2,
+                Event::Deposit {
+                    tx: 10,
+                    amount: dec!(1),
+                },
+            )
+        })?;
+
+        let written = journal.0.lock().unwrap().clone();
+        assert_eq!(String::from_utf8_lossy(&written).lines().count(), 4);
+        let (replayed, errors) = Engine::from_json_lines(&written[..])?;
+        assert!(errors.is_empty());
+        assert_eq!(
+            replayed.all_accounts_sorted(),
+            engine.engine.all_accounts_sorted()
+        );
+        assert_eq!(replayed.last_activity(2), Some(1_700_000_000));
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_by_locked_status() -> Result<()> {
+        let mut engine = Wrapper::new();
+        for client in 1..=4 {
+            let deposit = engine.deposit(client, 10)?;
+            if client % 2 == 0 {
+                engine.dispute(deposit)?;
+                engine.chargeback(deposit)?;
+            }
+        }
+        let clients = |accounts: Vec<AccountInfo>| {
+            let mut clients: Vec<_> = accounts.into_iter().map(|info| info.client).collect();
+            clients.sort();
+            clients
+        };
+        assert_eq!(clients(engine.engine.locked_accounts().collect()), [2, 4]);
+        assert_eq!(clients(engine.engine.unlocked_accounts().collect()), [1, 3]);
+        assert!(engine.engine.locked_accounts().all(|info| info.locked));
+        Ok(())
+    }
+
+    #[test]
+    fn mismatched_client_names_owner() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let (owner, submitter) = (7, 9);
+        let deposit = engine.deposit(owner, 10)?;
+        let err = engine
+            .dispute(CommitedTransaction {
+                client: submitter,
+                tx: deposit.tx,
+            })
+            .unwrap_err();
+        assert_eq!(
+            err.error_type,
+            ErrorType::TransactionDoesNotMatchClient {
+                tx: deposit.tx,
+                client: submitter,
+                owner,
+            }
+        );
+        let message = err.to_string();
+        assert!(
+            message.contains("client: 9") && message.contains("owner: 7"),
+            "{message}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn auto_resolve_expired() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let old = engine.deposit(1, 10)?;
+        let recent = engine.deposit(1, 5)?;
+        let charged_back = engine.deposit(2, 1)?;
+        for (transaction, timestamp) in [(old, 1_000), (recent, 5_000), (charged_back, 1_000)] {
+            engine.engine.handle(Transaction {
+                timestamp: Some(timestamp),
+                ..Transaction::new(
+                    transaction.client,
+                    Event::Dispute {
+                        tx: transaction.tx,
+                        reason: None,
+                    },
+                )
+            })?;
+        }
+        engine.chargeback(charged_back)?;
+
+        let hour = Duration::from_secs(3_600);
+        assert!(engine.engine.auto_resolve_expired(4_000, hour).is_empty());
+        assert_eq!(engine.engine.auto_resolve_expired(4_601, hour), [old.tx]);
+        assert_eq!(engine.account_info(1).available, dec!(10));
+        assert_eq!(engine.account_info(1).held, dec!(5));
+
+        assert_eq!(
+            engine.engine.auto_resolve_expired(10_000, hour),
+            [recent.tx]
+        );
+        assert_eq!(engine.account_info(1).available, dec!(15));
+        assert_eq!(engine.account_info(1).held, dec!(0));
+        Ok(())
+    }
+
+    #[test]
+    fn config_getters() {
+        let mut engine = Engine::new();
+        assert!(!engine.global_dispute());
+        engine.set_global_dispute(true);
+        assert!(engine.global_dispute());
+        engine.set_global_dispute(false);
+        assert!(!engine.global_dispute());
+
+        engine.set_withdrawal_bounds(Some(dec!(1)), None).unwrap();
+        engine.set_max_transaction_amount(Some(dec!(100))).unwrap();
+        engine.set_error_severity(ErrorKind::InsufficientFunds, Severity::Warning);
+        assert_eq!(engine.withdrawal_bounds(), (Some(dec!(1)), None));
+        assert_eq!(engine.max_transaction_amount(), Some(dec!(100)));
+        assert_eq!(
+            engine.error_severity(ErrorKind::InsufficientFunds),
+            Severity::Warning
+        );
+        assert_eq!(
+            engine.error_severity(ErrorKind::LockedAccount),
+            Severity::Error
+        );
+        assert_eq!(engine.minor_units(), None);
+        assert_eq!(Engine::with_minor_units(2).minor_units(), Some(2));
+        assert!(engine.allow_negative_available_on_dispute());
+        assert_eq!(engine.chargeback_fee(), dec!(0));
+    }
+
+    #[test]
+    fn apply_batch() -> Result<()> {
+        let mut engine = Wrapper::new();
+        engine.deposit(1, 10)?;
+        let locks = Arc::new(AtomicUsize::new(0));
+        engine.engine.set_on_lock({
+            let locks = locks.clone();
+            move |_, _| {
+                locks.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        let before = engine.engine.state_hash();
+
+        let batch = vec![
+            Transaction::new(
+                1,
+                Event::Deposit {
+                    tx: 2,
+                    amount: dec!(5),
+                },
+            ),
+            Transaction::new(
+                2,
+                Event::Deposit {
+                    tx: 3,
+                    amount: dec!(7),
+                },
+            ),
+            Transaction::new(
+                1,
+                Event::Withdrawal {
+                    tx: 4,
+                    amount: dec!(100),
+                },
+            ),
+            Transaction::new(
+                1,
+                Event::Dispute {
+                    tx: 1,
+                    reason: None,
+                },
+            ),
+        ];
+        let err = engine.engine.apply_batch(batch).unwrap_err();
+        assert_eq!(
+            err.error_type,
+            ErrorType::BatchFailed {
+                index: 2,
+                error: Box::new(ErrorType::InsufficientFunds { client: 1, tx: 4 }),
+            }
+        );
+        assert_eq!(engine.engine.state_hash(), before);
+        assert!(!engine.engine.contains_transaction(2));
+        assert!(engine.engine.client_ids().all(|client| client == 1));
+
+        engine.engine.apply_batch(vec![
+            Transaction::new(
+                1,
+                Event::Deposit {
+                    tx: 2,
+                    amount: dec!(5),
+                },
+            ),
+            Transaction::new(
+                1,
+                Event::Dispute {
+                    tx: 1,
+                    reason: None,
+                },
+            ),
+            Transaction::new(1, Event::Chargeback { tx: 1 }),
+        ])?;
+        assert_eq!(engine.account_info(1).total, dec!(5));
+        assert!(engine.account_info(1).locked);
+        assert_eq!(locks.load(Ordering::Relaxed), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn close_account() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let client = 1;
+        let deposit = engine.deposit(client, 10)?;
+        engine.withdraw(client, 4)?;
+        assert_eq!(
+            engine.engine.close_account(2).unwrap_err().error_type,
+            ErrorType::UnknownAccount { client: 2 }
+        );
+
+        // held funds, disputed or on hold, keep the account open
+        engine.dispute(deposit)?;
+        assert_eq!(
+            engine.engine.close_account(client).unwrap_err().error_type,
+            ErrorType::CannotCloseWithOpenDisputes { client }
+        );
+        engine.resolve(deposit)?;
+        engine.engine.handle(Transaction::new(
+            client,
+            Event::Hold {
+                tx: 1,
+                amount: dec!(1),
+            },
+        ))?;
+        assert_eq!(
+            engine.engine.close_account(client).unwrap_err().error_type,
+            ErrorType::CannotCloseWithOpenDisputes { client }
+        );
+        engine
+            .engine
+            .handle(Transaction::new(client, Event::ReleaseHold { tx: 1 }))?;
+        assert!(!engine.account_info(client).closed);
+
+        engine.engine.close_account(client)?;
+        assert!(engine.account_info(client).closed);
+        assert_eq!(engine.account_info(client).available, dec!(6));
+        for result in [
+            engine.deposit(client, 1).map(|_| ()),
+            engine.withdraw(client, 1).map(|_| ()),
+            engine.dispute(deposit).map(|_| ()),
+        ] {
+            assert_eq!(
+                result.unwrap_err().error_type,
+                ErrorType::ClosedAccount { client }
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_in_insertion_order() -> Result<()> {
+        let mut engine = Wrapper::new();
+        for client in [5, 2, 9] {
+            engine.deposit(client, 1)?;
+        }
+        engine.deposit(2, 1)?;
+        let order = |engine: &Wrapper| {
+            engine
+                .engine
+                .accounts_in_insertion_order()
+                .map(|info| info.client)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(order(&engine), [5, 2, 9]);
+
+        engine.deposit(7, 1)?;
+        engine.engine.rollback(1)?;
+        engine.deposit(1, 1)?;
+        assert_eq!(order(&engine), [5, 2, 9, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn preview_withdrawal() -> Result<()> {
+        let mut engine = Wrapper::new();
+        engine.deposit(1, 10)?;
+        assert_eq!(engine.engine.preview_withdrawal(1, dec!(4))?, dec!(6));
+        assert_eq!(engine.engine.preview_withdrawal(1, dec!(10))?, dec!(0));
+        assert_eq!(engine.engine.account_info(1).available, dec!(10));
+
+        assert_eq!(
+            engine
+                .engine
+                .preview_withdrawal(1, dec!(10.01))
+                .unwrap_err()
+                .error_type,
+            ErrorType::InsufficientFunds { client: 1, tx: 0 }
+        );
+        assert_eq!(
+            engine
+                .engine
+                .preview_withdrawal(2, dec!(1))
+                .unwrap_err()
+                .error_type,
+            ErrorType::InsufficientFunds { client: 2, tx: 0 }
+        );
+        assert!(engine.engine.client_ids().all(|client| client != 2));
+
+        let deposit = engine.deposit(1, 5)?;
+        engine.dispute(deposit)?;
+        engine.chargeback(deposit)?;
+        assert_eq!(
+            engine
+                .engine
+                .preview_withdrawal(1, dec!(1))
+                .unwrap_err()
+                .error_type,
+            ErrorType::LockedAccount { client: 1 }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn dispute_reason() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let with_reason = engine.deposit(1, 10)?;
+        let without_reason = engine.deposit(1, 20)?;
+        engine.engine.handle(Transaction::new(
+            1,
+            Event::Dispute {
+                tx: with_reason.tx,
+                reason: Some("10.4".to_string()),
+            },
+        ))?;
+        engine.dispute(without_reason)?;
+
+        let mut disputes: Vec<_> = engine.engine.disputed_transactions().collect();
+        disputes.sort_by_key(|dispute| dispute.tx);
+        let reasons: Vec<_> = disputes.into_iter().map(|dispute| dispute.reason).collect();
+        assert_eq!(reasons, [Some("10.4".to_string()), None]);
+        Ok(())
+    }
+
+    #[test]
+    fn max_open_disputes_per_client() -> Result<()> {
+        let mut engine = Wrapper::new();
+        engine.engine.set_max_open_disputes_per_client(Some(1));
+        let first = engine.deposit(1, 10)?;
+        let second = engine.deposit(1, 20)?;
+        let third = engine.deposit(1, 30)?;
+        let other_client = engine.deposit(2, 40)?;
+
+        engine.dispute(first)?;
+        assert_eq!(
+            engine.dispute(second).unwrap_err().error_type,
+            ErrorType::TooManyOpenDisputes { client: 1 }
+        );
+        assert_eq!(engine.engine.account_info(1).held, dec!(10));
+        engine.dispute(other_client)?;
+
+        engine.resolve(first)?;
+        engine.dispute(second)?;
+        engine.chargeback(second)?;
+        engine.dispute(third)?;
+        assert_eq!(engine.engine.account_info(1).held, dec!(30));
+
+        // a rolled back resolve reopens its dispute, a full partial resolve closes it
+        let partial = Event::PartialResolve {
+            tx: third.tx,
+            amount: dec!(30),
+        };
+        engine.resolve(third)?;
+        engine.engine.rollback(1)?;
+        assert!(engine.dispute(first).is_err());
+        engine.engine.handle(Transaction::new(1, partial))?;
+        engine.dispute(first)?;
+
+        // disputes open in another shard count after merging
+        let mut shard = Engine::new();
+        shard.set_max_open_disputes_per_client(Some(1));
+        for event in [
+            Event::Deposit {
+                tx: 100,
+                amount: dec!(5),
+            },
+            Event::Dispute {
+                tx: 100,
+                reason: None,
+            },
+        ] {
+            shard.handle(Transaction::new(3, event))?;
+        }
+        let last = engine.deposit(3, 1)?;
+        engine.engine.merge(shard)?;
+        assert_eq!(
+            engine.dispute(last).unwrap_err().error_type,
+            ErrorType::TooManyOpenDisputes { client: 3 }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn clear() -> Result<()> {
+        let mut engine = Wrapper::new();
+        engine.engine.set_global_dispute(true);
+        engine.engine.set_output_scale(2);
+        engine.engine.set_max_clients(Some(1));
+        engine.engine.set_withdrawal_bounds(None, Some(dec!(100)))?;
+        let deposit = engine.deposit(1, 10)?;
+        engine.dispute(deposit)?;
+
+        engine.engine.clear();
+        assert_eq!(engine.engine.all_accounts().count(), 0);
+        assert_eq!(engine.engine.disputed_transactions().count(), 0);
+        assert!(engine.engine.global_dispute());
+        assert_eq!(engine.engine.output_scale(), 2);
+        assert_eq!(engine.engine.max_clients(), Some(1));
+        assert_eq!(engine.engine.withdrawal_bounds(), (None, Some(dec!(100))));
+
+        // the old transaction id and client are free again, but the limits still apply
+        engine.engine.handle(Transaction::new(
+            2,
+            Event::Deposit {
+                tx: deposit.tx,
+                amount: dec!(5),
+            },
+        ))?;
+        assert_eq!(
+            engine.deposit(3, 5).unwrap_err().error_type,
+            ErrorType::TooManyClients { client: 3 }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn take_dirty_accounts() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let deposit = engine.deposit(1, 10)?;
+        engine.deposit(2, 20)?;
+        let clients = |engine: &mut Wrapper| {
+            engine
+                .engine
+                .take_dirty_accounts()
+                .into_iter()
+                .map(|info| info.client)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(clients(&mut engine), [1, 2]);
+        assert!(clients(&mut engine).is_empty());
+
+        engine.dispute(deposit)?;
+        engine.withdraw(3, 5).unwrap_err();
+        engine.withdraw(2, 50).unwrap_err();
+        let dirty = engine.engine.take_dirty_accounts();
+        assert_eq!(dirty.len(), 2);
+        assert_eq!(dirty[0].held, dec!(10));
+        // created by the rejected withdrawal, while client 2 didn't change
+        assert_eq!(dirty[1].client, 3);
+
+        engine.engine.rollback(1)?;
+        assert_eq!(clients(&mut engine), [2]);
+        Ok(())
+    }
+
+    #[test]
+    fn chargeback_without_account() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let deposit = engine.deposit(1, 10)?;
+        engine.dispute(deposit)?;
+        let snapshot = bincode::serialize(&(
+            IndexMap::<ClientId, ClientState>::new(),
+            engine
+                .engine
+                .funds_transactions
+                .iter()
+                .collect::<HashMap<_, _>>(),
+            &engine.engine.holds,
+        ))
+        .unwrap();
+
+        let mut engine = Engine::from_bytes(&snapshot)?;
         assert_eq!(
             engine
-                .handle(Transaction {
-                    client,
-                    event: Event::Withdrawal {
-                        tx: 2,
-                        amount: 2.into()
-                    }
-                })
+                .handle(Transaction::new(1, Event::Chargeback { tx: deposit.tx }))
                 .unwrap_err()
                 .error_type,
-            ErrorType::InsufficientFunds { client, tx: 2 }
+            ErrorType::UnknownAccount { client: 1 }
         );
-        engine
-            .handle(Transaction {
-                client,
-                event: Event::Withdrawal {
-                    tx: 3,
-                    amount: dec!(0.1234),
-                },
-            })
-            .unwrap();
-
-        let info: Vec<_> = engine.all_accounts().collect();
-        assert_eq!(
-            info,
-            vec![AccountInfo {
-                client,
-                available: dec!(1.1111),
-                held: Decimal::ZERO,
-                total: dec!(1.1111),
-                locked: false,
-            }]
-        )
-    }
-
-    struct Wrapper {
-        engine: Engine,
-        next_tx: TransactionId,
+        assert_eq!(engine.all_accounts().count(), 0);
+        assert_eq!(engine.disputed_transactions().count(), 1);
+        Ok(())
     }
 
-    #[derive(Copy, Clone)]
-    struct CommitedTransaction {
-        client: ClientId,
-        tx: TransactionId,
-    }
+    #[test]
+    fn daily_withdrawal_limit() -> Result<()> {
+        let mut engine = Wrapper::new();
+        engine.engine.set_daily_withdrawal_limit(Some(dec!(100)))?;
+        engine.deposit(1, 500)?;
+        engine.deposit(2, 500)?;
 
-    impl Wrapper {
-        fn new() -> Self {
-            Self {
-                engine: Engine::new(),
-                next_tx: 1,
-            }
-        }
+        engine.withdraw(1, 60)?;
+        assert_eq!(
+            engine.withdraw(1, 50).unwrap_err().error_type,
+            ErrorType::DailyLimitExceeded { client: 1 }
+        );
+        assert_eq!(
+            engine
+                .engine
+                .preview_withdrawal(1, dec!(50))
+                .unwrap_err()
+                .error_type,
+            ErrorType::DailyLimitExceeded { client: 1 }
+        );
+        engine.withdraw(1, 40)?;
+        engine.withdraw(2, 100)?;
+        assert_eq!(engine.engine.account_info(1).available, dec!(400));
 
-        fn deposit(
-            &mut self,
-            client: ClientId,
-            amount: impl Into<Decimal>,
-        ) -> Result<CommitedTransaction> {
-            let tx = self.next_tx;
-            self.next_tx += 1;
+        engine.engine.reset_daily_counters();
+        engine.withdraw(1, 100)?;
+        assert_eq!(engine.engine.account_info(1).available, dec!(300));
+        Ok(())
+    }
 
-            self.engine
-                .handle(Transaction {
-                    client,
-                    event: Event::Deposit {
-                        tx,
-                        amount: amount.into(),
-                    },
-                })
-                .map(|()| CommitedTransaction { client, tx })
+    #[test]
+    fn daily_withdrawal_limit_overflow() -> Result<()> {
+        let mut engine = Wrapper::new();
+        engine
+            .engine
+            .set_daily_withdrawal_limit(Some(Decimal::MAX))?;
+        let amount = dec!(39600000000000000000000000000);
+        for _ in 0..2 {
+            engine.deposit(1, amount)?;
+            engine.withdraw(1, amount)?;
         }
+        engine.deposit(1, amount)?;
+        assert_eq!(
+            engine.withdraw(1, amount).unwrap_err().error_type,
+            ErrorType::DailyLimitExceeded { client: 1 }
+        );
+        assert_eq!(engine.account_info(1).available, amount);
+        Ok(())
+    }
 
-        fn withdraw(
-            &mut self,
-            client: ClientId,
-            amount: impl Into<Decimal>,
-        ) -> Result<CommitedTransaction> {
-            let tx = self.next_tx;
-            self.next_tx += 1;
-
-            self.engine
-                .handle(Transaction {
-                    client,
-                    event: Event::Withdrawal {
-                        tx,
-                        amount: amount.into(),
-                    },
-                })
-                .map(|()| CommitedTransaction { client, tx })
-        }
+    #[test]
+    fn applied() -> Result<()> {
+        let mut engine = Wrapper::new();
+        engine.engine.set_idempotent(true);
+        engine.engine.set_buffer_early_disputes(true);
+        let deposit = Transaction::new(
+            1,
+            Event::Deposit {
+                tx: 1,
+                amount: dec!(10),
+            },
+        );
+        let dispute = |tx| Transaction::new(1, Event::Dispute { tx, reason: None });
 
-        fn dispute(&mut self, transaction: CommitedTransaction) -> Result<()> {
-            self.engine.handle(Transaction {
-                client: transaction.client,
-                event: Event::Dispute { tx: transaction.tx },
-            })
-        }
+        assert_eq!(engine.engine.handle(dispute(1))?, Applied::NoOp);
+        let json = serde_json::to_string(&deposit).unwrap();
+        assert_eq!(engine.engine.handle(deposit)?, Applied::Mutated);
+        assert_eq!(engine.engine.account_info(1).held, dec!(10));
+        let duplicate = serde_json::from_str(&json).unwrap();
+        assert_eq!(engine.engine.handle(duplicate)?, Applied::NoOp);
+        assert_eq!(
+            engine
+                .engine
+                .handle(Transaction::new(1, Event::Resolve { tx: 1 }))?,
+            Applied::Mutated
+        );
+        Ok(())
+    }
 
-        fn resolve(&mut self, transaction: CommitedTransaction) -> Result<()> {
-            self.engine.handle(Transaction {
-                client: transaction.client,
-                event: Event::Resolve { tx: transaction.tx },
-            })
-        }
+    #[test]
+    fn reject_unknown_account_withdrawals() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let tx = engine.ids.peek().unwrap();
+        assert_eq!(
+            engine.withdraw(1, 5).unwrap_err().error_type,
+            ErrorType::InsufficientFunds { client: 1, tx }
+        );
+        assert!(engine.engine.contains_transaction(tx));
+        assert_eq!(engine.engine.all_accounts().count(), 1);
 
-        fn chargeback(&mut self, transaction: CommitedTransaction) -> Result<()> {
-            self.engine.handle(Transaction {
-                client: transaction.client,
-                event: Event::Chargeback { tx: transaction.tx },
-            })
-        }
+        engine.engine.set_reject_unknown_account_withdrawals(true);
+        let tx = engine.ids.peek().unwrap();
+        assert_eq!(
+            engine.withdraw(2, 5).unwrap_err().error_type,
+            ErrorType::UnknownAccount { client: 2 }
+        );
+        assert_eq!(
+            engine
+                .engine
+                .preview_withdrawal(2, dec!(5))
+                .unwrap_err()
+                .error_type,
+            ErrorType::UnknownAccount { client: 2 }
+        );
+        assert!(!engine.engine.contains_transaction(tx));
+        assert_eq!(engine.engine.all_accounts().count(), 1);
 
-        fn account_info(&self, client: ClientId) -> AccountInfo {
-            self.engine.account_info(client)
-        }
+        // known accounts, even empty ones, still fail on their funds
+        assert_eq!(
+            engine.withdraw(1, 5).unwrap_err().error_type,
+            ErrorType::InsufficientFunds {
+                client: 1,
+                tx: tx + 1
+            }
+        );
+        engine.deposit(2, 10)?;
+        engine.withdraw(2, 5)?;
+        assert_eq!(engine.engine.account_info(2).available, dec!(5));
+        Ok(())
     }
 
     #[test]
-    fn test_multiple() -> Result<()> {
+    fn chargeback_fee_overflow() -> Result<()> {
         let mut engine = Wrapper::new();
-        let client_a = 1;
-        let client_b = 2;
-
-        engine.deposit(client_a, 5)?;
-        assert_eq!(engine.account_info(client_a).available, dec!(5));
-        assert_eq!(engine.account_info(client_b).available, dec!(0));
+        engine.engine.set_chargeback_fee(Decimal::MAX)?;
+        let first = engine.deposit(1, 10)?;
+        let second = engine.deposit(1, 10)?;
+        engine.dispute(first)?;
+        engine.dispute(second)?;
+        engine.chargeback(first)?;
+        assert_eq!(engine.account_info(1).available, -Decimal::MAX);
 
-        engine.deposit(client_b, 10)?;
-        assert_eq!(engine.account_info(client_a).available, dec!(5));
-        assert_eq!(engine.account_info(client_b).available, dec!(10));
+        assert_eq!(
+            engine.chargeback(second).unwrap_err().error_type,
+            ErrorType::ArithmeticOverflow {
+                client: 1,
+                tx: second.tx
+            }
+        );
+        let info = engine.account_info(1);
+        assert_eq!((info.available, info.held), (-Decimal::MAX, dec!(10)));
+        engine.resolve(second)?;
+        Ok(())
+    }
 
-        // this shouldn't work, even though b has enough, they have separate accounts
-        assert!(engine.withdraw(client_a, 10).is_err());
-        assert_eq!(engine.account_info(client_a).available, dec!(5));
-        assert_eq!(engine.account_info(client_b).available, dec!(10));
+    #[test]
+    fn verify_invariants() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let deposit = engine.deposit(1, 10)?;
+        let withdrawal = engine.withdraw(1, 4)?;
+        engine.deposit(2, 5)?;
+        engine.dispute(deposit)?;
+        engine.dispute(withdrawal)?;
+        engine.engine.handle(Transaction::new(
+            2,
+            Event::Hold {
+                tx: 1,
+                amount: dec!(2),
+            },
+        ))?;
+        engine.engine.verify_invariants()?;
 
-        engine.withdraw(client_b, 10)?;
-        engine.withdraw(client_a, 5)?;
-        assert_eq!(engine.account_info(client_a).available, dec!(0));
-        assert_eq!(engine.account_info(client_b).available, dec!(0));
+        let account = |held| ClientState {
+            available: Balance::Decimal(dec!(10)),
+            held: Balance::Decimal(held),
+            ..ClientState::default()
+        };
+        let disputed = |client, held| TransactionInfo {
+            status: Status::UnderDispute,
+            held: SignedAmount::credit(held),
+            ..TransactionInfo::new(client, SignedAmount::credit(dec!(10)), 0)
+        };
+        let consistent = Engine::from_parts([(1, account(dec!(6)))], [(1, disputed(1, dec!(6)))]);
+        consistent.verify_invariants()?;
 
+        let corrupted = [
+            // held funds without any dispute
+            Engine::from_parts([(1, account(dec!(5)))], []),
+            // a dispute that isn't held
+            Engine::from_parts([(1, account(dec!(0)))], [(1, disputed(1, dec!(10)))]),
+            // held funds left behind by a resolved dispute
+            Engine::from_parts(
+                [(1, account(dec!(10)))],
+                [(
+                    1,
+                    TransactionInfo {
+                        status: Status::None,
+                        ..disputed(1, dec!(10))
+                    },
+                )],
+            ),
+            // a dispute on a client without an account
+            Engine::from_parts([], [(1, disputed(1, dec!(10)))]),
+        ];
+        for engine in corrupted {
+            let err = engine.verify_invariants().unwrap_err();
+            assert_eq!(
+                err.error_type.kind(),
+                ErrorKind::InvariantViolation,
+                "{err}"
+            );
+        }
         Ok(())
     }
 
     #[test]
-    fn test_negative_balance() -> Result<()> {
+    fn void() -> Result<()> {
         let mut engine = Wrapper::new();
         let client = 1;
         let deposit = engine.deposit(client, 10)?;
-        engine.withdraw(client, 8)?;
-
-        engine.dispute(deposit)?;
-        assert!(engine.withdraw(client, 2).is_err()); // last of the funds, but they are held
-        assert_eq!(engine.account_info(client).available, dec!(-8));
-        assert_eq!(engine.account_info(client).held, dec!(10));
+        let withdrawal = engine.withdraw(client, 3)?;
+        engine
+            .engine
+            .handle(Transaction::new(client, Event::Void { tx: withdrawal.tx }))?;
+        assert_eq!(engine.account_info(client).available, dec!(10));
+        assert!(!engine.engine.contains_transaction(withdrawal.tx));
+        engine
+            .engine
+            .handle(Transaction::new(client, Event::Void { tx: deposit.tx }))?;
+        assert_eq!(engine.account_info(client).total, dec!(0));
 
-        // put some funds back
-        engine.deposit(client, 5)?;
-        engine.deposit(client, 7)?;
-        assert_eq!(engine.account_info(client).available, dec!(4));
-        assert_eq!(engine.account_info(client).held, dec!(10));
+        // the ids can be used again
+        engine.engine.handle(Transaction::new(
+            client,
+            Event::Deposit {
+                tx: deposit.tx,
+                amount: dec!(5),
+            },
+        ))?;
+        assert_eq!(engine.account_info(client).available, dec!(5));
+        assert_eq!(
+            engine
+                .engine
+                .handle(Transaction::new(2, Event::Void { tx: deposit.tx }))
+                .unwrap_err()
+                .error_type,
+            ErrorType::TransactionDoesNotMatchClient {
+                tx: deposit.tx,
+                client: 2,
+                owner: client
+            }
+        );
+        assert_eq!(
+            engine
+                .engine
+                .handle(Transaction::new(client, Event::Void { tx: withdrawal.tx }))
+                .unwrap_err()
+                .error_type,
+            ErrorType::UnknownTransaction { tx: withdrawal.tx }
+        );
 
-        engine.resolve(deposit)?;
-        assert_eq!(engine.account_info(client).available, dec!(14));
-        assert_eq!(engine.account_info(client).locked, false);
+        // voiding is undone by a rollback
+        engine
+            .engine
+            .handle(Transaction::new(client, Event::Void { tx: deposit.tx }))?;
+        engine.engine.rollback(1)?;
+        assert_eq!(engine.account_info(client).available, dec!(5));
         Ok(())
     }
 
     #[test]
-    fn locked_account() -> Result<()> {
+    fn void_disputed() -> Result<()> {
         let mut engine = Wrapper::new();
         let client = 1;
-        engine.deposit(client, 100)?;
-        let fraudulent = engine.deposit(client, 100)?;
-        let shady = engine.deposit(client, 100)?;
+        let disputed = engine.deposit(client, 10)?;
+        let reversed = engine.deposit(client, 5)?;
+        engine.dispute(disputed)?;
+        engine.dispute(reversed)?;
+        engine.chargeback(reversed)?;
+        for tx in [disputed.tx, reversed.tx] {
+            assert_eq!(
+                engine
+                    .engine
+                    .handle(Transaction::new(client, Event::Void { tx }))
+                    .unwrap_err()
+                    .error_type,
+                ErrorType::CannotVoidDisputed { tx }
+            );
+        }
+        let info = engine.account_info(client);
+        assert_eq!((info.available, info.held), (dec!(0), dec!(10)));
 
-        assert_eq!(engine.account_info(client).available, dec!(300));
-        assert_eq!(engine.account_info(client).locked, false);
+        // once resolved it's back to never disputed, and can be voided
+        engine.resolve(disputed)?;
+        engine
+            .engine
+            .handle(Transaction::new(client, Event::Void { tx: disputed.tx }))?;
+        assert_eq!(engine.account_info(client).total, dec!(0));
+        Ok(())
+    }
 
-        engine.withdraw(client, 50)?;
-        engine.dispute(fraudulent)?;
-        engine.chargeback(fraudulent)?;
+    #[test]
+    fn withdrawal_fee() -> Result<()> {
+        let mut engine = Wrapper::new();
+        engine.engine.set_withdrawal_fee(dec!(1.00))?;
+        engine.deposit(1, 11)?;
+        let withdrawal = engine.withdraw(1, 10)?;
+        assert_eq!(engine.account_info(1).available, dec!(0));
+        assert_eq!(engine.engine.total_fees_collected(), dec!(1));
 
-        // Still has funds available
-        assert_eq!(engine.account_info(client).available, dec!(150));
-        assert_eq!(engine.account_info(client).locked, true);
+        // the amount alone would fit, but not with the fee
+        engine.deposit(2, dec!(10.99))?;
+        let tx = engine.ids.peek().unwrap();
+        assert_eq!(
+            engine.withdraw(2, 10).unwrap_err().error_type,
+            ErrorType::InsufficientFunds { client: 2, tx }
+        );
+        assert_eq!(engine.engine.preview_withdrawal(2, dec!(9.99))?, dec!(0));
+        engine.deposit(2, dec!(0.01))?;
+        engine.withdraw(2, 10)?;
+        assert_eq!(engine.account_info(2).available, dec!(0));
+        assert_eq!(engine.engine.total_fees_collected(), dec!(2));
 
-        // Further deposits and/or withdrawals should fail
-        assert!(engine.withdraw(client, 10).is_err());
-        assert!(engine.deposit(client, 10000).is_err());
+        // a resolved dispute keeps the fee, a chargeback refunds it until it is reversed
+        engine.dispute(withdrawal)?;
+        engine.resolve(withdrawal)?;
+        assert_eq!(engine.account_info(1).available, dec!(0));
+        assert_eq!(engine.engine.total_fees_collected(), dec!(2));
+        engine.dispute(withdrawal)?;
+        let info = engine.account_info(1);
+        assert_eq!((info.available, info.held), (dec!(10), dec!(-10)));
+        engine.chargeback(withdrawal)?;
+        assert_eq!(engine.account_info(1).total, dec!(11));
+        assert_eq!(engine.engine.total_fees_collected(), dec!(1));
+        engine.reverse_chargeback(withdrawal)?;
+        assert_eq!(engine.account_info(1).total, dec!(0));
+        assert_eq!(engine.engine.total_fees_collected(), dec!(2));
 
-        // Other transactions can be disputed in the meantime
-        engine.dispute(shady)?;
         assert_eq!(
-            engine.account_info(client),
-            AccountInfo {
-                client,
-                available: dec!(50),
-                held: dec!(100),
-                total: dec!(150),
-                locked: true,
-            }
+            engine
+                .engine
+                .set_withdrawal_fee(dec!(-1))
+                .unwrap_err()
+                .error_type
+                .kind(),
+            ErrorKind::InvalidConfig
         );
+        assert_eq!(engine.engine.withdrawal_fee(), dec!(1));
         Ok(())
     }
 
     #[test]
-    fn globally_unique_transactions() {
-        let mut engine = Engine::new();
-        let client_a = 1;
-        let client_b = 2;
-        let tx = 1;
+    fn counts() -> Result<()> {
+        let mut engine = Wrapper::new();
+        assert_eq!(
+            (
+                engine.engine.account_count(),
+                engine.engine.transaction_count()
+            ),
+            (0, 0)
+        );
+        let deposit = engine.deposit(1, 10)?;
+        engine.deposit(2, 5)?;
+        engine.withdraw(1, 3)?;
+        engine.dispute(deposit)?;
+        engine.resolve(deposit)?;
+        assert_eq!(
+            (
+                engine.engine.account_count(),
+                engine.engine.transaction_count()
+            ),
+            (2, 3)
+        );
+
+        // rejected before anything is recorded
+        engine.deposit(3, -1).unwrap_err();
+        engine.withdraw(1, -1).unwrap_err();
+        engine.engine.set_reject_zero_amount(true);
+        engine.deposit(4, 0).unwrap_err();
         engine
-            .handle(Transaction {
-                client: client_a,
-                event: Event::Deposit {
-                    tx,
-                    amount: 10.into(),
+            .engine
+            .handle(Transaction::new(1, Event::Resolve { tx: 100 }))
+            .unwrap_err();
+        engine
+            .engine
+            .handle(Transaction::new(
+                1,
+                Event::Deposit {
+                    tx: deposit.tx,
+                    amount: dec!(1),
                 },
-            })
-            .unwrap();
+            ))
+            .unwrap_err();
+        assert_eq!(
+            (
+                engine.engine.account_count(),
+                engine.engine.transaction_count()
+            ),
+            (2, 3)
+        );
+        assert_eq!(
+            engine.engine.account_count(),
+            engine.engine.all_accounts().count()
+        );
+
+        // rejected, but its id is recorded
+        engine.withdraw(2, 100).unwrap_err();
+        assert_eq!(engine.engine.transaction_count(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn void_rejected() -> Result<()> {
+        let mut engine = Wrapper::new();
+        let client = 1;
+        engine.deposit(client, 10)?;
+        // rejected, but keeps its id
+        let tx = engine.ids.peek().unwrap();
+        engine.withdraw(client, 50).unwrap_err();
+        assert!(engine.engine.contains_transaction(tx));
         assert_eq!(
             engine
-                .handle(Transaction {
-                    client: client_b,
-                    event: Event::Deposit {
-                        tx,
-                        amount: 10.into()
-                    }
-                })
+                .engine
+                .handle(Transaction::new(client, Event::Void { tx }))
                 .unwrap_err()
                 .error_type,
-            ErrorType::ReusedTransactionId { tx }
+            ErrorType::TransactionNotApplied { tx }
+        );
+        assert_eq!(engine.account_info(client).available, dec!(10));
+
+        let charged_back = engine.deposit(client, 5)?;
+        engine.dispute(charged_back)?;
+        engine.chargeback(charged_back)?;
+        let tx = engine.ids.peek().unwrap();
+        assert_eq!(
+            engine.deposit(client, 50).unwrap_err().error_type,
+            ErrorType::LockedAccount { client }
+        );
+        assert_eq!(
+            engine
+                .engine
+                .handle(Transaction::new(client, Event::Void { tx }))
+                .unwrap_err()
+                .error_type,
+            ErrorType::TransactionNotApplied { tx }
         );
+        assert_eq!(engine.account_info(client).available, dec!(10));
+        Ok(())
     }
 }
@@ -1,30 +1,317 @@
 use std::fmt::{self, Display, Formatter};
 
-use crate::{ClientId, TransactionId};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, PartialEq, Eq)]
+use crate::{ClientId, Decimal, TransactionId};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ErrorType {
-    ReusedTransactionId { tx: TransactionId },
-    NegativeWithdrawal { tx: TransactionId },
-    NegativeDeposit { tx: TransactionId },
-    UnknownTransaction { tx: TransactionId },
-    LockedAccount { client: ClientId },
-    InsufficientFunds { client: ClientId, tx: TransactionId },
-    UnknownTransactionForDispute { tx: TransactionId },
-    TransactionDoesNotMatchClient { tx: TransactionId, client: ClientId },
-    TransactionAlreadyUnderDispute { tx: TransactionId },
-    TransactionNotUnderDispute { tx: TransactionId },
+    ReusedTransactionId {
+        tx: TransactionId,
+    },
+    NegativeWithdrawal {
+        tx: TransactionId,
+    },
+    NegativeDeposit {
+        tx: TransactionId,
+    },
+    UnknownTransaction {
+        tx: TransactionId,
+    },
+    LockedAccount {
+        client: ClientId,
+    },
+    InsufficientFunds {
+        client: ClientId,
+        tx: TransactionId,
+    },
+    UnknownTransactionForDispute {
+        tx: TransactionId,
+    },
+    /// `client` submitted the event, but the transaction was recorded for `owner`
+    TransactionDoesNotMatchClient {
+        tx: TransactionId,
+        client: ClientId,
+        owner: ClientId,
+    },
+    TransactionAlreadyUnderDispute {
+        tx: TransactionId,
+    },
+    TransactionNotUnderDispute {
+        tx: TransactionId,
+    },
+    ArithmeticOverflow {
+        tx: TransactionId,
+        client: ClientId,
+    },
+    InvalidJson {
+        message: String,
+    },
+    WithdrawalOutOfBounds {
+        tx: TransactionId,
+        amount: Decimal,
+    },
+    DisputeWindowExpired {
+        tx: TransactionId,
+    },
+    NegativeHold {
+        tx: TransactionId,
+    },
+    UnknownHold {
+        tx: TransactionId,
+    },
+    RollbackTooFar {
+        requested: usize,
+        available: usize,
+    },
+    InvalidSnapshot {
+        message: String,
+    },
+    TransactionNotFlagged {
+        tx: TransactionId,
+    },
+    ZeroAmount {
+        tx: TransactionId,
+    },
+    DisputeWouldOverdraw {
+        tx: TransactionId,
+        client: ClientId,
+    },
+    TooManyClients {
+        client: ClientId,
+    },
+    NegativePartialResolve {
+        tx: TransactionId,
+    },
+    PartialResolveTooLarge {
+        tx: TransactionId,
+    },
+    InexactAmount {
+        tx: TransactionId,
+        amount: Decimal,
+    },
+    AmountTooLarge {
+        tx: TransactionId,
+        amount: Decimal,
+    },
+    MalformedRow {
+        line: u64,
+        byte: u64,
+        error: String,
+    },
+    InvalidCsv {
+        message: String,
+    },
+    FieldTooLong {
+        line: u64,
+        len: usize,
+        max: usize,
+    },
+    TransactionNotChargedBack {
+        tx: TransactionId,
+    },
+    AccountAlreadyExists {
+        client: ClientId,
+    },
+    InvalidConfig {
+        message: String,
+    },
+    BatchFailed {
+        index: usize,
+        error: Box<ErrorType>,
+    },
+    TransactionIdsExhausted,
+    ClosedAccount {
+        client: ClientId,
+    },
+    CannotCloseWithOpenDisputes {
+        client: ClientId,
+    },
+    UnknownAccount {
+        client: ClientId,
+    },
+    TooManyOpenDisputes {
+        client: ClientId,
+    },
+    DailyLimitExceeded {
+        client: ClientId,
+    },
+    InvariantViolation {
+        message: String,
+    },
+    CannotVoidDisputed {
+        tx: TransactionId,
+    },
+    TransactionNotApplied {
+        tx: TransactionId,
+    },
+    MergeOverflow {
+        client: ClientId,
+    },
+    InterestOverflow {
+        client: ClientId,
+    },
+    /// The transaction is already flagged, or was charged back
+    CannotFlag {
+        tx: TransactionId,
+    },
+    /// The seeded available and held funds don't add up to a total
+    SeedOverflow {
+        client: ClientId,
+    },
+    /// A seeded balance has more decimal places than the engine's minor units
+    InexactSeed {
+        client: ClientId,
+        amount: Decimal,
+    },
+}
+
+/// The variant of an `ErrorType` without its data, used to configure how errors are treated.
+///
+/// Serialized as the variant's name, e.g. in error reports next to the `Display` of the error itself, and read
+/// the same way from the `[severities]` table of the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum ErrorKind {
+    ReusedTransactionId,
+    NegativeWithdrawal,
+    NegativeDeposit,
+    UnknownTransaction,
+    LockedAccount,
+    InsufficientFunds,
+    UnknownTransactionForDispute,
+    TransactionDoesNotMatchClient,
+    TransactionAlreadyUnderDispute,
+    TransactionNotUnderDispute,
+    ArithmeticOverflow,
+    InvalidJson,
+    WithdrawalOutOfBounds,
+    DisputeWindowExpired,
+    NegativeHold,
+    UnknownHold,
+    RollbackTooFar,
+    InvalidSnapshot,
+    TransactionNotFlagged,
+    ZeroAmount,
+    DisputeWouldOverdraw,
+    TooManyClients,
+    NegativePartialResolve,
+    PartialResolveTooLarge,
+    InexactAmount,
+    AmountTooLarge,
+    MalformedRow,
+    InvalidCsv,
+    FieldTooLong,
+    TransactionNotChargedBack,
+    AccountAlreadyExists,
+    InvalidConfig,
+    BatchFailed,
+    TransactionIdsExhausted,
+    ClosedAccount,
+    CannotCloseWithOpenDisputes,
+    UnknownAccount,
+    TooManyOpenDisputes,
+    DailyLimitExceeded,
+    InvariantViolation,
+    CannotVoidDisputed,
+    TransactionNotApplied,
+    MergeOverflow,
+    InterestOverflow,
+    CannotFlag,
+    SeedOverflow,
+    InexactSeed,
+}
+
+impl ErrorType {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ErrorType::ReusedTransactionId { .. } => ErrorKind::ReusedTransactionId,
+            ErrorType::NegativeWithdrawal { .. } => ErrorKind::NegativeWithdrawal,
+            ErrorType::NegativeDeposit { .. } => ErrorKind::NegativeDeposit,
+            ErrorType::UnknownTransaction { .. } => ErrorKind::UnknownTransaction,
+            ErrorType::LockedAccount { .. } => ErrorKind::LockedAccount,
+            ErrorType::InsufficientFunds { .. } => ErrorKind::InsufficientFunds,
+            ErrorType::UnknownTransactionForDispute { .. } => {
+                ErrorKind::UnknownTransactionForDispute
+            }
+            ErrorType::TransactionDoesNotMatchClient { .. } => {
+                ErrorKind::TransactionDoesNotMatchClient
+            }
+            ErrorType::TransactionAlreadyUnderDispute { .. } => {
+                ErrorKind::TransactionAlreadyUnderDispute
+            }
+            ErrorType::TransactionNotUnderDispute { .. } => ErrorKind::TransactionNotUnderDispute,
+            ErrorType::ArithmeticOverflow { .. } => ErrorKind::ArithmeticOverflow,
+            ErrorType::InvalidJson { .. } => ErrorKind::InvalidJson,
+            ErrorType::WithdrawalOutOfBounds { .. } => ErrorKind::WithdrawalOutOfBounds,
+            ErrorType::DisputeWindowExpired { .. } => ErrorKind::DisputeWindowExpired,
+            ErrorType::NegativeHold { .. } => ErrorKind::NegativeHold,
+            ErrorType::UnknownHold { .. } => ErrorKind::UnknownHold,
+            ErrorType::RollbackTooFar { .. } => ErrorKind::RollbackTooFar,
+            ErrorType::InvalidSnapshot { .. } => ErrorKind::InvalidSnapshot,
+            ErrorType::TransactionNotFlagged { .. } => ErrorKind::TransactionNotFlagged,
+            ErrorType::ZeroAmount { .. } => ErrorKind::ZeroAmount,
+            ErrorType::DisputeWouldOverdraw { .. } => ErrorKind::DisputeWouldOverdraw,
+            ErrorType::TooManyClients { .. } => ErrorKind::TooManyClients,
+            ErrorType::NegativePartialResolve { .. } => ErrorKind::NegativePartialResolve,
+            ErrorType::PartialResolveTooLarge { .. } => ErrorKind::PartialResolveTooLarge,
+            ErrorType::InexactAmount { .. } => ErrorKind::InexactAmount,
+            ErrorType::AmountTooLarge { .. } => ErrorKind::AmountTooLarge,
+            ErrorType::MalformedRow { .. } => ErrorKind::MalformedRow,
+            ErrorType::InvalidCsv { .. } => ErrorKind::InvalidCsv,
+            ErrorType::FieldTooLong { .. } => ErrorKind::FieldTooLong,
+            ErrorType::TransactionNotChargedBack { .. } => ErrorKind::TransactionNotChargedBack,
+            ErrorType::AccountAlreadyExists { .. } => ErrorKind::AccountAlreadyExists,
+            ErrorType::InvalidConfig { .. } => ErrorKind::InvalidConfig,
+            ErrorType::BatchFailed { .. } => ErrorKind::BatchFailed,
+            ErrorType::TransactionIdsExhausted => ErrorKind::TransactionIdsExhausted,
+            ErrorType::ClosedAccount { .. } => ErrorKind::ClosedAccount,
+            ErrorType::CannotCloseWithOpenDisputes { .. } => ErrorKind::CannotCloseWithOpenDisputes,
+            ErrorType::UnknownAccount { .. } => ErrorKind::UnknownAccount,
+            ErrorType::TooManyOpenDisputes { .. } => ErrorKind::TooManyOpenDisputes,
+            ErrorType::DailyLimitExceeded { .. } => ErrorKind::DailyLimitExceeded,
+            ErrorType::InvariantViolation { .. } => ErrorKind::InvariantViolation,
+            ErrorType::CannotVoidDisputed { .. } => ErrorKind::CannotVoidDisputed,
+            ErrorType::TransactionNotApplied { .. } => ErrorKind::TransactionNotApplied,
+            ErrorType::MergeOverflow { .. } => ErrorKind::MergeOverflow,
+            ErrorType::InterestOverflow { .. } => ErrorKind::InterestOverflow,
+            ErrorType::CannotFlag { .. } => ErrorKind::CannotFlag,
+            ErrorType::SeedOverflow { .. } => ErrorKind::SeedOverflow,
+            ErrorType::InexactSeed { .. } => ErrorKind::InexactSeed,
+        }
+    }
+}
+
+/// How loudly an error should be reported, configured per `ErrorKind` on the engine
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Warning,
+    #[default]
+    Error,
+}
+
+impl Severity {
+    pub fn level(self) -> log::Level {
+        match self {
+            Severity::Warning => log::Level::Warn,
+            Severity::Error => log::Level::Error,
+        }
+    }
 }
 
 // wrapping error type to leave space for other (optional) data, such as backtrace
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Error {
     pub error_type: ErrorType,
+    pub severity: Severity,
 }
 
 impl From<ErrorType> for Error {
     fn from(error_type: ErrorType) -> Self {
-        Self { error_type }
+        Self {
+            error_type,
+            severity: Severity::default(),
+        }
     }
 }
 
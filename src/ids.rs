@@ -0,0 +1,266 @@
+//! Newtype wrappers around `ClientId` and `TransactionId` so the two can't be swapped by accident.
+//!
+//! The plain type aliases stay the default, these only add typed constructors (one for every kind of event) and
+//! accessors on top of them. `Engine::handle` takes the typed transactions as they are, the engine's queries
+//! still take the plain ids, which the newtypes convert to with `into()`.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ClientId, Decimal, Event, Transaction, TransactionId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TypedClientId(pub ClientId);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TypedTransactionId(pub TransactionId);
+
+impl From<ClientId> for TypedClientId {
+    fn from(client: ClientId) -> Self {
+        Self(client)
+    }
+}
+
+impl From<TypedClientId> for ClientId {
+    fn from(client: TypedClientId) -> Self {
+        client.0
+    }
+}
+
+impl From<TransactionId> for TypedTransactionId {
+    fn from(tx: TransactionId) -> Self {
+        Self(tx)
+    }
+}
+
+impl From<TypedTransactionId> for TransactionId {
+    fn from(tx: TypedTransactionId) -> Self {
+        tx.0
+    }
+}
+
+impl Transaction {
+    pub fn deposit(client: TypedClientId, tx: TypedTransactionId, amount: Decimal) -> Self {
+        Self::new(
+            client.into(),
+            Event::Deposit {
+                tx: tx.into(),
+                amount,
+            },
+        )
+    }
+
+    pub fn withdrawal(client: TypedClientId, tx: TypedTransactionId, amount: Decimal) -> Self {
+        Self::new(
+            client.into(),
+            Event::Withdrawal {
+                tx: tx.into(),
+                amount,
+            },
+        )
+    }
+
+    pub fn dispute(client: TypedClientId, tx: TypedTransactionId) -> Self {
+        Self::new(
+            client.into(),
+            Event::Dispute {
+                tx: tx.into(),
+                reason: None,
+            },
+        )
+    }
+
+    pub fn resolve(client: TypedClientId, tx: TypedTransactionId) -> Self {
+        Self::new(client.into(), Event::Resolve { tx: tx.into() })
+    }
+
+    pub fn chargeback(client: TypedClientId, tx: TypedTransactionId) -> Self {
+        Self::new(client.into(), Event::Chargeback { tx: tx.into() })
+    }
+
+    pub fn adjustment(
+        client: TypedClientId,
+        tx: TypedTransactionId,
+        amount: Decimal,
+        reason: String,
+    ) -> Self {
+        Self::new(
+            client.into(),
+            Event::Adjustment {
+                tx: tx.into(),
+                amount,
+                reason,
+            },
+        )
+    }
+
+    pub fn flag(client: TypedClientId, tx: TypedTransactionId) -> Self {
+        Self::new(client.into(), Event::Flag { tx: tx.into() })
+    }
+
+    pub fn partial_resolve(client: TypedClientId, tx: TypedTransactionId, amount: Decimal) -> Self {
+        Self::new(
+            client.into(),
+            Event::PartialResolve {
+                tx: tx.into(),
+                amount,
+            },
+        )
+    }
+
+    pub fn reverse_chargeback(client: TypedClientId, tx: TypedTransactionId) -> Self {
+        Self::new(client.into(), Event::ReverseChargeback { tx: tx.into() })
+    }
+
+    pub fn hold(client: TypedClientId, tx: TypedTransactionId, amount: Decimal) -> Self {
+        Self::new(
+            client.into(),
+            Event::Hold {
+                tx: tx.into(),
+                amount,
+            },
+        )
+    }
+
+    pub fn release_hold(client: TypedClientId, tx: TypedTransactionId) -> Self {
+        Self::new(client.into(), Event::ReleaseHold { tx: tx.into() })
+    }
+
+    pub fn void(client: TypedClientId, tx: TypedTransactionId) -> Self {
+        Self::new(client.into(), Event::Void { tx: tx.into() })
+    }
+
+    pub fn typed_client(&self) -> TypedClientId {
+        self.client.into()
+    }
+
+    pub fn typed_tx(&self) -> TypedTransactionId {
+        self.event.tx().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use csv::{ReaderBuilder, Writer};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Plain {
+        client: ClientId,
+        tx: TransactionId,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Typed {
+        client: TypedClientId,
+        tx: TypedTransactionId,
+    }
+
+    fn to_csv<T: Serialize>(row: T) -> String {
+        let mut writer = Writer::from_writer(vec![]);
+        writer.serialize(row).unwrap();
+        String::from_utf8(writer.into_inner().unwrap()).unwrap()
+    }
+
+    fn from_csv<T: for<'de> Deserialize<'de>>(data: &str) -> T {
+        ReaderBuilder::new()
+            .from_reader(data.as_bytes())
+            .deserialize()
+            .next()
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn same_representation() {
+        let plain = to_csv(Plain {
+            client: 7,
+            tx: 70000,
+        });
+        let typed = to_csv(Typed {
+            client: 7.into(),
+            tx: 70000.into(),
+        });
+        assert_eq!(plain, "client,tx\n7,70000\n");
+        assert_eq!(plain, typed);
+
+        assert_eq!(
+            from_csv::<Typed>(&plain),
+            Typed {
+                client: TypedClientId(7),
+                tx: TypedTransactionId(70000),
+            }
+        );
+        assert_eq!(
+            from_csv::<Plain>(&typed),
+            Plain {
+                client: 7,
+                tx: 70000
+            }
+        );
+    }
+
+    #[test]
+    fn typed_constructors() {
+        let transaction = Transaction::deposit(TypedClientId(1), TypedTransactionId(2), 3.into());
+        assert_eq!(transaction.client, 1);
+        assert!(matches!(
+            transaction.event,
+            Event::Deposit { tx: 2, amount } if amount == Decimal::from(3)
+        ));
+        assert_eq!(transaction.typed_client(), TypedClientId(1));
+        assert_eq!(transaction.typed_tx(), TypedTransactionId(2));
+
+        let (client, tx) = (TypedClientId(1), TypedTransactionId(2));
+        let amount = Decimal::from(3);
+        let reason = "correction".to_string();
+        assert!(matches!(
+            Transaction::adjustment(client, tx, amount, reason).event,
+            Event::Adjustment { tx: 2, .. }
+        ));
+        assert!(matches!(
+            Transaction::flag(client, tx).event,
+            Event::Flag { tx: 2 }
+        ));
+        assert!(matches!(
+            Transaction::partial_resolve(client, tx, amount).event,
+            Event::PartialResolve { tx: 2, .. }
+        ));
+        assert!(matches!(
+            Transaction::reverse_chargeback(client, tx).event,
+            Event::ReverseChargeback { tx: 2 }
+        ));
+        assert!(matches!(
+            Transaction::hold(client, tx, amount).event,
+            Event::Hold { tx: 2, .. }
+        ));
+        assert!(matches!(
+            Transaction::release_hold(client, tx).event,
+            Event::ReleaseHold { tx: 2 }
+        ));
+        assert!(matches!(
+            Transaction::void(client, tx).event,
+            Event::Void { tx: 2 }
+        ));
+    }
+
+    #[test]
+    fn handled_by_the_engine() {
+        let (client, tx) = (TypedClientId(1), TypedTransactionId(2));
+        let mut engine = crate::Engine::new();
+        for transaction in [
+            Transaction::deposit(client, tx, 10.into()),
+            Transaction::dispute(client, tx),
+            Transaction::partial_resolve(client, tx, 4.into()),
+            Transaction::chargeback(client, tx),
+            Transaction::reverse_chargeback(client, tx),
+        ] {
+            engine.handle(transaction).unwrap();
+        }
+        let info = engine.account_info(client.into());
+        assert_eq!(info.available, Decimal::from(10));
+        assert!(!info.locked);
+    }
+}
@@ -1,41 +1,995 @@
 pub use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
+use rust_decimal::RoundingStrategy;
+use std::{
+    collections::HashMap,
+    io::{self, Read},
+    iter,
+};
 
+use csv::{ByteRecord, Reader, ReaderBuilder, Trim};
+use errors::{Error, ErrorType};
+use serde::{ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
+
+pub mod allocator;
+pub mod amount;
+mod balance;
+pub mod config;
+pub mod currency;
 pub mod engine;
 pub mod errors;
-pub use engine::Engine;
+#[cfg(feature = "typed-ids")]
+pub mod ids;
+pub mod observer;
+pub mod signed_amount;
+pub mod store;
+#[cfg(feature = "async")]
+pub mod stream;
+pub use allocator::TransactionIdAllocator;
+pub use config::EngineConfig;
+pub use currency::Currency;
+pub use engine::{Applied, DisputeView, Engine, Fnv1a, HeldFunds, TransactionView};
+pub use observer::EngineObserver;
+pub use signed_amount::SignedAmount;
+pub use store::TransactionStore;
 
-pub type ClientId = u16;
+pub type ClientId = u32;
 pub type TransactionId = u32;
+/// Seconds since the unix epoch
+pub type Timestamp = i64;
 pub type Result<T> = std::result::Result<T, errors::Error>;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum Event {
     #[serde(rename = "deposit")]
-    Deposit { tx: TransactionId, amount: Decimal },
+    Deposit {
+        tx: TransactionId,
+        #[serde(deserialize_with = "amount::deserialize")]
+        amount: Decimal,
+    },
     #[serde(rename = "withdrawal")]
-    Withdrawal { tx: TransactionId, amount: Decimal },
+    Withdrawal {
+        tx: TransactionId,
+        #[serde(deserialize_with = "amount::deserialize")]
+        amount: Decimal,
+    },
+    /// Administrative credit (positive `amount`) or debit (negative `amount`), which skips the funds check
+    #[serde(rename = "adjustment")]
+    Adjustment {
+        tx: TransactionId,
+        #[serde(deserialize_with = "amount::deserialize")]
+        amount: Decimal,
+        reason: String,
+    },
+    /// Marks a transaction for review before it is disputed, without holding any funds
+    #[serde(rename = "flag")]
+    Flag { tx: TransactionId },
+    /// `reason` is the code the payment network gave for the dispute, when there is one
     #[serde(rename = "dispute")]
-    Dispute { tx: TransactionId },
+    Dispute {
+        tx: TransactionId,
+        #[serde(default, deserialize_with = "non_empty")]
+        reason: Option<String>,
+    },
     #[serde(rename = "resolve")]
     Resolve { tx: TransactionId },
+    /// Releases `amount` of a disputed transaction's held funds, the dispute ends once nothing is held anymore
+    #[serde(rename = "partial_resolve")]
+    PartialResolve {
+        tx: TransactionId,
+        #[serde(deserialize_with = "amount::deserialize")]
+        amount: Decimal,
+    },
     #[serde(rename = "chargeback")]
     Chargeback { tx: TransactionId },
+    /// Undoes a chargeback, e.g. when the bank wins the representment, after which the transaction can be
+    /// disputed and charged back again. Only the charged back amount is returned, the chargeback fee is kept.
+    #[serde(rename = "reverse_chargeback")]
+    ReverseChargeback { tx: TransactionId },
+    /// Administrative hold on available funds, `tx` identifies the hold rather than a deposit or withdrawal.
+    /// Fails with `InsufficientFunds` if the client has less than `amount` available.
+    #[serde(rename = "hold")]
+    Hold {
+        tx: TransactionId,
+        #[serde(deserialize_with = "amount::deserialize")]
+        amount: Decimal,
+    },
+    #[serde(rename = "release")]
+    ReleaseHold { tx: TransactionId },
+    /// Removes a deposit, withdrawal or adjustment recorded in error as if it never happened, as long as it isn't
+    /// flagged, disputed or charged back, after which its id can be used again. Rejected transactions keep their id
+    /// but can't be voided, they never changed the balance.
+    #[serde(rename = "void")]
+    Void { tx: TransactionId },
 }
 
-#[derive(Debug, Deserialize)]
+impl Event {
+    pub fn tx(&self) -> TransactionId {
+        match *self {
+            Event::Deposit { tx, .. }
+            | Event::Withdrawal { tx, .. }
+            | Event::Adjustment { tx, .. }
+            | Event::Flag { tx }
+            | Event::Dispute { tx, .. }
+            | Event::Resolve { tx }
+            | Event::PartialResolve { tx, .. }
+            | Event::Chargeback { tx }
+            | Event::ReverseChargeback { tx }
+            | Event::Hold { tx, .. }
+            | Event::ReleaseHold { tx }
+            | Event::Void { tx } => tx,
+        }
+    }
+
+    /// The transaction id and amount of events that carry an amount
+    pub fn amount(&self) -> Option<(TransactionId, Decimal)> {
+        match *self {
+            Event::Deposit { tx, amount }
+            | Event::Withdrawal { tx, amount }
+            | Event::Adjustment { tx, amount, .. }
+            | Event::PartialResolve { tx, amount }
+            | Event::Hold { tx, amount } => Some((tx, amount)),
+            Event::Flag { .. }
+            | Event::Dispute { .. }
+            | Event::Resolve { .. }
+            | Event::Chargeback { .. }
+            | Event::ReverseChargeback { .. }
+            | Event::ReleaseHold { .. }
+            | Event::Void { .. } => None,
+        }
+    }
+}
+
+/// One row of the input, columns are matched by header name so they can come in any order.
+///
+/// Recognized columns are `type`, `client`, `tx`, `amount`, `reason` (for adjustments and disputes) and the
+/// optional `currency` and `timestamp`, any other column is ignored.
+#[derive(Debug, Clone, Deserialize)]
 pub struct Transaction {
     pub client: ClientId,
     #[serde(flatten)]
     pub event: Event,
+    /// Only used when this transaction creates the client's account
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    /// When the transaction happened upstream, kept as the account's last activity
+    #[serde(default)]
+    pub timestamp: Option<Timestamp>,
+}
+
+impl Transaction {
+    pub fn new(client: ClientId, event: Event) -> Self {
+        Self {
+            client,
+            event,
+            currency: None,
+            timestamp: None,
+        }
+    }
+}
+
+/// Written as a flat row with the same columns it is read from, `amount` and `reason` are left out for events
+/// without them and `currency` or `timestamp` when they aren't set, so a `csv` writer has to be `flexible` to write mixed events.
+///
+/// This can't be derived because of the flattened `event`, which would be written as a map that the `csv` crate
+/// doesn't support.
+impl Serialize for Transaction {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let (kind, tx, amount, reason) = match self.event {
+            Event::Deposit { tx, amount } => ("deposit", tx, Some(amount), None),
+            Event::Withdrawal { tx, amount } => ("withdrawal", tx, Some(amount), None),
+            Event::Adjustment {
+                tx,
+                amount,
+                ref reason,
+            } => ("adjustment", tx, Some(amount), Some(reason)),
+            Event::Flag { tx } => ("flag", tx, None, None),
+            Event::Dispute { tx, ref reason } => ("dispute", tx, None, reason.as_ref()),
+            Event::Resolve { tx } => ("resolve", tx, None, None),
+            Event::PartialResolve { tx, amount } => ("partial_resolve", tx, Some(amount), None),
+            Event::Chargeback { tx } => ("chargeback", tx, None, None),
+            Event::ReverseChargeback { tx } => ("reverse_chargeback", tx, None, None),
+            Event::Hold { tx, amount } => ("hold", tx, Some(amount), None),
+            Event::ReleaseHold { tx } => ("release", tx, None, None),
+            Event::Void { tx } => ("void", tx, None, None),
+        };
+        let len = 3
+            + usize::from(amount.is_some())
+            + usize::from(reason.is_some())
+            + usize::from(self.currency.is_some())
+            + usize::from(self.timestamp.is_some());
+        let mut row = serializer.serialize_struct("Transaction", len)?;
+        row.serialize_field("type", kind)?;
+        row.serialize_field("client", &self.client)?;
+        row.serialize_field("tx", &tx)?;
+        match amount {
+            Some(amount) => row.serialize_field("amount", &amount)?,
+            None => row.skip_field("amount")?,
+        }
+        match reason {
+            Some(reason) => row.serialize_field("reason", reason)?,
+            None => row.skip_field("reason")?,
+        }
+        match &self.currency {
+            Some(currency) => row.serialize_field("currency", currency)?,
+            None => row.skip_field("currency")?,
+        }
+        match &self.timestamp {
+            Some(timestamp) => row.serialize_field("timestamp", timestamp)?,
+            None => row.skip_field("timestamp")?,
+        }
+        row.end()
+    }
+}
+
+/// Column order of input without a header row
+pub const POSITIONAL_COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// How `parse_transactions_with` reads its input
+#[derive(Debug, Clone)]
+pub struct ParseOptions {
+    /// The input has no header row, columns are read in the order of `POSITIONAL_COLUMNS`
+    pub no_headers: bool,
+    /// Fail with `FieldTooLong` on longer fields, so we don't even try to parse a megabyte long amount
+    pub max_field_len: Option<usize>,
+    /// Field delimiter, `,` by default, European exports often use `;`
+    pub delimiter: u8,
+    /// Size in bytes of the buffer the input is read through (`csv`'s default of 8 KiB if not set), must not be 0.
+    ///
+    /// The reader does its own buffering, so the input doesn't need to be wrapped in a `BufReader`.
+    pub buffer_capacity: Option<usize>,
+    /// Whether `"` quotes fields, input that doesn't quote them can then contain it literally
+    pub quoting: bool,
+    /// Fields of exactly this text are read as if they were empty, e.g. `\N` in Postgres `COPY` output
+    pub null: Option<String>,
+    /// Separator allowed between groups of three digits in amounts, like `,` in `1,234.56` (none by default).
+    ///
+    /// Anything ambiguous, like `1,23.4`, is rejected with `MalformedRow`, see `amount::parse`.
+    pub thousands_separator: Option<char>,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            no_headers: false,
+            max_field_len: None,
+            delimiter: b',',
+            buffer_capacity: None,
+            quoting: true,
+            null: None,
+            thousands_separator: None,
+        }
+    }
+}
+
+impl ParseOptions {
+    /// The text format of Postgres' `COPY ... TO STDOUT`: tab delimited, unquoted and with `\N` for nulls.
+    ///
+    /// `COPY` only writes a header row with the `HEADER` option, set `no_headers` if it's left out.
+    pub fn postgres_copy() -> Self {
+        Self {
+            delimiter: b'\t',
+            quoting: false,
+            null: Some("\\N".to_owned()),
+            ..Self::default()
+        }
+    }
+}
+
+/// Reads transactions from CSV input with a header row, see `parse_transactions_with`
+pub fn parse_transactions<R: Read>(reader: R) -> impl Iterator<Item = Result<Transaction>> {
+    parse_transactions_with(reader, ParseOptions::default())
+}
+
+/// Reads one transaction per CSV row, whitespace around fields and a UTF-8 byte order mark are ignored, and the
+/// `type` is matched case-insensitively.
+///
+/// Rows that can't be parsed are yielded as `MalformedRow` and reading goes on with the next one. Any other error
+/// (e.g. failing to read the input) is yielded as the last item.
+pub fn parse_transactions_with<R: Read>(
+    reader: R,
+    options: ParseOptions,
+) -> impl Iterator<Item = Result<Transaction>> {
+    let (reader, read_bom) = skip_bom(reader);
+    let mut builder = ReaderBuilder::new();
+    if let Some(capacity) = options.buffer_capacity {
+        builder.buffer_capacity(capacity);
+    }
+    let mut reader = builder
+        .trim(Trim::All)
+        .delimiter(options.delimiter)
+        .quoting(options.quoting)
+        .has_headers(!options.no_headers)
+        .flexible(options.no_headers)
+        .from_reader(reader);
+    let headers = match read_bom {
+        Err(err) => Err(invalid_csv(err.into())),
+        Ok(()) if options.no_headers => Ok(ByteRecord::from(POSITIONAL_COLUMNS.to_vec())),
+        Ok(()) => reader.byte_headers().cloned().map_err(invalid_csv),
+    };
+    let mut record = ByteRecord::new();
+    let mut finished = false;
+    iter::from_fn(move || {
+        if finished {
+            return None;
+        }
+        let next = next_transaction(&mut reader, &headers, &mut record, &options).transpose();
+        finished = match &next {
+            Some(Err(err)) => !matches!(err.error_type, ErrorType::MalformedRow { .. }),
+            Some(Ok(_)) => false,
+            None => true,
+        };
+        next
+    })
 }
 
-#[derive(Debug, Serialize, PartialEq, Eq)]
+/// `csv` strips a byte order mark itself, but only one that arrives with its first read of the input, which a
+/// small enough buffer splits up (or even mistakes for the end of the input)
+fn skip_bom<R: Read>(mut reader: R) -> (impl Read, io::Result<()>) {
+    const BOM: &[u8] = b"\xEF\xBB\xBF";
+    let mut start = Vec::with_capacity(BOM.len());
+    let read = (&mut reader)
+        .take(BOM.len() as u64)
+        .read_to_end(&mut start)
+        .map(drop);
+    if start == BOM {
+        start.clear();
+    }
+    (io::Cursor::new(start).chain(reader), read)
+}
+
+fn next_transaction<R: Read>(
+    reader: &mut Reader<R>,
+    headers: &Result<ByteRecord>,
+    record: &mut ByteRecord,
+    options: &ParseOptions,
+) -> Result<Option<Transaction>> {
+    let headers = headers.as_ref().map_err(Clone::clone)?;
+    match reader.read_byte_record(record) {
+        Ok(true) => {}
+        Ok(false) => return Ok(None),
+        Err(err) if matches!(err.kind(), csv::ErrorKind::UnequalLengths { .. }) => {
+            return Err(malformed_row(&err))
+        }
+        Err(err) => return Err(invalid_csv(err)),
+    }
+    // checked on the raw fields, before any of them is rewritten below
+    let line = record.position().map_or(0, |position| position.line());
+    if let Some(max) = options.max_field_len {
+        if let Some(field) = record.iter().find(|field| field.len() > max) {
+            return Err(ErrorType::FieldTooLong {
+                line,
+                len: field.len(),
+                max,
+            }
+            .into());
+        }
+    }
+    // some feeds write the type as `Deposit` or `DEPOSIT`, which serde's tags wouldn't match
+    let type_column = headers.iter().position(|header| header == b"type");
+    let is_null = |field: &[u8]| {
+        options
+            .null
+            .as_deref()
+            .is_some_and(|null| field == null.as_bytes())
+    };
+    let is_uppercase_type = |column, field: &[u8]| {
+        Some(column) == type_column && field.iter().any(u8::is_ascii_uppercase)
+    };
+    // `amount::deserialize` only takes plain numbers, so separated amounts are parsed here
+    let amount_column = headers.iter().position(|header| header == b"amount");
+    let separator = options.thousands_separator;
+    let is_separated_amount = |column, field: &[u8]| {
+        Some(column) == amount_column
+            && separator.is_some_and(|separator| String::from_utf8_lossy(field).contains(separator))
+    };
+    if record.iter().enumerate().any(|(column, field)| {
+        is_null(field) || is_uppercase_type(column, field) || is_separated_amount(column, field)
+    }) {
+        let mut fields = ByteRecord::with_capacity(record.as_slice().len(), record.len());
+        for (column, field) in record.iter().enumerate() {
+            if is_null(field) {
+                fields.push_field(b"");
+            } else if Some(column) == type_column {
+                fields.push_field(&field.to_ascii_lowercase());
+            } else if is_separated_amount(column, field) {
+                let (line, byte) = record
+                    .position()
+                    .map_or((0, 0), |position| (position.line(), position.byte()));
+                let amount = amount::parse(String::from_utf8_lossy(field).trim(), separator)
+                    .map_err(|error| ErrorType::MalformedRow { line, byte, error })?;
+                fields.push_field(amount.to_string().as_bytes());
+            } else {
+                fields.push_field(field);
+            }
+        }
+        fields.set_position(record.position().cloned());
+        *record = fields;
+    }
+    if options.no_headers {
+        // disputes, resolves and chargebacks usually leave out the amount entirely
+        while record.len() < headers.len() {
+            record.push_field(b"");
+        }
+    }
+    record.deserialize(Some(headers)).map(Some).map_err(|err| {
+        // numbers too large for serde's buffering fail before reaching `amount::deserialize`, so the generic
+        // message is replaced by the one naming the amount
+        match amount_error(headers, record) {
+            Some(error) => {
+                let byte = record.position().map_or(0, |position| position.byte());
+                ErrorType::MalformedRow { line, byte, error }.into()
+            }
+            None => malformed_row(&err),
+        }
+    })
+}
+
+/// Why the `amount` field of `record` can't be parsed, if it has a non-empty one that can't
+fn amount_error(headers: &ByteRecord, record: &ByteRecord) -> Option<String> {
+    let column = headers.iter().position(|header| header == b"amount")?;
+    let field = String::from_utf8_lossy(record.get(column)?);
+    let field = field.trim();
+    if field.is_empty() {
+        return None;
+    }
+    amount::parse(field, None).err()
+}
+
+fn malformed_row(err: &csv::Error) -> Error {
+    let (line, byte) = err
+        .position()
+        .map_or((0, 0), |position| (position.line(), position.byte()));
+    ErrorType::MalformedRow {
+        line,
+        byte,
+        error: err.to_string(),
+    }
+    .into()
+}
+
+fn invalid_csv(err: csv::Error) -> Error {
+    ErrorType::InvalidCsv {
+        message: err.to_string(),
+    }
+    .into()
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
 pub struct AccountInfo {
     pub client: ClientId,
+    #[serde(serialize_with = "plain_decimal")]
     pub available: Decimal,
+    #[serde(serialize_with = "plain_decimal")]
     pub held: Decimal,
+    #[serde(serialize_with = "plain_decimal")]
     pub total: Decimal,
     pub locked: bool,
+    pub currency: Option<Currency>,
+    /// Closed with `Engine::close_account`
+    pub closed: bool,
+}
+
+/// How amounts are rounded to the output scale
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoundingMode {
+    /// Banker's rounding, midpoints go to the even neighbour
+    #[default]
+    HalfEven,
+    /// Midpoints go away from zero
+    HalfUp,
+    Floor,
+    Ceil,
+    Truncate,
+}
+
+impl From<RoundingMode> for RoundingStrategy {
+    fn from(mode: RoundingMode) -> Self {
+        match mode {
+            RoundingMode::HalfEven => RoundingStrategy::MidpointNearestEven,
+            RoundingMode::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            RoundingMode::Floor => RoundingStrategy::ToNegativeInfinity,
+            RoundingMode::Ceil => RoundingStrategy::ToPositiveInfinity,
+            RoundingMode::Truncate => RoundingStrategy::ToZero,
+        }
+    }
+}
+
+impl AccountInfo {
+    /// Returns a copy with every amount rounded to `scale` decimal places, meant for display only.
+    ///
+    /// `total` is rounded from the precise value rather than summed from the rounded parts.
+    pub fn display_rounded(&self, scale: u32) -> AccountInfo {
+        self.display_rounded_with(scale, RoundingMode::default())
+    }
+
+    /// Same as `display_rounded`, with the given rounding instead of banker's rounding
+    pub fn display_rounded_with(&self, scale: u32, mode: RoundingMode) -> AccountInfo {
+        let round =
+            |amount: Decimal| normalize_zero(amount.round_dp_with_strategy(scale, mode.into()));
+        AccountInfo {
+            available: round(self.available),
+            held: round(self.held),
+            total: round(self.total),
+            ..self.clone()
+        }
+    }
+
+    /// How `other` differs from this account, or `None` if the balances, lock and closing are the same.
+    ///
+    /// The client and currency are not compared. A delta too large for a `Decimal` saturates at `Decimal::MAX`
+    /// (or `Decimal::MIN`).
+    pub fn diff(&self, other: &AccountInfo) -> Option<AccountDiff> {
+        let diff = AccountDiff {
+            available: other.available.saturating_sub(self.available),
+            held: other.held.saturating_sub(self.held),
+            total: other.total.saturating_sub(self.total),
+            locked: (self.locked != other.locked).then_some(other.locked),
+            closed: (self.closed != other.closed).then_some(other.closed),
+        };
+        (diff != AccountDiff::default()).then_some(diff)
+    }
+}
+
+/// Per field deltas between two accounts, each computed as `other - self`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AccountDiff {
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    /// The other account's lock, only when it differs
+    pub locked: Option<bool>,
+    /// Whether the other account is closed, only when it differs
+    pub closed: Option<bool>,
+}
+
+/// Differences between two sets of accounts, everything is ordered by client id
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct AccountsDiff {
+    pub changed: Vec<(ClientId, AccountDiff)>,
+    pub only_left: Vec<ClientId>,
+    pub only_right: Vec<ClientId>,
+}
+
+/// Compares two sets of accounts by client, e.g. the engine's output against an external ledger
+pub fn diff_accounts(
+    left: impl IntoIterator<Item = AccountInfo>,
+    right: impl IntoIterator<Item = AccountInfo>,
+) -> AccountsDiff {
+    let mut right: HashMap<_, _> = right.into_iter().map(|info| (info.client, info)).collect();
+    let mut diff = AccountsDiff::default();
+    for info in left {
+        match right.remove(&info.client) {
+            Some(other) => diff
+                .changed
+                .extend(info.diff(&other).map(|delta| (info.client, delta))),
+            None => diff.only_left.push(info.client),
+        }
+    }
+    diff.only_right.extend(right.into_keys());
+    diff.changed.sort_by_key(|&(client, _)| client);
+    diff.only_left.sort();
+    diff.only_right.sort();
+    diff
+}
+
+/// Downstream parsers reject scientific notation, which `Decimal` would produce if anything in the build enabled
+/// one of `rust_decimal`'s `serde-float` features, so amounts are always written through their `Display`
+fn plain_decimal<S: Serializer>(
+    amount: &Decimal,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_str(amount)
+}
+
+/// An empty optional column, like `reason` on a dispute in a file that also has adjustments, is left out
+fn non_empty<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<String>, D::Error> {
+    let text = Option::<String>::deserialize(deserializer)?;
+    Ok(text.filter(|text| !text.is_empty()))
+}
+
+/// Any zero, like `-0` or `0.0000`, becomes a plain `0` so reports never show a negative zero
+fn normalize_zero(amount: Decimal) -> Decimal {
+    if amount.is_zero() {
+        Decimal::ZERO
+    } else {
+        amount
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn display_rounded_keeps_original() {
+        let info = AccountInfo {
+            client: 1,
+            available: dec!(1.23456),
+            held: dec!(0.00005),
+            total: dec!(1.23461),
+            locked: false,
+            currency: None,
+            closed: false,
+        };
+        let rounded = info.display_rounded(2);
+        assert_eq!(
+            rounded,
+            AccountInfo {
+                client: 1,
+                available: dec!(1.23),
+                held: dec!(0.00),
+                total: dec!(1.23),
+                locked: false,
+                currency: None,
+                closed: false,
+            }
+        );
+        assert_eq!(info.available, dec!(1.23456));
+        assert_eq!(info.held, dec!(0.00005));
+        assert_eq!(info.total, dec!(1.23461));
+    }
+
+    #[test]
+    fn transaction_round_trip() {
+        let transactions = vec![
+            Transaction {
+                currency: Some("EUR".parse().unwrap()),
+                ..Transaction::new(
+                    1,
+                    Event::Deposit {
+                        tx: 1,
+                        amount: dec!(1.5),
+                    },
+                )
+            },
+            Transaction::new(
+                1,
+                Event::Dispute {
+                    tx: 1,
+                    reason: None,
+                },
+            ),
+            Transaction::new(
+                2,
+                Event::Withdrawal {
+                    tx: 2,
+                    amount: dec!(0.25),
+                },
+            ),
+        ];
+
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_writer(vec![]);
+        for transaction in &transactions {
+            writer.serialize(transaction).unwrap();
+        }
+        let rows = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            rows,
+            "deposit,1,1,1.5,EUR\ndispute,1,1\nwithdrawal,2,2,0.25\n"
+        );
+
+        let input = "type,client,tx,amount,currency\ndeposit,1,1,1.5,EUR\n";
+        let mut reader = csv::Reader::from_reader(input.as_bytes());
+        let parsed: Transaction = reader.deserialize().next().unwrap().unwrap();
+        let mut writer = csv::Writer::from_writer(vec![]);
+        writer.serialize(parsed).unwrap();
+        assert_eq!(
+            String::from_utf8(writer.into_inner().unwrap()).unwrap(),
+            input
+        );
+
+        let json = serde_json::to_string(&transactions).unwrap();
+        assert_eq!(
+            serde_json::to_value(&transactions[1]).unwrap(),
+            serde_json::json!({"type": "dispute", "client": 1, "tx": 1})
+        );
+
+        let parsed: Vec<Transaction> = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+    }
+
+    #[test]
+    fn display_rounded_normalizes_zero() {
+        let info = AccountInfo {
+            client: 1,
+            available: dec!(-0.00001),
+            held: dec!(0.00001),
+            total: dec!(0),
+            locked: false,
+            currency: None,
+            closed: false,
+        };
+        let rounded = info.display_rounded(4);
+        assert_eq!(rounded.available.to_string(), "0");
+        assert_eq!(rounded.held.to_string(), "0");
+    }
+
+    fn account(client: ClientId, available: Decimal, held: Decimal, locked: bool) -> AccountInfo {
+        AccountInfo {
+            client,
+            available,
+            held,
+            total: available + held,
+            locked,
+            currency: None,
+            closed: false,
+        }
+    }
+
+    #[test]
+    fn diff() {
+        let info = account(1, dec!(5), dec!(2), false);
+        assert_eq!(info.diff(&info.clone()), None);
+        // scale doesn't matter
+        assert_eq!(info.diff(&account(1, dec!(5.00), dec!(2.0), false)), None);
+        assert_eq!(
+            info.diff(&account(1, dec!(4), dec!(2), true)),
+            Some(AccountDiff {
+                available: dec!(-1),
+                held: dec!(0),
+                total: dec!(-1),
+                locked: Some(true),
+                closed: None,
+            })
+        );
+
+        let low = account(1, Decimal::MIN, dec!(0), false);
+        let high = account(1, Decimal::MAX, dec!(0), false);
+        assert_eq!(low.diff(&high).unwrap().available, Decimal::MAX);
+        assert_eq!(high.diff(&low).unwrap().total, Decimal::MIN);
+    }
+
+    #[test]
+    fn diff_account_sets() {
+        let diff = diff_accounts(
+            [
+                account(1, dec!(5), dec!(0), false),
+                account(2, dec!(1), dec!(0), false),
+                account(3, dec!(1), dec!(0), false),
+            ],
+            [
+                account(4, dec!(1), dec!(0), false),
+                account(2, dec!(1), dec!(0), false),
+                account(1, dec!(5), dec!(1), false),
+            ],
+        );
+        assert_eq!(
+            diff,
+            AccountsDiff {
+                changed: vec![(
+                    1,
+                    AccountDiff {
+                        held: dec!(1),
+                        total: dec!(1),
+                        ..Default::default()
+                    }
+                )],
+                only_left: vec![3],
+                only_right: vec![4],
+            }
+        );
+        assert_eq!(
+            diff_accounts(Vec::new(), Vec::new()),
+            AccountsDiff::default()
+        );
+    }
+
+    #[test]
+    fn parse_in_memory() {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 1.5\n\
+                     teleport, 1, 2, 1.0\n\
+                     dispute, 1, 1,\n\
+                     withdrawal, 2\n";
+        let mut transactions = parse_transactions(input.as_bytes());
+        let transaction = transactions.next().unwrap().unwrap();
+        assert_eq!(transaction.client, 1);
+        assert!(
+            matches!(transaction.event, Event::Deposit { tx: 1, amount } if amount == dec!(1.5))
+        );
+        assert!(matches!(
+            transactions.next().unwrap().unwrap_err().error_type,
+            ErrorType::MalformedRow { line: 3, .. }
+        ));
+        assert!(matches!(
+            transactions.next().unwrap().unwrap().event,
+            Event::Dispute {
+                tx: 1,
+                reason: None
+            }
+        ));
+        assert!(matches!(
+            transactions.next().unwrap().unwrap_err().error_type,
+            ErrorType::MalformedRow { line: 5, .. }
+        ));
+        assert!(transactions.next().is_none());
+
+        let options = ParseOptions {
+            no_headers: true,
+            max_field_len: Some(7),
+            ..ParseOptions::default()
+        };
+        let mut transactions = parse_transactions_with(
+            "dispute,1,1\ndeposit,1,2,12.34567\ndeposit,1,3,1\n".as_bytes(),
+            options.clone(),
+        );
+        assert!(matches!(
+            transactions.next().unwrap().unwrap().event,
+            Event::Dispute {
+                tx: 1,
+                reason: None
+            }
+        ));
+        assert_eq!(
+            transactions.next().unwrap().unwrap_err().error_type,
+            ErrorType::FieldTooLong {
+                line: 2,
+                len: 8,
+                max: 7
+            }
+        );
+        assert!(transactions.next().is_none());
+
+        // the limit applies to the field as written, not to the amount it is rewritten to
+        let mut transactions = parse_transactions_with(
+            "deposit,1,4,\"1,234,567\"\n".as_bytes(),
+            ParseOptions {
+                thousands_separator: Some(','),
+                ..options
+            },
+        );
+        assert_eq!(
+            transactions.next().unwrap().unwrap_err().error_type,
+            ErrorType::FieldTooLong {
+                line: 1,
+                len: 9,
+                max: 7
+            }
+        );
+    }
+
+    #[test]
+    fn rounding_modes() {
+        let info = account(1, dec!(0.00005), dec!(-0.00005), false);
+        let rounded = |mode| {
+            let info = info.display_rounded_with(4, mode);
+            (info.available, info.held)
+        };
+        assert_eq!(rounded(RoundingMode::HalfEven), (dec!(0), dec!(0)));
+        assert_eq!(rounded(RoundingMode::HalfUp), (dec!(0.0001), dec!(-0.0001)));
+        assert_eq!(rounded(RoundingMode::Floor), (dec!(0), dec!(-0.0001)));
+        assert_eq!(rounded(RoundingMode::Ceil), (dec!(0.0001), dec!(0)));
+        assert_eq!(rounded(RoundingMode::Truncate), (dec!(0), dec!(0)));
+        assert_eq!(
+            info.display_rounded(4),
+            info.display_rounded_with(4, RoundingMode::HalfEven)
+        );
+    }
+
+    #[test]
+    fn no_scientific_notation() {
+        let mut writer = csv::Writer::from_writer(vec![]);
+        for (available, held) in [
+            (dec!(0.0001), dec!(123456789.1234)),
+            (dec!(0.0000000000000000000000000001), dec!(0)),
+            (dec!(79228162514264337593543950335), dec!(0)),
+        ] {
+            writer
+                .serialize(account(1, available, held, false))
+                .unwrap();
+        }
+        let written = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            written,
+            "client,available,held,total,locked,currency,closed\n\
+             1,0.0001,123456789.1234,123456789.1235,false,,false\n\
+             1,0.0000000000000000000000000001,0,0.0000000000000000000000000001,false,,false\n\
+             1,79228162514264337593543950335,0,79228162514264337593543950335,false,,false\n"
+        );
+    }
+
+    #[test]
+    fn dispute_reasons() {
+        let without = "type,client,tx,amount\n\
+                       dispute,1,1,\n";
+        let with = "type,client,tx,amount,reason\n\
+                    dispute,1,1,,fraud\n\
+                    dispute,1,2,,\n\
+                    adjustment,1,3,1.0,correction\n";
+        let events: Vec<_> = parse_transactions(without.as_bytes())
+            .chain(parse_transactions(with.as_bytes()))
+            .map(|transaction| transaction.unwrap().event)
+            .collect();
+        assert!(matches!(
+            events[0],
+            Event::Dispute {
+                tx: 1,
+                reason: None
+            }
+        ));
+        assert!(
+            matches!(&events[1], Event::Dispute { tx: 1, reason: Some(reason) } if reason == "fraud")
+        );
+        assert!(matches!(
+            events[2],
+            Event::Dispute {
+                tx: 2,
+                reason: None
+            }
+        ));
+        assert!(matches!(&events[3], Event::Adjustment { reason, .. } if reason == "correction"));
+    }
+
+    #[test]
+    fn tiny_buffer() {
+        let mut input = String::from("\u{feff}type, client, tx, amount, reason\n");
+        for tx in 1..=2000 {
+            let client = tx % 7;
+            match tx % 5 {
+                0 => input.push_str(&format!("dispute, {client}, {}, ,\n", tx - 1)),
+                1 => input.push_str(&format!(
+                    "adjustment, {client}, {tx}, -0.5,\"fee, monthly\"\n"
+                )),
+                _ => input.push_str(&format!("deposit, {client}, {tx}, {tx}.{tx},\n")),
+            }
+        }
+        input.push_str("withdrawal, 1, 2001, 5,");
+
+        let rows = |buffer_capacity| {
+            let options = ParseOptions {
+                buffer_capacity,
+                ..ParseOptions::default()
+            };
+            parse_transactions_with(input.as_bytes(), options)
+                .map(|transaction| format!("{:?}", transaction.unwrap()))
+                .collect::<Vec<_>>()
+        };
+        let expected = rows(None);
+        assert_eq!(expected.len(), 2001);
+        for capacity in [1, 2, 3, 7, 64] {
+            assert_eq!(rows(Some(capacity)), expected, "buffer of {capacity} bytes");
+        }
+    }
+
+    #[test]
+    fn case_insensitive_types() {
+        let input = "type,client,tx,amount\n\
+                     Deposit,1,1,2.0\n\
+                     WITHDRAWAL,1,2,1.0\n\
+                     DisPute,1,1,\n\
+                     RESOLVE,1,1,\n\
+                     ChargeBack,1,1,\n\
+                     Reverse_Chargeback,1,1,\n";
+        let events: Vec<_> = parse_transactions(input.as_bytes())
+            .map(|transaction| transaction.unwrap().event)
+            .collect();
+        assert!(matches!(
+            events[..],
+            [
+                Event::Deposit { tx: 1, .. },
+                Event::Withdrawal { tx: 2, .. },
+                Event::Dispute { tx: 1, .. },
+                Event::Resolve { tx: 1 },
+                Event::Chargeback { tx: 1 },
+                Event::ReverseChargeback { tx: 1 },
+            ]
+        ));
+
+        // only the type, other fields like the dispute reason are kept as they are
+        let input = "type,client,tx,amount,reason\nDISPUTE,1,1,,Fraud\n";
+        let mut transactions = parse_transactions(input.as_bytes());
+        assert!(matches!(
+            transactions.next().unwrap().unwrap().event,
+            Event::Dispute { tx: 1, reason: Some(reason) } if reason == "Fraud"
+        ));
+    }
 }
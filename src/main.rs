@@ -1,46 +1,523 @@
 use std::{
-    env,
-    fs::File,
-    io::{self, Read},
+    collections::HashMap,
+    env, fmt,
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io::{self, Read, Write},
 };
 
-use csv::{ReaderBuilder, Trim, Writer};
+use csv::Writer;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::Serialize;
 
-use interview::{Engine, Transaction};
+use interview::{
+    errors::{Error, ErrorKind, ErrorType},
+    parse_transactions_with, AccountInfo, ClientId, Engine, EngineConfig, Fnv1a, ParseOptions,
+    RoundingMode, Transaction, TransactionId,
+};
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
+const USAGE: &str =
+    "Expecting one argument: path to transactions.csv. If you'd like to read from stdin pass --
+
+Options:
+    --gzip                 decompress the input with gzip, implied by a .gz extension
+    --gzip-output          compress the written accounts with gzip
+    --emit-metadata        start the output with a comment line holding the version, config hash and transaction count
+    --no-headers           the input has no header row, columns are read in the order type, client, tx, amount
+    --all-columns          also write the currency and closed columns of the accounts
+    --minimal-decimals     write amounts without trailing zeros instead of with the output scale (4 decimal places by
+                           default, see output_scale in config.example.toml)
+    --max-field-len <N>    refuse input with fields longer than N bytes
+    --delimiter <C>        field delimiter of the input, a single byte like `;` or `\t` for tab (`,` by default)
+    --format <FORMAT>      `csv` (the default) or `copy` for the text format of Postgres' COPY, tab delimited with \\N
+                           for nulls (pass --no-headers too unless it was copied WITH HEADER)
+    --buffer-size <N>      read the input through a buffer of N bytes (8 KiB by default), larger is faster for huge files
+    --thousands-separator <C>
+                           separator allowed in amounts like 1,234.56 with `,` (none by default)
+    --strict               exit with an error if any transaction failed
+    --errors <PATH>        also write every failed transaction and malformed row to a CSV file
+    --config <PATH>        read the engine settings from a TOML file, see config.example.toml
+    --global-dispute       let any client dispute another client's deposits, overrides the config file";
+
+#[derive(Default)]
+struct Options {
+    path: String,
+    gzip: bool,
+    gzip_output: bool,
+    no_headers: bool,
+    emit_metadata: bool,
+    all_columns: bool,
+    minimal_decimals: bool,
+    max_field_len: Option<usize>,
+    delimiter: u8,
+    /// Postgres `COPY` text format instead of CSV
+    copy: bool,
+    buffer_size: Option<usize>,
+    thousands_separator: Option<char>,
+    strict: bool,
+    errors: Option<String>,
+    config: EngineConfig,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut options = Options::default();
+        let mut path = None;
+        let mut config_path = None;
+        let mut global_dispute = false;
+        let mut delimiter = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--gzip" => options.gzip = true,
+                "--gzip-output" => options.gzip_output = true,
+                "--no-headers" => options.no_headers = true,
+                "--emit-metadata" => options.emit_metadata = true,
+                "--all-columns" => options.all_columns = true,
+                "--minimal-decimals" => options.minimal_decimals = true,
+                "--strict" => options.strict = true,
+                "--global-dispute" => global_dispute = true,
+                "--config" => config_path = Some(value(&mut args, &arg)?),
+                "--errors" => options.errors = Some(value(&mut args, &arg)?),
+                "--max-field-len" => options.max_field_len = Some(value(&mut args, &arg)?.parse()?),
+                "--delimiter" => delimiter = Some(parse_delimiter(&value(&mut args, &arg)?)?),
+                "--format" => {
+                    options.copy = match value(&mut args, &arg)?.as_str() {
+                        "csv" => false,
+                        "copy" => true,
+                        format => {
+                            return Err(
+                                format!("--format expects csv or copy, got {format:?}").into()
+                            )
+                        }
+                    }
+                }
+                "--buffer-size" => match value(&mut args, &arg)?.parse()? {
+                    0 => return Err("--buffer-size has to be at least 1 byte".into()),
+                    size => options.buffer_size = Some(size),
+                },
+                "--thousands-separator" => {
+                    options.thousands_separator = match value(&mut args, &arg)?.as_str() {
+                        "none" => None,
+                        separator => Some(separator.parse()?),
+                    }
+                }
+                "--" => path = Some(arg),
+                _ if arg.starts_with("--") => {
+                    return Err(format!("Unknown option {arg}\n\n{USAGE}").into())
+                }
+                _ => path = Some(arg),
+            }
+        }
+        options.path = path.ok_or(USAGE)?;
+        options.delimiter = delimiter.unwrap_or(if options.copy { b'\t' } else { b',' });
+        if let Some(config_path) = config_path {
+            let toml = fs::read_to_string(&config_path)?;
+            options.config = EngineConfig::from_toml(&toml)
+                .map_err(|err| format!("Invalid config {config_path}: {err}"))?;
+        }
+        // flags take precedence over the config file
+        if global_dispute {
+            options.config.global_dispute = Some(true);
+        }
+        Ok(options)
+    }
+
+    /// Identifies the settings that change the results, so a dump can be traced back to its ruleset
+    fn config_hash(&self, engine: &Engine) -> u64 {
+        let mut hasher = Fnv1a::default();
+        (
+            engine.output_scale(),
+            engine.rounding(),
+            self.minimal_decimals,
+            self.all_columns,
+            self.max_field_len,
+            self.delimiter,
+            self.copy,
+            self.thousands_separator,
+            &self.config,
+        )
+            .hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// Everything that changes how the accounts are written, besides where they are written to
+struct OutputFormat {
+    /// Written as a comment line before the accounts
+    metadata: Option<String>,
+    /// Amounts are written with exactly this many decimal places, or as short as possible if `None`
+    scale: Option<u32>,
+    /// Every column of `AccountInfo`, instead of only the documented `client,available,held,total,locked`
+    all_columns: bool,
+}
+
+/// The columns written without `--all-columns`, which existing consumers of the output expect
+#[derive(Serialize)]
+struct Columns {
+    client: ClientId,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+}
+
+impl From<AccountInfo> for Columns {
+    fn from(info: AccountInfo) -> Self {
+        Self {
+            client: info.client,
+            available: info.available.to_string(),
+            held: info.held.to_string(),
+            total: info.total.to_string(),
+            locked: info.locked,
+        }
+    }
+}
+
+/// Written to stderr once all transactions are handled
+#[derive(Default)]
+struct Summary {
+    succeeded: usize,
+    failed: HashMap<ErrorKind, usize>,
+    /// Rows that couldn't be parsed into a transaction
+    malformed: usize,
+}
+
+impl Summary {
+    fn failed(&self) -> usize {
+        self.malformed + self.failed.values().sum::<usize>()
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} transactions succeeded, {} failed",
+            self.succeeded,
+            self.failed()
+        )?;
+        let mut failed: Vec<_> = self
+            .failed
+            .iter()
+            .map(|(kind, count)| format!("{kind:?}: {count}"))
+            .collect();
+        if self.malformed > 0 {
+            failed.push(format!("Malformed: {}", self.malformed));
+        }
+        if !failed.is_empty() {
+            failed.sort();
+            write!(f, " ({})", failed.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// One row of the `--errors` report, `row` counts the rows of the input starting at 1 (not counting the header)
+#[derive(Serialize)]
+struct ErrorRow {
+    row: usize,
+    /// Unknown for malformed rows
+    client: Option<ClientId>,
+    tx: Option<TransactionId>,
+    kind: ErrorKind,
+    message: String,
+}
+
+fn parse_delimiter(value: &str) -> Result<u8> {
+    match value.as_bytes() {
+        b"\\t" => Ok(b'\t'),
+        &[delimiter] => Ok(delimiter),
+        _ => Err(format!("--delimiter expects a single byte, got {value:?}").into()),
+    }
+}
+
+fn value(args: &mut impl Iterator<Item = String>, option: &str) -> Result<String> {
+    Ok(args
+        .next()
+        .ok_or_else(|| format!("{option} expects a value\n\n{USAGE}"))?)
+}
+
 fn main() -> Result<()> {
     env_logger::init();
 
-    let path = env::args()
-        .nth(1)
-        .ok_or("Expecting one argument: path to transactions.csv. If you'd like to read from stdin pass --")?;
+    let options = Options::parse(env::args().skip(1))?;
 
     let mut file_input;
     let mut stdin_input;
     let input: &mut dyn Read;
-    if path == "--" {
+    if options.path == "--" {
         stdin_input = io::stdin();
         input = &mut stdin_input;
     } else {
-        file_input = File::open(path)?;
+        file_input = File::open(&options.path)?;
         input = &mut file_input;
     }
-    let mut reader = ReaderBuilder::new().trim(Trim::All).from_reader(input);
+    let mut gzip_input;
+    let input: &mut dyn Read = if options.gzip || options.path.ends_with(".gz") {
+        gzip_input = GzDecoder::new(input);
+        &mut gzip_input
+    } else {
+        input
+    };
+    let parse_options = if options.copy {
+        ParseOptions::postgres_copy()
+    } else {
+        ParseOptions::default()
+    };
+    let transactions = parse_transactions_with(
+        input,
+        ParseOptions {
+            no_headers: options.no_headers,
+            max_field_len: options.max_field_len,
+            delimiter: options.delimiter,
+            buffer_capacity: options.buffer_size,
+            thousands_separator: options.thousands_separator,
+            ..parse_options
+        },
+    );
 
-    let mut engine = Engine::new();
-    engine.set_global_dispute(false);
-    for record in reader.deserialize() {
-        let transaction: Transaction = record?;
-        if let Err(err) = engine.handle(transaction) {
-            eprintln!("Error handling transaction: {}", err)
+    let mut engine = options.config.build();
+    let mut transaction_count = 0;
+    let mut summary = Summary::default();
+    let mut errors = Vec::new();
+    for transaction in transactions {
+        transaction_count += 1;
+        let transaction = match transaction {
+            Ok(transaction) => transaction,
+            Err(err) => match err.error_type {
+                ErrorType::MalformedRow { line, byte, error } => {
+                    // a single bad row shouldn't discard the rest of the file, so it is only reported
+                    log::error!("Skipping malformed row on line {line} (byte {byte}): {error}");
+                    summary.malformed += 1;
+                    if options.errors.is_some() {
+                        errors.push(ErrorRow {
+                            row: transaction_count,
+                            client: None,
+                            tx: None,
+                            kind: ErrorKind::MalformedRow,
+                            message: format!("malformed row on line {line} (byte {byte}): {error}"),
+                        });
+                    }
+                    continue;
+                }
+                ErrorType::FieldTooLong { line, len, max } => {
+                    let message = format!(
+                        "Field of {len} bytes on line {line} exceeds the maximum field length of {max}"
+                    );
+                    return Err(message.into());
+                }
+                _ => return Err(err.to_string().into()),
+            },
+        };
+        let (client, tx) = (transaction.client, transaction.event.tx());
+        match handle_logged(&mut engine, transaction) {
+            Ok(()) => summary.succeeded += 1,
+            Err(err) => {
+                *summary.failed.entry(err.error_type.kind()).or_default() += 1;
+                if options.errors.is_some() {
+                    errors.push(ErrorRow {
+                        row: transaction_count,
+                        client: Some(client),
+                        tx: Some(tx),
+                        kind: err.error_type.kind(),
+                        message: err.to_string(),
+                    });
+                }
+            }
         }
     }
+    if let Some(path) = &options.errors {
+        let mut writer = Writer::from_path(path)?;
+        for error in errors {
+            writer.serialize(error)?;
+        }
+        writer.flush()?;
+    }
 
-    let mut writer = Writer::from_writer(io::stdout());
-    for info in engine.all_accounts() {
-        writer.serialize(info)?;
+    let format = OutputFormat {
+        metadata: options.emit_metadata.then(|| {
+            format!(
+                "# {} {} config={:016x} transactions={}",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION"),
+                options.config_hash(&engine),
+                transaction_count
+            )
+        }),
+        scale: (!options.minimal_decimals).then_some(engine.output_scale()),
+        all_columns: options.all_columns,
+    };
+    if options.gzip_output {
+        write_accounts(
+            &engine,
+            &format,
+            GzEncoder::new(io::stdout(), Compression::default()),
+        )?
+        .finish()?;
+    } else {
+        write_accounts(&engine, &format, io::stdout())?;
+    }
+    eprintln!("{summary}");
+    if options.strict && summary.failed() > 0 {
+        return Err(format!("{} transactions failed", summary.failed()).into());
     }
     Ok(())
 }
+
+/// Logs a failed transaction at the level of its severity, which the config can lower per error kind
+fn handle_logged(engine: &mut Engine, transaction: Transaction) -> std::result::Result<(), Error> {
+    engine.handle(transaction).map(|_| ()).map_err(|err| {
+        log::log!(err.severity.level(), "Error handling transaction: {}", err);
+        err
+    })
+}
+
+/// Downstream parsers expect fixed width decimals, e.g. `5.0000` instead of `5`
+fn fixed_scale(info: AccountInfo, scale: u32, rounding: RoundingMode) -> AccountInfo {
+    let mut info = info.display_rounded_with(scale, rounding);
+    for amount in [&mut info.available, &mut info.held, &mut info.total] {
+        amount.rescale(scale);
+    }
+    info
+}
+
+/// Returns the output back so wrappers like `GzEncoder` can be finished properly.
+///
+/// Everything is flushed before returning, so a broken pipe or a full disk fails here instead of being lost when
+/// the output is dropped. The error tells how many accounts were written before, some of which may be buffered.
+fn write_accounts<W: Write>(engine: &Engine, format: &OutputFormat, output: W) -> Result<W> {
+    let mut written = 0;
+    let write = || -> Result<W> {
+        let mut output = output;
+        if let Some(metadata) = &format.metadata {
+            writeln!(output, "{metadata}")?;
+        }
+        let mut writer = Writer::from_writer(output);
+        for info in engine.all_accounts() {
+            let info = match format.scale {
+                Some(scale) => fixed_scale(info, scale, engine.rounding()),
+                None => info,
+            };
+            if format.all_columns {
+                writer.serialize(info)?;
+            } else {
+                writer.serialize(Columns::from(info))?;
+            }
+            written += 1;
+        }
+        writer.flush()?;
+        let mut output = writer.into_inner().map_err(|err| err.into_error())?;
+        output.flush()?;
+        Ok(output)
+    };
+    write().map_err(|err| {
+        let total = engine.all_accounts().count();
+        format!("Failed writing the accounts, {written} of {total} were written: {err}").into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use std::sync::Mutex;
+
+    use super::*;
+    use interview::Event;
+
+    /// Accepts `capacity` bytes, then fails like a full disk
+    #[derive(Debug)]
+    struct FullDisk {
+        capacity: usize,
+    }
+
+    impl Write for FullDisk {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.capacity == 0 {
+                return Err(io::Error::new(io::ErrorKind::StorageFull, "no space left"));
+            }
+            let len = buf.len().min(self.capacity);
+            self.capacity -= len;
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct CapturingLogger;
+
+    static CAPTURED: Mutex<Vec<(log::Level, String)>> = Mutex::new(Vec::new());
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            CAPTURED
+                .lock()
+                .unwrap()
+                .push((record.level(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn configured_severity() {
+        static LOGGER: CapturingLogger = CapturingLogger;
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let config =
+            EngineConfig::from_toml("[severities]\nInsufficientFunds = \"warning\"").unwrap();
+        let mut engine = config.build();
+        let withdrawal = Event::Withdrawal {
+            tx: 1,
+            amount: dec!(1),
+        };
+        let deposit = Event::Deposit {
+            tx: 2,
+            amount: dec!(-1),
+        };
+        assert!(handle_logged(&mut engine, Transaction::new(1, withdrawal)).is_err());
+        assert!(handle_logged(&mut engine, Transaction::new(1, deposit)).is_err());
+
+        let levels: Vec<_> = CAPTURED
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, message)| message.starts_with("Error handling transaction"))
+            .map(|(level, _)| *level)
+            .collect();
+        // kinds left out of the config keep the error level
+        assert_eq!(levels, vec![log::Level::Warn, log::Level::Error]);
+    }
+
+    #[test]
+    fn failing_output() {
+        let mut engine = Engine::new();
+        for client in 1..=3 {
+            let deposit = Event::Deposit {
+                tx: client,
+                amount: dec!(1),
+            };
+            engine.handle(Transaction::new(client, deposit)).unwrap();
+        }
+        let format = OutputFormat {
+            metadata: None,
+            scale: Some(4),
+            all_columns: false,
+        };
+        // the csv writer buffers every row, so the failure only shows up once it is flushed
+        let err = write_accounts(&engine, &format, FullDisk { capacity: 16 }).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("3 of 3 were written"), "{message}");
+        assert!(message.contains("no space left"), "{message}");
+        write_accounts(&engine, &format, FullDisk { capacity: 1024 }).unwrap();
+    }
+}
@@ -0,0 +1,23 @@
+use rust_decimal::Decimal;
+
+use crate::{errors::Error, ClientId, TransactionId};
+
+/// Hook for metrics, every handled transaction ends in exactly one of these calls.
+///
+/// All methods do nothing by default so implementations only pick what they need. Observers are `Send` so the
+/// engine holding one can be moved to another thread.
+pub trait EngineObserver: Send {
+    fn on_deposit(&mut self, _client: ClientId, _tx: TransactionId, _amount: Decimal) {}
+    fn on_withdrawal(&mut self, _client: ClientId, _tx: TransactionId, _amount: Decimal) {}
+    fn on_adjustment(&mut self, _client: ClientId, _tx: TransactionId, _amount: Decimal) {}
+    fn on_flag(&mut self, _client: ClientId, _tx: TransactionId) {}
+    fn on_dispute(&mut self, _client: ClientId, _tx: TransactionId) {}
+    fn on_resolve(&mut self, _client: ClientId, _tx: TransactionId) {}
+    fn on_partial_resolve(&mut self, _client: ClientId, _tx: TransactionId, _amount: Decimal) {}
+    fn on_chargeback(&mut self, _client: ClientId, _tx: TransactionId) {}
+    fn on_reverse_chargeback(&mut self, _client: ClientId, _tx: TransactionId) {}
+    fn on_hold(&mut self, _client: ClientId, _tx: TransactionId, _amount: Decimal) {}
+    fn on_release_hold(&mut self, _client: ClientId, _tx: TransactionId) {}
+    fn on_void(&mut self, _client: ClientId, _tx: TransactionId) {}
+    fn on_rejected(&mut self, _error: &Error) {}
+}
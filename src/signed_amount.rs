@@ -0,0 +1,115 @@
+//! Amounts of recorded transactions as they changed the client's balance.
+//!
+//! Deposits credit the account and withdrawals debit it, so a disputed withdrawal holds a negative amount. Keeping
+//! the direction in the type rather than in the sign of a bare `Decimal` makes it explicit wherever funds move.
+
+use std::ops::Sub;
+
+use rust_decimal::Decimal;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct SignedAmount(Decimal);
+
+impl SignedAmount {
+    pub const ZERO: SignedAmount = SignedAmount(Decimal::ZERO);
+
+    /// Money added to the account, like a deposit of `amount`
+    pub fn credit(amount: Decimal) -> Self {
+        Self(amount)
+    }
+
+    /// Money taken out of the account, like a withdrawal of `amount`
+    pub fn debit(amount: Decimal) -> Self {
+        Self(-amount)
+    }
+
+    /// Credit if `change` is positive and debit if it's negative, as adjustments are given
+    pub fn from_balance_change(change: Decimal) -> Self {
+        Self(change)
+    }
+
+    /// How much the balance changed, negative for debits
+    pub fn balance_change(self) -> Decimal {
+        self.0
+    }
+
+    pub fn is_credit(self) -> bool {
+        self.0 > Decimal::ZERO
+    }
+
+    pub fn is_debit(self) -> bool {
+        self.0 < Decimal::ZERO
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.0.is_zero()
+    }
+
+    /// The amount without its direction
+    pub fn magnitude(self) -> Decimal {
+        self.0.abs()
+    }
+
+    /// `amount` in the same direction as this one, e.g. part of a disputed withdrawal's held funds
+    pub fn with_magnitude(self, amount: Decimal) -> Self {
+        if self.is_debit() {
+            Self::debit(amount)
+        } else {
+            Self::credit(amount)
+        }
+    }
+
+    /// Moves this amount from the `from` balance to the `to` one, returning both new balances.
+    ///
+    /// Debits move funds the other way, which is how disputing a withdrawal puts a negative amount on hold.
+    pub fn move_between(self, from: Decimal, to: Decimal) -> (Decimal, Decimal) {
+        (from - self.0, to + self.0)
+    }
+
+    /// `move_between`, or `None` if either balance would overflow
+    pub fn checked_move_between(self, from: Decimal, to: Decimal) -> Option<(Decimal, Decimal)> {
+        Some((from.checked_sub(self.0)?, to.checked_add(self.0)?))
+    }
+}
+
+impl Sub for SignedAmount {
+    type Output = SignedAmount;
+
+    fn sub(self, other: SignedAmount) -> SignedAmount {
+        SignedAmount(self.0 - other.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn directions() {
+        let deposit = SignedAmount::credit(dec!(10));
+        let withdrawal = SignedAmount::debit(dec!(10));
+        assert!(deposit.is_credit() && !deposit.is_debit());
+        assert!(withdrawal.is_debit() && !withdrawal.is_credit());
+        assert_eq!(withdrawal.balance_change(), dec!(-10));
+        assert_eq!(withdrawal.magnitude(), dec!(10));
+        assert_eq!(SignedAmount::from_balance_change(dec!(-10)), withdrawal);
+
+        // disputing moves a deposit from available to held, and a withdrawal the other way
+        assert_eq!(deposit.move_between(dec!(15), dec!(0)), (dec!(5), dec!(10)));
+        assert_eq!(
+            withdrawal.move_between(dec!(15), dec!(0)),
+            (dec!(25), dec!(-10))
+        );
+        assert_eq!(
+            withdrawal.with_magnitude(dec!(4)),
+            SignedAmount::debit(dec!(4))
+        );
+        assert_eq!(
+            withdrawal - withdrawal.with_magnitude(dec!(4)),
+            SignedAmount::debit(dec!(6))
+        );
+        assert_eq!(deposit.checked_move_between(Decimal::MIN, dec!(0)), None);
+    }
+}
@@ -0,0 +1,183 @@
+//! Where the engine keeps the deposits, withdrawals and adjustments it recorded, so they can be disputed later.
+//!
+//! These outnumber the accounts by far, so datasets that don't fit in memory can keep them elsewhere (e.g. in
+//! sled or rocksdb) by implementing `TransactionStore` and building the engine with
+//! `Engine::with_transaction_store`. `TransactionInfo` is opaque, but implements `Serialize` and `Deserialize`
+//! for stores that need to write it out.
+
+use std::collections::HashMap;
+
+use crate::{engine::TransactionInfo, TransactionId};
+
+/// Stores are `Send` so the engine holding one can be moved to another thread.
+pub trait TransactionStore: Send {
+    /// Returns the transaction previously stored under `tx`, if any
+    fn insert(&mut self, tx: TransactionId, info: TransactionInfo) -> Option<TransactionInfo>;
+
+    fn get(&self, tx: TransactionId) -> Option<&TransactionInfo>;
+
+    fn get_mut(&mut self, tx: TransactionId) -> Option<&mut TransactionInfo>;
+
+    fn contains(&self, tx: TransactionId) -> bool {
+        self.get(tx).is_some()
+    }
+
+    fn remove(&mut self, tx: TransactionId) -> Option<TransactionInfo>;
+
+    /// Every stored transaction, in any order
+    fn iter(&self) -> Box<dyn Iterator<Item = (TransactionId, &TransactionInfo)> + '_>;
+
+    /// Keeps only the transactions `keep` returns `true` for
+    fn retain(&mut self, keep: &mut dyn FnMut(TransactionId, &TransactionInfo) -> bool);
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn clear(&mut self);
+}
+
+/// The default store, keeping everything in memory
+impl TransactionStore for HashMap<TransactionId, TransactionInfo> {
+    fn insert(&mut self, tx: TransactionId, info: TransactionInfo) -> Option<TransactionInfo> {
+        HashMap::insert(self, tx, info)
+    }
+
+    fn get(&self, tx: TransactionId) -> Option<&TransactionInfo> {
+        HashMap::get(self, &tx)
+    }
+
+    fn get_mut(&mut self, tx: TransactionId) -> Option<&mut TransactionInfo> {
+        HashMap::get_mut(self, &tx)
+    }
+
+    fn contains(&self, tx: TransactionId) -> bool {
+        self.contains_key(&tx)
+    }
+
+    fn remove(&mut self, tx: TransactionId) -> Option<TransactionInfo> {
+        HashMap::remove(self, &tx)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (TransactionId, &TransactionInfo)> + '_> {
+        Box::new(HashMap::iter(self).map(|(&tx, info)| (tx, info)))
+    }
+
+    fn retain(&mut self, keep: &mut dyn FnMut(TransactionId, &TransactionInfo) -> bool) {
+        HashMap::retain(self, |&tx, info| keep(tx, info));
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn clear(&mut self) {
+        HashMap::clear(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::{Engine, Event, Transaction};
+
+    /// Ordered by id, counting what is written so the test can tell the engine used it
+    struct CountingStore {
+        transactions: BTreeMap<TransactionId, TransactionInfo>,
+        inserts: Arc<AtomicUsize>,
+    }
+
+    impl TransactionStore for CountingStore {
+        fn insert(&mut self, tx: TransactionId, info: TransactionInfo) -> Option<TransactionInfo> {
+            self.inserts.fetch_add(1, Ordering::Relaxed);
+            self.transactions.insert(tx, info)
+        }
+
+        fn get(&self, tx: TransactionId) -> Option<&TransactionInfo> {
+            self.transactions.get(&tx)
+        }
+
+        fn get_mut(&mut self, tx: TransactionId) -> Option<&mut TransactionInfo> {
+            self.transactions.get_mut(&tx)
+        }
+
+        fn remove(&mut self, tx: TransactionId) -> Option<TransactionInfo> {
+            self.transactions.remove(&tx)
+        }
+
+        fn iter(&self) -> Box<dyn Iterator<Item = (TransactionId, &TransactionInfo)> + '_> {
+            Box::new(self.transactions.iter().map(|(&tx, info)| (tx, info)))
+        }
+
+        fn retain(&mut self, keep: &mut dyn FnMut(TransactionId, &TransactionInfo) -> bool) {
+            self.transactions.retain(|&tx, info| keep(tx, info));
+        }
+
+        fn len(&self) -> usize {
+            self.transactions.len()
+        }
+
+        fn clear(&mut self) {
+            self.transactions.clear();
+        }
+    }
+
+    #[test]
+    fn custom_store() {
+        let inserts = Arc::new(AtomicUsize::new(0));
+        let mut engine = Engine::with_transaction_store(CountingStore {
+            transactions: BTreeMap::new(),
+            inserts: inserts.clone(),
+        });
+        let events = [
+            Event::Deposit {
+                tx: 1,
+                amount: dec!(10),
+            },
+            Event::Deposit {
+                tx: 2,
+                amount: dec!(5),
+            },
+            Event::Withdrawal {
+                tx: 3,
+                amount: dec!(3),
+            },
+            Event::Dispute {
+                tx: 2,
+                reason: None,
+            },
+            Event::Chargeback { tx: 2 },
+        ];
+        for event in events {
+            engine.handle(Transaction::new(1, event)).unwrap();
+        }
+        assert_eq!(inserts.load(Ordering::Relaxed), 3);
+        assert!(engine.contains_transaction(3));
+        assert_eq!(engine.amount_of(3).unwrap(), dec!(-3));
+        let info = engine.account_info(1);
+        assert_eq!(
+            (info.available, info.held, info.locked),
+            (dec!(7), dec!(0), true)
+        );
+
+        // rolling back puts the transaction back into the store
+        engine.rollback(1).unwrap();
+        assert_eq!(inserts.load(Ordering::Relaxed), 4);
+        assert_eq!(engine.disputed_transactions().count(), 1);
+
+        let restored = Engine::from_bytes(&engine.to_bytes()).unwrap();
+        assert_eq!(restored.state_hash(), engine.state_hash());
+    }
+}
@@ -0,0 +1,121 @@
+//! Driving the engine from an async stream of transactions, e.g. coming from a message queue.
+//!
+//! The engine itself stays synchronous, this only awaits the next transaction and hands it to `handle`.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{Stream, StreamExt};
+
+use crate::{errors::Error, Engine, Transaction};
+
+impl Engine {
+    /// Handles every transaction of `stream` in order, yielding to the executor after each one so a long
+    /// stream of ready items doesn't starve other tasks.
+    ///
+    /// Failed transactions are collected along with their position in the stream, like `from_transactions`.
+    pub async fn process_async<S: Stream<Item = Transaction>>(
+        &mut self,
+        stream: S,
+    ) -> Vec<(usize, Error)> {
+        let mut stream = std::pin::pin!(stream);
+        let mut errors = Vec::new();
+        let mut index = 0;
+        while let Some(transaction) = stream.next().await {
+            if let Err(err) = self.handle(transaction) {
+                errors.push((index, err));
+            }
+            index += 1;
+            YieldNow(false).await;
+        }
+        errors
+    }
+}
+
+/// Returns `Pending` exactly once, after asking to be polled again
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::{errors::ErrorType, Event};
+
+    #[tokio::test]
+    async fn process_stream() {
+        let mut engine = Engine::new();
+        let errors = engine
+            .process_async(stream::iter([
+                Transaction::new(
+                    1,
+                    Event::Deposit {
+                        tx: 1,
+                        amount: dec!(5),
+                    },
+                ),
+                Transaction::new(
+                    1,
+                    Event::Withdrawal {
+                        tx: 2,
+                        amount: dec!(10),
+                    },
+                ),
+                Transaction::new(
+                    1,
+                    Event::Withdrawal {
+                        tx: 3,
+                        amount: dec!(2),
+                    },
+                ),
+            ]))
+            .await;
+        assert_eq!(engine.account_info(1).available, dec!(3));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, 1);
+        assert_eq!(
+            errors[0].1.error_type,
+            ErrorType::InsufficientFunds { client: 1, tx: 2 }
+        );
+    }
+
+    #[tokio::test]
+    async fn spawned_stream() {
+        // `spawn` requires the future to be `Send`, like it is for multi-threaded executors
+        let engine = tokio::spawn(async {
+            let mut engine = Engine::new();
+            let transactions = (1..=3).map(|tx| {
+                Transaction::new(
+                    1,
+                    Event::Deposit {
+                        tx,
+                        amount: dec!(1),
+                    },
+                )
+            });
+            let errors = engine.process_async(stream::iter(transactions)).await;
+            assert!(errors.is_empty());
+            engine
+        })
+        .await
+        .unwrap();
+        assert_eq!(engine.account_info(1).available, dec!(3));
+    }
+}
@@ -0,0 +1,487 @@
+use std::{
+    io::{Read, Write},
+    process::{Command, Output, Stdio},
+};
+
+use flate2::read::GzDecoder;
+
+fn run(args: &[&str], input: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_interview"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// Output rows sorted by client, since accounts are not written in any particular order
+fn accounts(output: &Output) -> Vec<String> {
+    accounts_with_header(output, "client,available,held,total,locked")
+}
+
+fn accounts_with_header(output: &Output, header: &str) -> Vec<String> {
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8(output.stdout.clone()).unwrap();
+    let mut lines = stdout.lines();
+    assert_eq!(lines.next(), Some(header));
+    let mut rows: Vec<_> = lines.map(str::to_owned).collect();
+    rows.sort();
+    rows
+}
+
+#[test]
+fn reordered_columns() {
+    let output = run(
+        &["--"],
+        b"amount,type,client,tx\n\
+          1.5,deposit,1,1\n\
+          0.5,withdrawal,1,2\n\
+          ,dispute,1,1\n",
+    );
+    assert_eq!(accounts(&output), vec!["1,-0.5000,1.5000,1.0000,false"]);
+}
+
+#[test]
+fn header_with_bom_and_whitespace() {
+    let output = run(
+        &["--"],
+        b"\xEF\xBB\xBF type , client ,tx, amount\n\
+          deposit, 1, 1, 2.0\n\
+          deposit, 2, 2, 3.0\n",
+    );
+    assert_eq!(
+        accounts(&output),
+        vec![
+            "1,2.0000,0.0000,2.0000,false",
+            "2,3.0000,0.0000,3.0000,false"
+        ]
+    );
+}
+
+#[test]
+fn gzip_output() {
+    let output = run(
+        &["--gzip-output", "--"],
+        b"type,client,tx,amount\n\
+          deposit,1,1,2.5\n",
+    );
+    assert!(output.status.success());
+    let mut decompressed = String::new();
+    GzDecoder::new(&output.stdout[..])
+        .read_to_string(&mut decompressed)
+        .unwrap();
+    assert_eq!(
+        decompressed,
+        "client,available,held,total,locked\n1,2.5000,0.0000,2.5000,false\n"
+    );
+}
+
+#[test]
+fn signed_amounts() {
+    let output = run(
+        &["--"],
+        b"type,client,tx,amount\n\
+          deposit,1,1,+5.00\n\
+          deposit,2,2,5.00\n\
+          deposit,3,3,-5.00\n\
+          withdrawal,2,4,+1.5\n\
+          withdrawal,2,5,-1.5\n",
+    );
+    assert_eq!(
+        accounts(&output),
+        vec![
+            "1,5.0000,0.0000,5.0000,false",
+            "2,3.5000,0.0000,3.5000,false"
+        ]
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("NegativeDeposit { tx: 3 }"), "{stderr}");
+    assert!(stderr.contains("NegativeWithdrawal { tx: 5 }"), "{stderr}");
+}
+
+#[test]
+fn max_field_len() {
+    let mut input = b"type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,1".to_vec();
+    input.extend([b'0'; 1000]);
+    input.push(b'\n');
+
+    let output = run(&["--max-field-len", "64", "--"], &input);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Field of 1001 bytes on line 3 exceeds the maximum field length of 64"),
+        "{stderr}"
+    );
+
+    // without the limit the amount is just too large to parse, so only that row is skipped
+    assert_eq!(
+        accounts(&run(&["--"], &input)),
+        vec!["1,1.0000,0.0000,1.0000,false"]
+    );
+    let output = run(
+        &["--max-field-len", "64", "--"],
+        b"type,client,tx,amount\ndeposit,1,1,1.0\n",
+    );
+    assert_eq!(accounts(&output), vec!["1,1.0000,0.0000,1.0000,false"]);
+}
+
+#[test]
+fn decimal_places() {
+    let input = b"type,client,tx,amount\n\
+                  deposit,1,1,5\n\
+                  deposit,2,2,1.23456\n";
+    assert_eq!(
+        accounts(&run(&["--"], input)),
+        vec![
+            "1,5.0000,0.0000,5.0000,false",
+            "2,1.2346,0.0000,1.2346,false"
+        ]
+    );
+    assert_eq!(
+        accounts(&run(&["--minimal-decimals", "--"], input)),
+        vec!["1,5,0,5,false", "2,1.23456,0,1.23456,false"]
+    );
+}
+
+#[test]
+fn metadata() {
+    let input = b"type,client,tx,amount\n\
+                  deposit,1,1,5\n\
+                  withdrawal,1,2,10\n\
+                  dispute,1,1,\n";
+    let metadata = |args: &[&str]| {
+        let output = run(args, input);
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let mut lines = stdout.lines();
+        let metadata = lines.next().unwrap().to_owned();
+        assert_eq!(lines.next(), Some("client,available,held,total,locked"));
+        metadata
+    };
+
+    let default = metadata(&["--emit-metadata", "--"]);
+    let prefix = format!("# interview {} config=", env!("CARGO_PKG_VERSION"));
+    let (hash, count) = default
+        .strip_prefix(&prefix)
+        .unwrap()
+        .split_once(' ')
+        .unwrap();
+    assert_eq!(hash.len(), 16);
+    assert!(hash.chars().all(|c| c.is_ascii_hexdigit()));
+    assert_eq!(count, "transactions=3");
+
+    assert_eq!(metadata(&["--emit-metadata", "--"]), default);
+    assert_ne!(
+        metadata(&["--emit-metadata", "--minimal-decimals", "--"]),
+        default
+    );
+}
+
+#[test]
+fn currency_column() {
+    let input = b"type,client,tx,amount,currency\n\
+                  deposit,1,1,5,eur\n\
+                  deposit,2,2,5,\n\
+                  deposit,2,3,5,USD\n";
+    let output = run(&["--all-columns", "--"], input);
+    assert_eq!(
+        accounts_with_header(
+            &output,
+            "client,available,held,total,locked,currency,closed"
+        ),
+        vec![
+            "1,5.0000,0.0000,5.0000,false,EUR,false",
+            "2,10.0000,0.0000,10.0000,false,,false"
+        ]
+    );
+
+    // only written when asked for, so the documented columns stay the same
+    let output = run(&["--"], input);
+    assert_eq!(
+        accounts(&output),
+        vec![
+            "1,5.0000,0.0000,5.0000,false",
+            "2,10.0000,0.0000,10.0000,false"
+        ]
+    );
+}
+
+#[test]
+fn strict() {
+    let input = b"type,client,tx,amount\n\
+          deposit,1,1,1.0\n\
+          withdrawal,1,2,2.0\n\
+          withdrawal,1,3,2.0\n\
+          dispute,1,4,\n";
+    let output = run(&["--"], input);
+    assert_eq!(accounts(&output), vec!["1,1.0000,0.0000,1.0000,false"]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains(
+            "1 transactions succeeded, 3 failed (InsufficientFunds: 2, UnknownTransactionForDispute: 1)"
+        ),
+        "{stderr}"
+    );
+
+    // the accounts are still written, but the exit code reflects the failures
+    let output = run(&["--strict", "--"], input);
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("1,1.0000,0.0000,1.0000,false"));
+
+    let output = run(
+        &["--strict", "--"],
+        b"type,client,tx,amount\ndeposit,1,1,1.0\n",
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+fn malformed_rows() {
+    let output = run(
+        &["--"],
+        b"type,client,tx,amount\n\
+          deposit,1,1,1.0\n\
+          deposit,1,2,one\n\
+          teleport,1,3,1.0\n\
+          deposit,1,4\n\
+          deposit,1,5,2.0\n",
+    );
+    assert_eq!(accounts(&output), vec!["1,3.0000,0.0000,3.0000,false"]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("2 transactions succeeded, 3 failed (Malformed: 3)"),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains("Skipping malformed row on line 3 (byte 38)"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn gzip_input() {
+    let fixture = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/transactions.csv.gz"
+    );
+    let expected = vec![
+        "1,2.0000,0.0000,2.0000,false",
+        "2,1.5000,0.0000,1.5000,false",
+    ];
+    assert_eq!(accounts(&run(&[fixture], b"")), expected);
+
+    let compressed = std::fs::read(fixture).unwrap();
+    assert_eq!(accounts(&run(&["--gzip", "--"], &compressed)), expected);
+}
+
+#[test]
+fn no_headers() {
+    let output = run(
+        &["--no-headers", "--"],
+        b"deposit,1,1,5.0\n\
+          deposit, 2, 2, 3.0\n\
+          withdrawal,1,3,1.0\n\
+          dispute,2,2\n\
+          resolve,2,2,\n\
+          dispute,1,1\n",
+    );
+    assert_eq!(
+        accounts(&output),
+        vec![
+            "1,-1.0000,5.0000,4.0000,false",
+            "2,3.0000,0.0000,3.0000,false"
+        ]
+    );
+}
+
+#[test]
+fn thousands_separators() {
+    let input = b"type,client,tx,amount\n\
+                  deposit,1,1,\"1,234.56\"\n\
+                  deposit,2,2,1234.56\n\
+                  deposit,3,3,\"1,23.4\"\n";
+    let output = run(&["--thousands-separator", ",", "--"], input);
+    assert_eq!(
+        accounts(&output),
+        vec![
+            "1,1234.5600,0.0000,1234.5600,false",
+            "2,1234.5600,0.0000,1234.5600,false"
+        ]
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("ambiguous thousands separators in amount \"1,23.4\""),
+        "{stderr}"
+    );
+
+    let output = run(&["--"], input);
+    assert_eq!(
+        accounts(&output),
+        vec!["2,1234.5600,0.0000,1234.5600,false"]
+    );
+}
+
+#[test]
+fn config_file() {
+    let config = concat!(env!("CARGO_TARGET_TMPDIR"), "/config_file.toml");
+    std::fs::write(config, "global_dispute = false\noutput_scale = 2\n").unwrap();
+    let input = b"type,client,tx,amount\n\
+                  deposit,1,1,1.255\n\
+                  dispute,2,1,\n";
+    assert_eq!(
+        accounts(&run(&["--config", config, "--"], input)),
+        vec!["1,1.26,0.00,1.26,false"]
+    );
+    // the flag overrides the config file
+    assert_eq!(
+        accounts(&run(&["--config", config, "--global-dispute", "--"], input)),
+        vec!["1,0.00,1.26,1.26,false"]
+    );
+
+    std::fs::write(config, "output_scale = \"two\"\n").unwrap();
+    // exits before reading the input, so none is passed to avoid a broken pipe
+    let output = run(&["--config", config, "--"], b"");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Invalid config"));
+}
+
+#[test]
+fn semicolon_delimiter() {
+    let output = run(
+        &["--delimiter", ";", "--thousands-separator", ",", "--"],
+        b"type;client;tx;amount;currency\n\
+          deposit;1;1;1,234.5;EUR\n\
+          withdrawal;1;2;0.5;\n\
+          deposit;2;3;2.0;\n\
+          dispute;2;3;;\n",
+    );
+    assert_eq!(
+        accounts(&output),
+        vec![
+            "1,1234.0000,0.0000,1234.0000,false",
+            "2,0.0000,2.0000,2.0000,false"
+        ]
+    );
+
+    let output = run(
+        &["--no-headers", "--delimiter", "\\t", "--"],
+        b"deposit\t1\t1\t5\ndispute\t1\t1\n",
+    );
+    assert_eq!(accounts(&output), vec!["1,0.0000,5.0000,5.0000,false"]);
+}
+
+#[test]
+fn buffer_size() {
+    let mut input = b"type,client,tx,amount\n".to_vec();
+    for tx in 1..=500 {
+        input.extend(format!("deposit,{},{tx},1.5\n", tx % 3).bytes());
+    }
+    input.extend(b"dispute,1,1,\n");
+    let expected = vec![
+        "0,249.0000,0.0000,249.0000,false",
+        "1,249.0000,1.5000,250.5000,false",
+        "2,250.5000,0.0000,250.5000,false",
+    ];
+    assert_eq!(accounts(&run(&["--"], &input)), expected);
+    for size in ["1", "5", "1048576"] {
+        assert_eq!(
+            accounts(&run(&["--buffer-size", size, "--"], &input)),
+            expected
+        );
+    }
+
+    let output = run(&["--buffer-size", "0", "--"], b"");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn errors_report() {
+    let errors = concat!(env!("CARGO_TARGET_TMPDIR"), "/errors_report.csv");
+    let output = run(
+        &["--errors", errors, "--"],
+        b"type,client,tx,amount\n\
+          deposit,1,1,2.0\n\
+          withdrawal,1,2,5.0\n\
+          deposit,2,three,1.0\n\
+          dispute,2,1,\n\
+          deposit,2,3,1.0\n",
+    );
+    assert_eq!(
+        accounts(&output),
+        vec![
+            "1,2.0000,0.0000,2.0000,false",
+            "2,1.0000,0.0000,1.0000,false"
+        ]
+    );
+    let report = std::fs::read_to_string(errors).unwrap();
+    let mut lines = report.lines();
+    assert_eq!(lines.next(), Some("row,client,tx,kind,message"));
+    assert_eq!(
+        lines.next(),
+        Some("2,1,2,InsufficientFunds,\"InsufficientFunds { client: 1, tx: 2 }\"")
+    );
+    let malformed = lines.next().unwrap();
+    assert!(
+        malformed.starts_with("3,,,MalformedRow,\"malformed row on line 4"),
+        "{malformed}"
+    );
+    assert_eq!(
+        lines.next(),
+        Some("4,2,1,TransactionDoesNotMatchClient,\"TransactionDoesNotMatchClient { tx: 1, client: 2, owner: 1 }\"")
+    );
+    assert_eq!(lines.next(), None);
+}
+
+#[test]
+fn postgres_copy() {
+    let fixture = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/transactions.copy"
+    );
+    assert_eq!(
+        accounts(&run(&["--format", "copy", "--no-headers", fixture], b"")),
+        vec![
+            "1,7.5000,0.0000,7.5000,false",
+            "2,0.0000,0.0000,0.0000,true"
+        ]
+    );
+
+    // with a header row, quotes are taken literally
+    let output = run(
+        &["--format", "copy", "--"],
+        b"type\tclient\ttx\tamount\treason\n\
+          adjustment\t1\t1\t3.0\t\"manual\n\
+          dispute\t1\t1\t\\N\t\\N\n",
+    );
+    assert_eq!(accounts(&output), vec!["1,0.0000,3.0000,3.0000,false"]);
+}
+
+#[test]
+fn wide_client_ids() {
+    let output = run(
+        &["--"],
+        b"type,client,tx,amount\n\
+          deposit,70000,1,2.0\n\
+          deposit,4294967295,2,3.0\n\
+          dispute,70000,1,\n\
+          deposit,4294967296,3,1.0\n",
+    );
+    assert_eq!(
+        accounts(&output),
+        vec![
+            "4294967295,3.0000,0.0000,3.0000,false",
+            "70000,0.0000,2.0000,2.0000,false"
+        ]
+    );
+    // one past the largest id doesn't fit
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Skipping malformed row on line 5"));
+}